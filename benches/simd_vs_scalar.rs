@@ -7,9 +7,9 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use std::collections::HashMap;
 use erock::{interpreter, lexer, parser};
 
-fn build_ast() -> (parser::Arena, usize) {
+fn build_ast() -> (parser::Arena, parser::NodeId) {
     let input = "sum = 3.14 + (x - 2) * 10";
-    let tokens = lexer::tokenize(input);
+    let tokens = lexer::tokenize(input).expect("lex failed");
     parser::parse(tokens).expect("parse failed")
 }
 
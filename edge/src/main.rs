@@ -3,15 +3,75 @@ SPDX-FileCopyrightText: 2025 Eric Waller
 SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
 */
 
-use axum::{routing::{get, post}, Json, Router};
+use axum::{http::StatusCode, routing::{get, post}, Json, Router};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use erock::{lexer, parser, interpreter};
+use erock::{analysis, lexer, parser, interpreter};
 mod jit_health;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// ---------- errors ----------
+#[derive(Serialize)]
+struct SpanResp {
+    start: usize,   // byte offsets into `expr`
+    end: usize,
+    line: usize,
+    column: usize,
+}
+#[derive(Serialize)]
+struct ErrorResp {
+    error: String,
+    kind: &'static str,
+    span: SpanResp,
+}
+type ApiError = (StatusCode, Json<ErrorResp>);
+
+fn error_kind(e: &parser::ParseError) -> &'static str {
+    match e {
+        parser::ParseError::Lex(lexer::LexError::UnexpectedChar { .. }) => "unexpected_character",
+        parser::ParseError::Lex(lexer::LexError::MalformedNumber { .. }) => "malformed_number",
+        parser::ParseError::Lex(lexer::LexError::UnterminatedString { .. }) => "unterminated_string",
+        parser::ParseError::UnexpectedToken { .. } => "unexpected_token",
+        parser::ParseError::UnbalancedParen { .. } => "unbalanced_paren",
+        parser::ParseError::TrailingInput { .. } => "trailing_input",
+        parser::ParseError::UnexpectedEof { .. } => "unexpected_eof",
+        parser::ParseError::UnknownFunction { .. } => "unknown_function",
+        parser::ParseError::ArityMismatch { .. } => "arity_mismatch",
+        parser::ParseError::DuplicateFunction { .. } => "duplicate_function",
+        parser::ParseError::DuplicateParameter { .. } => "duplicate_parameter",
+        parser::ParseError::RecursiveFunction { .. } => "recursive_function",
+        parser::ParseError::UnusedValue { .. } => "unused_value",
+    }
+}
+
+fn bad_request(error: String, kind: &'static str, span: lexer::Span) -> ApiError {
+    let body = ErrorResp {
+        error,
+        kind,
+        span: SpanResp {
+            start: span.start.offset,
+            end: span.end.offset,
+            line: span.start.line,
+            column: span.start.column,
+        },
+    };
+    (StatusCode::BAD_REQUEST, Json(body))
+}
+
+// Parse `expr` and check that `vars` supplies every free name but `x`,
+// which would otherwise read as 0.0.
+fn compile(expr: &str, vars: &HashMap<String, f64>) -> Result<(parser::Arena, parser::NodeId), ApiError> {
+    let (arena, root) = parser::parse_str(expr).map_err(|e| bad_request(e.to_string(), error_kind(&e), e.span()))?;
+    let needs = analysis::analyze(&arena, root);
+    if let Some(name) = needs.missing(vars).into_iter().find(|&name| name != "x") {
+        let span = needs.free[name];
+        return Err(bad_request(format!("no value for variable '{}' used at {}", name, span), "missing_variable", span));
+    }
+    Ok((arena, root))
+}
+
 // ---------- /evaluate ----------
 #[derive(Deserialize)]
 struct EvalReq {
@@ -22,12 +82,11 @@ struct EvalReq {
 #[derive(Serialize)]
 struct EvalResp { y: Vec<f64> }
 
-async fn evaluate(Json(req): Json<EvalReq>) -> Json<EvalResp> {
-    let tokens = lexer::tokenize(&req.expr);
-    let (arena, root) = parser::parse(tokens).expect("parse error");
+async fn evaluate(Json(req): Json<EvalReq>) -> Result<Json<EvalResp>, ApiError> {
     let fixed = req.vars.unwrap_or_default();
+    let (arena, root) = compile(&req.expr, &fixed)?;
     let y = interpreter::simd_eval_over_x(root, &arena, &fixed, &req.x);
-    Json(EvalResp { y })
+    Ok(Json(EvalResp { y }))
 }
 
 // ---------- /bisect ----------
@@ -48,10 +107,9 @@ struct BisectResp {
     bracket_ok: bool,
 }
 
-async fn bisect(Json(req): Json<BisectReq>) -> Json<BisectResp> {
-    let tokens = lexer::tokenize(&req.expr);
-    let (arena, root) = parser::parse(tokens).expect("parse error");
+async fn bisect(Json(req): Json<BisectReq>) -> Result<Json<BisectResp>, ApiError> {
     let fixed = req.vars.unwrap_or_default();
+    let (arena, root) = compile(&req.expr, &fixed)?;
 
     let eval_at = |t: f64| -> f64 {
         interpreter::simd_eval_over_x(root, &arena, &fixed, &vec![t])[0]
@@ -64,7 +122,7 @@ async fn bisect(Json(req): Json<BisectReq>) -> Json<BisectResp> {
 
     let bracket_ok = (flo <= 0.0 && fhi >= 0.0) || (flo >= 0.0 && fhi <= 0.0);
     if !bracket_ok {
-        return Json(BisectResp { root: f64::NAN, f: f64::NAN, iters: 0, bracket_ok });
+        return Ok(Json(BisectResp { root: f64::NAN, f: f64::NAN, iters: 0, bracket_ok }));
     }
 
     let tol = req.tol.unwrap_or(1e-9);
@@ -77,7 +135,7 @@ async fn bisect(Json(req): Json<BisectReq>) -> Json<BisectResp> {
         iters += 1;
 
         if (hi - lo).abs() <= tol {
-            return Json(BisectResp { root: mid, f: fm, iters, bracket_ok: true });
+            return Ok(Json(BisectResp { root: mid, f: fm, iters, bracket_ok: true }));
         }
         if (flo <= 0.0 && fm <= 0.0) || (flo >= 0.0 && fm >= 0.0) {
             lo = mid; flo = fm;
@@ -88,7 +146,7 @@ async fn bisect(Json(req): Json<BisectReq>) -> Json<BisectResp> {
 
     let mid = 0.5 * (lo + hi);
     let fm = eval_at(mid);
-    Json(BisectResp { root: mid, f: fm, iters, bracket_ok: true })
+    Ok(Json(BisectResp { root: mid, f: fm, iters, bracket_ok: true }))
 }
 
 // ---------- /bisect_auto ----------
@@ -117,10 +175,9 @@ fn same_sign(a: f64, b: f64) -> bool {
     (a >= 0.0 && b >= 0.0) || (a <= 0.0 && b <= 0.0)
 }
 
-async fn bisect_auto(Json(req): Json<BisectAutoReq>) -> Json<BisectAutoResp> {
-    let tokens = lexer::tokenize(&req.expr);
-    let (arena, root) = parser::parse(tokens).expect("parse error");
+async fn bisect_auto(Json(req): Json<BisectAutoReq>) -> Result<Json<BisectAutoResp>, ApiError> {
     let fixed = req.vars.unwrap_or_default();
+    let (arena, root) = compile(&req.expr, &fixed)?;
 
     let eval_at = |t: f64| -> f64 {
         interpreter::simd_eval_over_x(root, &arena, &fixed, &vec![t])[0]
@@ -132,7 +189,7 @@ async fn bisect_auto(Json(req): Json<BisectAutoReq>) -> Json<BisectAutoResp> {
     let f0 = eval_at(g);
 
     if f0.abs() == 0.0 {
-        return Json(BisectAutoResp { root: g, f: f0, lo: g, hi: g, iters: 0, bracket_ok: true, expansions: 0 });
+        return Ok(Json(BisectAutoResp { root: g, f: f0, lo: g, hi: g, iters: 0, bracket_ok: true, expansions: 0 }));
     }
 
     // Exponential outward search
@@ -163,7 +220,7 @@ async fn bisect_auto(Json(req): Json<BisectAutoReq>) -> Json<BisectAutoResp> {
     }
 
     if !lo.is_finite() || !hi.is_finite() {
-        return Json(BisectAutoResp { root: f64::NAN, f: f64::NAN, lo: f64::NAN, hi: f64::NAN, iters: 0, bracket_ok: false, expansions });
+        return Ok(Json(BisectAutoResp { root: f64::NAN, f: f64::NAN, lo: f64::NAN, hi: f64::NAN, iters: 0, bracket_ok: false, expansions }));
     }
 
     // Bisection on the found bracket
@@ -178,7 +235,7 @@ async fn bisect_auto(Json(req): Json<BisectAutoReq>) -> Json<BisectAutoResp> {
         iters += 1;
 
         if (hi - lo).abs() <= tol {
-            return Json(BisectAutoResp { root: mid, f: fm, lo, hi, iters, bracket_ok: true, expansions });
+            return Ok(Json(BisectAutoResp { root: mid, f: fm, lo, hi, iters, bracket_ok: true, expansions }));
         }
         if same_sign(fm, flo) {
             lo = mid; flo = fm;
@@ -189,7 +246,7 @@ async fn bisect_auto(Json(req): Json<BisectAutoReq>) -> Json<BisectAutoResp> {
 
     let mid = 0.5 * (lo + hi);
     let fm = eval_at(mid);
-    Json(BisectAutoResp { root: mid, f: fm, lo, hi, iters, bracket_ok: true, expansions })
+    Ok(Json(BisectAutoResp { root: mid, f: fm, lo, hi, iters, bracket_ok: true, expansions }))
 }
 
 // ---------- /health ----------
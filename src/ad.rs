@@ -0,0 +1,686 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Automatic differentiation: values together with exact partial
+//! derivatives, without building derivative trees like [`crate::diff`].
+//!
+//! [`interpret_dual`] and [`simd_dual_over_x`] evaluate in forward mode
+//! with dual numbers: every intermediate value carries its partials with
+//! respect to the `wrt` variables. Scoping follows the SIMD evaluator, so
+//! `let` and `=` bind for later statements without touching `variables`.
+//!
+//! [`gradient`] and [`gradient_rows`] run in reverse mode instead: one
+//! evaluation records each operation and its local derivatives on a tape,
+//! and one backward sweep yields the partials for every input at once,
+//! which is cheaper when there are many inputs.
+//!
+//! Both modes differentiate the branch taken by conditionals, `abs`, `min`,
+//! `max` and `clamp`; comparisons, logical operators, `floor` and `ceil`
+//! have zero partials.
+
+use crate::functions::{Builtin, Lanes};
+use crate::interpreter::{binary_op, binary_op_simd, from_bool, truthy, truthy_mask, user_function, Memo};
+use crate::lexer::Token;
+use crate::parser::{Arena, ExprKind, NodeId};
+use std::collections::HashMap;
+use wide::f64x4;
+
+/// A value and its partial derivatives, one per requested variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    pub value: f64,
+    pub partials: Vec<f64>,
+}
+
+/// Values and partials over a slice of x values: `partials[k][i]` is the
+/// derivative with respect to the `k`th variable at `xs[i]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradients {
+    pub values: Vec<f64>,
+    pub partials: Vec<Vec<f64>>,
+}
+
+/// A value and its partial derivative with respect to each input read while
+/// computing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedGradient {
+    pub value: f64,
+    pub partials: HashMap<String, f64>,
+}
+
+/// Evaluate the tree at `root` with its partial derivatives with respect to
+/// each name in `wrt`, in order.
+pub fn interpret_dual(root: NodeId, arena: &Arena, variables: &HashMap<String, f64>, wrt: &[&str]) -> Gradient {
+    let mut env = DualEnv::new(arena, variables, wrt, None);
+    let r = eval(root, arena, &mut env);
+    Gradient { value: r.v, partials: r.d }
+}
+
+/// [`interpret_dual`] across `xs` in 4-wide lanes, like
+/// [`crate::interpreter::simd_eval_over_x`]. Include `"x"` in `wrt` for
+/// the derivative along `xs`.
+pub fn simd_dual_over_x(
+    root: NodeId,
+    arena: &Arena,
+    variables: &HashMap<String, f64>,
+    xs: &[f64],
+    wrt: &[&str],
+) -> Gradients {
+    let n = xs.len();
+    let mut out = Gradients { values: Vec::with_capacity(n), partials: vec![Vec::with_capacity(n); wrt.len()] };
+    let mut env = DualEnv::new(arena, variables, wrt, Some(f64x4::ZERO));
+    for chunk in xs.chunks(4) {
+        let mut buf = [chunk[chunk.len() - 1]; 4];
+        buf[..chunk.len()].copy_from_slice(chunk);
+
+        // Shared nodes are computed once per block of lanes.
+        env.x = Some(f64x4::from(buf));
+        env.locals.clear();
+        env.memo.invalidate();
+        let r = eval(root, arena, &mut env);
+        out.values.extend_from_slice(&r.v.to_array()[..chunk.len()]);
+        for (column, d) in out.partials.iter_mut().zip(&r.d) {
+            column.extend_from_slice(&d.to_array()[..chunk.len()]);
+        }
+    }
+    out
+}
+
+// What the dual evaluator needs beyond `Lanes`: the backend's own operator
+// semantics and per-lane selection.
+trait AdLanes: Lanes {
+    type Mask: Copy;
+    fn binary(op: &Token, l: Self, r: Self) -> Self;
+    fn truthy(self) -> Self::Mask;
+    fn and(a: Self::Mask, b: Self::Mask) -> Self::Mask;
+    fn or(a: Self::Mask, b: Self::Mask) -> Self::Mask;
+    fn not(m: Self::Mask) -> Self::Mask;
+    fn all(m: Self::Mask) -> bool;
+    fn none(m: Self::Mask) -> bool;
+    fn select(m: Self::Mask, t: Self, e: Self) -> Self;
+
+    fn from_mask(m: Self::Mask) -> Self {
+        Self::select(m, Self::splat(1.0), Self::splat(0.0))
+    }
+
+    fn test(op: Token, l: Self, r: Self) -> Self::Mask {
+        Self::binary(&op, l, r).truthy()
+    }
+}
+
+impl AdLanes for f64 {
+    type Mask = bool;
+    fn binary(op: &Token, l: Self, r: Self) -> Self { binary_op(op, l, r) }
+    fn truthy(self) -> bool { truthy(self) }
+    fn and(a: bool, b: bool) -> bool { a && b }
+    fn or(a: bool, b: bool) -> bool { a || b }
+    fn not(m: bool) -> bool { !m }
+    fn all(m: bool) -> bool { m }
+    fn none(m: bool) -> bool { !m }
+    fn select(m: bool, t: Self, e: Self) -> Self { if m { t } else { e } }
+}
+
+impl AdLanes for f64x4 {
+    type Mask = f64x4;
+    fn binary(op: &Token, l: Self, r: Self) -> Self { binary_op_simd(op, l, r) }
+    fn truthy(self) -> f64x4 { truthy_mask(self) }
+    fn and(a: f64x4, b: f64x4) -> f64x4 { a & b }
+    fn or(a: f64x4, b: f64x4) -> f64x4 { a | b }
+    fn not(m: f64x4) -> f64x4 { !m }
+    fn all(m: f64x4) -> bool { m.all() }
+    fn none(m: f64x4) -> bool { m.none() }
+    fn select(m: f64x4, t: Self, e: Self) -> Self { m.blend(t, e) }
+}
+
+// A value with its partials, in `wrt` order.
+#[derive(Clone)]
+struct Dual<L> {
+    v: L,
+    d: Vec<L>,
+}
+
+impl<L: AdLanes> Dual<L> {
+    fn constant(v: L, n: usize) -> Self {
+        Dual { v, d: vec![L::splat(0.0); n] }
+    }
+
+    fn scaled(&self, k: L) -> Vec<L> {
+        self.d.iter().map(|&d| d * k).collect()
+    }
+
+    fn select(m: L::Mask, t: &Self, e: &Self) -> Self {
+        let d = t.d.iter().zip(&e.d).map(|(&a, &b)| L::select(m, a, b)).collect();
+        Dual { v: L::select(m, t.v, e.v), d }
+    }
+}
+
+fn zip<L: Copy>(a: &[L], b: &[L], f: impl Fn(L, L) -> L) -> Vec<L> {
+    a.iter().zip(b).map(|(&a, &b)| f(a, b)).collect()
+}
+
+// `term` where `seed` is nonzero, else 0, so a term that does not depend on
+// a variable contributes nothing even when it is not finite.
+fn seeded<L: AdLanes>(seed: L, term: L) -> L {
+    L::select(L::test(Token::EqEq, seed, L::splat(0.0)), L::splat(0.0), term)
+}
+
+struct DualEnv<'v, L> {
+    variables: &'v HashMap<String, f64>,
+    wrt: &'v [&'v str],
+    // Lanes of `x` for the SIMD evaluator; the scalar one reads `variables`.
+    x: Option<L>,
+    locals: Vec<(String, Dual<L>)>,
+    memo: Memo<Dual<L>>,
+}
+
+impl<'v, L: AdLanes> DualEnv<'v, L> {
+    fn new(arena: &Arena, variables: &'v HashMap<String, f64>, wrt: &'v [&'v str], x: Option<L>) -> Self {
+        let fill = Dual::constant(L::splat(0.0), 0);
+        DualEnv { variables, wrt, x, locals: Vec::new(), memo: Memo::new(arena, fill) }
+    }
+
+    fn get(&self, name: &str) -> Dual<L> {
+        if let Some((_, v)) = self.locals.iter().rev().find(|(n, _)| n == name) {
+            return v.clone();
+        }
+        let v = match self.x {
+            Some(x) if name == "x" => x,
+            _ => L::splat(self.variables.get(name).copied().unwrap_or(0.0)),
+        };
+        let d = self.wrt.iter().map(|&w| L::splat(if w == name { 1.0 } else { 0.0 })).collect();
+        Dual { v, d }
+    }
+
+    fn nan(&self) -> Dual<L> {
+        Dual::constant(L::splat(f64::NAN), self.wrt.len())
+    }
+}
+
+fn eval<L: AdLanes>(idx: NodeId, arena: &Arena, env: &mut DualEnv<L>) -> Dual<L> {
+    if !arena.is_shared(idx) {
+        return eval_node(idx, arena, env);
+    }
+    if let Some(v) = env.memo.get(idx) {
+        return v;
+    }
+    let v = eval_node(idx, arena, env);
+    env.memo.set(idx, v.clone());
+    v
+}
+
+fn eval_node<L: AdLanes>(idx: NodeId, arena: &Arena, env: &mut DualEnv<L>) -> Dual<L> {
+    let n = env.wrt.len();
+    let Some(expr) = arena.get(idx) else { return env.nan() };
+    match &expr.kind {
+        ExprKind::Number(v) => Dual::constant(L::splat(*v), n),
+        ExprKind::Identifier(name) => env.get(name),
+        ExprKind::Unary { op, operand } => {
+            let a = eval(*operand, arena, env);
+            match op {
+                Token::Minus => Dual { v: -a.v, d: a.scaled(L::splat(-1.0)) },
+                Token::Bang => Dual::constant(L::from_mask(L::not(a.v.truthy())), n),
+                _ => a,
+            }
+        }
+        ExprKind::Binary { left, op, right } => {
+            let l = eval(*left, arena, env);
+            let r = eval(*right, arena, env);
+            let v = L::binary(op, l.v, r.v);
+            let d = match op {
+                Token::Plus => zip(&l.d, &r.d, |a, b| a + b),
+                Token::Minus => zip(&l.d, &r.d, |a, b| a - b),
+                Token::Star => zip(&l.d, &r.d, |a, b| a * r.v + l.v * b),
+                Token::Slash => {
+                    // Division by zero is NaN, and so are its partials.
+                    let by_zero = L::test(Token::EqEq, r.v, L::splat(0.0));
+                    zip(&l.d, &r.d, |a, b| L::select(by_zero, L::splat(f64::NAN), (a * r.v - l.v * b) / (r.v * r.v)))
+                }
+                Token::Caret => {
+                    // d(u^w) = w u^(w-1) du + u^w ln(u) dw
+                    let du = r.v * L::binary(&Token::Caret, l.v, r.v - L::splat(1.0));
+                    let dw = v * l.v.ln();
+                    zip(&l.d, &r.d, |a, b| seeded(a, du * a) + seeded(b, dw * b))
+                }
+                _ => vec![L::splat(0.0); n],
+            };
+            Dual { v, d }
+        }
+        ExprKind::Logical { left, op, right } => {
+            let l = eval(*left, arena, env).v.truthy();
+            let m = match op {
+                Token::AndAnd if L::none(l) => l,
+                Token::OrOr if L::all(l) => l,
+                Token::AndAnd => L::and(l, eval(*right, arena, env).v.truthy()),
+                _ => L::or(l, eval(*right, arena, env).v.truthy()),
+            };
+            Dual::constant(L::from_mask(m), n)
+        }
+        ExprKind::If { cond, then_branch, else_branch } => {
+            let m = eval(*cond, arena, env).v.truthy();
+            if L::all(m) {
+                eval(*then_branch, arena, env)
+            } else if L::none(m) {
+                eval(*else_branch, arena, env)
+            } else {
+                let t = eval(*then_branch, arena, env);
+                let e = eval(*else_branch, arena, env);
+                Dual::select(m, &t, &e)
+            }
+        }
+        ExprKind::Call { name, args } => {
+            let vals: Vec<Dual<L>> = args.iter().map(|&a| eval(a, arena, env)).collect();
+            if let Some(builtin) = Builtin::from_name(name) {
+                if !builtin.arity().accepts(vals.len()) {
+                    return env.nan();
+                }
+                return call_builtin(builtin, &vals);
+            }
+            let Some((params, body)) = user_function(arena, name) else { return env.nan() };
+            if params.len() != vals.len() {
+                return env.nan();
+            }
+            let scope = env.locals.len();
+            env.locals.extend(params.iter().cloned().zip(vals));
+            env.memo.invalidate();
+            let v = eval(body, arena, env);
+            env.locals.truncate(scope);
+            env.memo.invalidate();
+            v
+        }
+        ExprKind::Assign { name, value } | ExprKind::Let { name, value } => {
+            let v = eval(*value, arena, env);
+            env.locals.push((name.clone(), v.clone()));
+            env.memo.invalidate();
+            v
+        }
+        ExprKind::Program { statements } => {
+            let scope = env.locals.len();
+            let mut v = env.nan();
+            for &stmt in statements {
+                v = eval(stmt, arena, env);
+            }
+            env.locals.truncate(scope);
+            env.memo.invalidate();
+            v
+        }
+        ExprKind::FnDef { .. }
+        | ExprKind::Str(_)
+        | ExprKind::Print { .. }
+        | ExprKind::Vector { .. }
+        | ExprKind::Index { .. } => env.nan(),
+    }
+}
+
+// `args` satisfies the builtin's arity.
+fn call_builtin<L: AdLanes>(builtin: Builtin, args: &[Dual<L>]) -> Dual<L> {
+    let values: Vec<L> = args.iter().map(|a| a.v).collect();
+    let v = builtin.eval(&values);
+    let a = &args[0];
+    let one = L::splat(1.0);
+    let d = match builtin {
+        Builtin::Sqrt => a.scaled(L::splat(0.5) / v),
+        Builtin::Sin => a.scaled(a.v.cos()),
+        Builtin::Cos => a.scaled(-a.v.sin()),
+        Builtin::Tan => {
+            let c = a.v.cos();
+            a.scaled(one / (c * c))
+        }
+        Builtin::Exp => a.scaled(v),
+        Builtin::Ln => a.scaled(one / a.v),
+        Builtin::Log10 => a.scaled(one / (a.v * L::splat(std::f64::consts::LN_10))),
+        Builtin::Abs => a.scaled(L::select(L::test(Token::Lt, a.v, L::splat(0.0)), -one, one)),
+        Builtin::Sum | Builtin::Mean => a.d.clone(),
+        Builtin::Norm => a.scaled(L::select(L::test(Token::Lt, a.v, L::splat(0.0)), -one, one)),
+        Builtin::Floor | Builtin::Ceil | Builtin::Len | Builtin::Std | Builtin::CountIf => {
+            vec![L::splat(0.0); a.d.len()]
+        }
+        Builtin::Dot => {
+            let b = &args[1];
+            zip(&a.d, &b.d, |da, db| da * b.v + a.v * db)
+        }
+        Builtin::Atan2 => {
+            let (y, x) = (a, &args[1]);
+            let r2 = x.v * x.v + y.v * y.v;
+            zip(&y.d, &x.d, |dy, dx| (x.v * dy - y.v * dx) / r2)
+        }
+        Builtin::Hypot => {
+            let b = &args[1];
+            zip(&a.d, &b.d, |da, db| (a.v * da + b.v * db) / v)
+        }
+        // Pairwise, following the argument the running result came from.
+        Builtin::Min | Builtin::Max => {
+            let op = if builtin == Builtin::Min { Token::Le } else { Token::Ge };
+            let mut best = a.clone();
+            for next in &args[1..] {
+                best = Dual::select(L::test(op.clone(), best.v, next.v), &best, next);
+            }
+            best.d
+        }
+        // max(x, lo) then min with hi, so `hi` wins even when lo > hi.
+        Builtin::Clamp => {
+            let (lo, hi) = (&args[1], &args[2]);
+            let raised = Dual::select(L::test(Token::Lt, a.v, lo.v), lo, a);
+            Dual::select(L::test(Token::Gt, raised.v, hi.v), hi, &raised).d
+        }
+    };
+    Dual { v, d }
+}
+
+// ========== Reverse mode ==========
+
+/// Evaluate the tree at `root` and its gradient by reverse mode. `partials`
+/// covers every name read from `variables` (or defaulted to 0); a name that
+/// only appears in a branch not taken is left out.
+pub fn gradient(root: NodeId, arena: &Arena, variables: &HashMap<String, f64>) -> NamedGradient {
+    let mut env = TapeEnv::new(arena, variables);
+    let out = record(root, arena, &mut env);
+    let adjoints = env.tape.adjoints(out);
+    let partials = env.inputs.iter().map(|(name, &slot)| (name.clone(), adjoints[slot])).collect();
+    NamedGradient { value: env.tape.values[out], partials }
+}
+
+/// [`gradient`] for each row of input values, given in `names` order (a
+/// short row reads the rest as 0), with partials in the same order. The
+/// tape is reused between rows.
+pub fn gradient_rows(root: NodeId, arena: &Arena, names: &[&str], rows: &[Vec<f64>]) -> Vec<Gradient> {
+    let mut variables: HashMap<String, f64> = names.iter().map(|&n| (n.to_string(), 0.0)).collect();
+    let mut tape = Tape::default();
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        for (k, &name) in names.iter().enumerate() {
+            variables.insert(name.to_string(), row.get(k).copied().unwrap_or(0.0));
+        }
+        let mut env = TapeEnv::new(arena, &variables);
+        tape.clear();
+        env.tape = tape;
+        let slot = record(root, arena, &mut env);
+        let adjoints = env.tape.adjoints(slot);
+        let partials = names.iter().map(|&n| env.inputs.get(n).map_or(0.0, |&s| adjoints[s])).collect();
+        out.push(Gradient { value: env.tape.values[slot], partials });
+        tape = env.tape;
+    }
+    out
+}
+
+// Operations in evaluation order: each slot's value and the slots it was
+// computed from, with the partial of the slot with respect to each.
+#[derive(Default)]
+struct Tape {
+    values: Vec<f64>,
+    starts: Vec<usize>,
+    edges: Vec<(usize, f64)>,
+}
+
+impl Tape {
+    fn push(&mut self, v: f64, edges: &[(usize, f64)]) -> usize {
+        self.starts.push(self.edges.len());
+        self.edges.extend_from_slice(edges);
+        self.values.push(v);
+        self.values.len() - 1
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.starts.clear();
+        self.edges.clear();
+    }
+
+    // d(out)/d(slot) for every slot, in one sweep from `out` back to the
+    // start. Slots with a zero adjoint pass nothing on, so an infinite
+    // local partial does not turn into NaN.
+    fn adjoints(&self, out: usize) -> Vec<f64> {
+        let mut adj = vec![0.0; self.values.len()];
+        adj[out] = 1.0;
+        for slot in (0..=out).rev() {
+            let a = adj[slot];
+            if a == 0.0 {
+                continue;
+            }
+            let end = self.starts.get(slot + 1).copied().unwrap_or(self.edges.len());
+            for &(from, w) in &self.edges[self.starts[slot]..end] {
+                adj[from] += a * w;
+            }
+        }
+        adj
+    }
+}
+
+struct TapeEnv<'v> {
+    variables: &'v HashMap<String, f64>,
+    tape: Tape,
+    // Slot of each name read from `variables`.
+    inputs: HashMap<String, usize>,
+    locals: Vec<(String, usize)>,
+    memo: Memo<usize>,
+}
+
+impl<'v> TapeEnv<'v> {
+    fn new(arena: &Arena, variables: &'v HashMap<String, f64>) -> Self {
+        let (tape, inputs, locals) = (Tape::default(), HashMap::new(), Vec::new());
+        TapeEnv { variables, tape, inputs, locals, memo: Memo::new(arena, 0) }
+    }
+
+    fn get(&mut self, name: &str) -> usize {
+        if let Some(&(_, slot)) = self.locals.iter().rev().find(|(n, _)| n == name) {
+            return slot;
+        }
+        if let Some(&slot) = self.inputs.get(name) {
+            return slot;
+        }
+        let slot = self.tape.push(self.variables.get(name).copied().unwrap_or(0.0), &[]);
+        self.inputs.insert(name.to_string(), slot);
+        slot
+    }
+
+    fn constant(&mut self, v: f64) -> usize {
+        self.tape.push(v, &[])
+    }
+}
+
+fn record(idx: NodeId, arena: &Arena, env: &mut TapeEnv) -> usize {
+    if !arena.is_shared(idx) {
+        return record_node(idx, arena, env);
+    }
+    if let Some(slot) = env.memo.get(idx) {
+        return slot;
+    }
+    let slot = record_node(idx, arena, env);
+    env.memo.set(idx, slot);
+    slot
+}
+
+fn recorded_value(idx: NodeId, arena: &Arena, env: &mut TapeEnv) -> f64 {
+    let slot = record(idx, arena, env);
+    env.tape.values[slot]
+}
+
+fn record_node(idx: NodeId, arena: &Arena, env: &mut TapeEnv) -> usize {
+    let Some(expr) = arena.get(idx) else { return env.constant(f64::NAN) };
+    match &expr.kind {
+        ExprKind::Number(v) => env.constant(*v),
+        ExprKind::Identifier(name) => env.get(name),
+        ExprKind::Unary { op, operand } => {
+            let a = record(*operand, arena, env);
+            let v = env.tape.values[a];
+            match op {
+                Token::Minus => env.tape.push(-v, &[(a, -1.0)]),
+                Token::Bang => env.constant(from_bool(!truthy(v))),
+                _ => a,
+            }
+        }
+        ExprKind::Binary { left, op, right } => {
+            let (l, r) = (record(*left, arena, env), record(*right, arena, env));
+            let (lv, rv) = (env.tape.values[l], env.tape.values[r]);
+            let v = binary_op(op, lv, rv);
+            match op {
+                Token::Plus => env.tape.push(v, &[(l, 1.0), (r, 1.0)]),
+                Token::Minus => env.tape.push(v, &[(l, 1.0), (r, -1.0)]),
+                Token::Star => env.tape.push(v, &[(l, rv), (r, lv)]),
+                // Division by zero is NaN, and so are its partials.
+                Token::Slash if rv == 0.0 => env.tape.push(v, &[(l, f64::NAN), (r, f64::NAN)]),
+                Token::Slash => env.tape.push(v, &[(l, 1.0 / rv), (r, -lv / (rv * rv))]),
+                Token::Caret => env.tape.push(v, &[(l, rv * lv.powf(rv - 1.0)), (r, v * lv.ln())]),
+                _ => env.constant(v),
+            }
+        }
+        ExprKind::Logical { left, op, right } => {
+            let l = truthy(recorded_value(*left, arena, env));
+            let v = match op {
+                Token::AndAnd if !l => 0.0,
+                Token::OrOr if l => 1.0,
+                _ => from_bool(truthy(recorded_value(*right, arena, env))),
+            };
+            env.constant(v)
+        }
+        ExprKind::If { cond, then_branch, else_branch } => {
+            if truthy(recorded_value(*cond, arena, env)) {
+                record(*then_branch, arena, env)
+            } else {
+                record(*else_branch, arena, env)
+            }
+        }
+        ExprKind::Call { name, args } => {
+            let slots: Vec<usize> = args.iter().map(|&a| record(a, arena, env)).collect();
+            if let Some(builtin) = Builtin::from_name(name) {
+                if !builtin.arity().accepts(slots.len()) {
+                    return env.constant(f64::NAN);
+                }
+                return record_builtin(builtin, &slots, env);
+            }
+            let Some((params, body)) = user_function(arena, name) else { return env.constant(f64::NAN) };
+            if params.len() != slots.len() {
+                return env.constant(f64::NAN);
+            }
+            let scope = env.locals.len();
+            env.locals.extend(params.iter().cloned().zip(slots));
+            env.memo.invalidate();
+            let slot = record(body, arena, env);
+            env.locals.truncate(scope);
+            env.memo.invalidate();
+            slot
+        }
+        ExprKind::Assign { name, value } | ExprKind::Let { name, value } => {
+            let slot = record(*value, arena, env);
+            env.locals.push((name.clone(), slot));
+            env.memo.invalidate();
+            slot
+        }
+        ExprKind::Program { statements } => {
+            let scope = env.locals.len();
+            let mut slot = None;
+            for &stmt in statements {
+                slot = Some(record(stmt, arena, env));
+            }
+            env.locals.truncate(scope);
+            env.memo.invalidate();
+            slot.unwrap_or_else(|| env.constant(f64::NAN))
+        }
+        ExprKind::FnDef { .. }
+        | ExprKind::Str(_)
+        | ExprKind::Print { .. }
+        | ExprKind::Vector { .. }
+        | ExprKind::Index { .. } => env.constant(f64::NAN),
+    }
+}
+
+// `slots` satisfies the builtin's arity.
+fn record_builtin(builtin: Builtin, slots: &[usize], env: &mut TapeEnv) -> usize {
+    let vals: Vec<f64> = slots.iter().map(|&s| env.tape.values[s]).collect();
+    let v = builtin.eval(&vals);
+    let (s, a) = (slots[0], vals[0]);
+    let edges: Vec<(usize, f64)> = match builtin {
+        Builtin::Sqrt => vec![(s, 0.5 / v)],
+        Builtin::Sin => vec![(s, a.cos())],
+        Builtin::Cos => vec![(s, -a.sin())],
+        Builtin::Tan => vec![(s, 1.0 / (a.cos() * a.cos()))],
+        Builtin::Exp => vec![(s, v)],
+        Builtin::Ln => vec![(s, 1.0 / a)],
+        Builtin::Log10 => vec![(s, 1.0 / (a * std::f64::consts::LN_10))],
+        Builtin::Abs | Builtin::Norm => vec![(s, if a < 0.0 { -1.0 } else { 1.0 })],
+        Builtin::Sum | Builtin::Mean => vec![(s, 1.0)],
+        Builtin::Floor | Builtin::Ceil | Builtin::Len | Builtin::Std | Builtin::CountIf => vec![],
+        Builtin::Dot => vec![(s, vals[1]), (slots[1], a)],
+        Builtin::Atan2 => {
+            let (y, x) = (a, vals[1]);
+            let r2 = x * x + y * y;
+            vec![(s, x / r2), (slots[1], -y / r2)]
+        }
+        Builtin::Hypot => vec![(s, a / v), (slots[1], vals[1] / v)],
+        // Follow the argument the result came from, as forward mode does.
+        Builtin::Min | Builtin::Max => {
+            let mut best = 0;
+            for j in 1..vals.len() {
+                let keep = if builtin == Builtin::Min { vals[best] <= vals[j] } else { vals[best] >= vals[j] };
+                if !keep {
+                    best = j;
+                }
+            }
+            vec![(slots[best], 1.0)]
+        }
+        Builtin::Clamp => {
+            let raised = if a < vals[1] { 1 } else { 0 };
+            let chosen = if vals[raised] > vals[2] { 2 } else { raised };
+            vec![(slots[chosen], 1.0)]
+        }
+    };
+    env.tape.push(v, &edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    fn vars(pairs: &[(&str, f64)]) -> HashMap<String, f64> {
+        pairs.iter().map(|&(name, v)| (name.to_string(), v)).collect()
+    }
+
+    #[test]
+    fn forward_division_by_zero_is_nan() {
+        for (src, at) in [("x / 0", 2.0), ("x / 0", 0.0), ("1 / x", 0.0), ("y / (x - 1)", 1.0)] {
+            let (arena, root) = parse_str(src).unwrap();
+            let g = interpret_dual(root, &arena, &vars(&[("x", at), ("y", 3.0)]), &["x", "y"]);
+            assert!(g.value.is_nan(), "{}", src);
+            assert!(g.partials.iter().all(|d| d.is_nan()), "{} at x = {}: {:?}", src, at, g.partials);
+            let lanes = simd_dual_over_x(root, &arena, &vars(&[("y", 3.0)]), &[at, 5.0], &["x"]);
+            assert!(lanes.partials[0][0].is_nan(), "{}", src);
+        }
+        let (arena, root) = parse_str("x / 2 + x * 0").unwrap();
+        assert_eq!(interpret_dual(root, &arena, &vars(&[("x", 0.0)]), &["x"]).partials, [0.5]);
+    }
+
+    #[test]
+    fn forward_clamp_follows_the_result() {
+        let (arena, root) = parse_str("clamp(k, x, 1)").unwrap();
+        for (x, slope) in [(3.0, 0.0), (0.5, 1.0), (-2.0, 0.0)] {
+            let g = interpret_dual(root, &arena, &vars(&[("x", x)]), &["x"]);
+            assert_eq!(g.partials, [slope], "x = {}", x);
+            assert_eq!(simd_dual_over_x(root, &arena, &HashMap::new(), &[x], &["x"]).partials[0], [slope]);
+        }
+    }
+
+    #[test]
+    fn reverse_division_by_zero_is_nan() {
+        for (src, at) in [("x / 0", 2.0), ("x / 0", 0.0), ("1 / x", 0.0), ("y / (x - 1)", 1.0)] {
+            let (arena, root) = parse_str(src).unwrap();
+            let g = gradient(root, &arena, &vars(&[("x", at), ("y", 3.0)]));
+            assert!(g.value.is_nan(), "{}", src);
+            assert!(g.partials["x"].is_nan(), "{} at x = {}: {}", src, at, g.partials["x"]);
+            let rows = gradient_rows(root, &arena, &["x"], &[vec![at]]);
+            assert!(rows[0].partials[0].is_nan(), "{}", src);
+        }
+        // Only the division that divides by zero is affected.
+        let (arena, root) = parse_str("x / 2 + x * 0").unwrap();
+        assert_eq!(gradient(root, &arena, &vars(&[("x", 0.0)])).partials["x"], 0.5);
+    }
+
+    #[test]
+    fn reverse_clamp_follows_the_result() {
+        let (arena, root) = parse_str("clamp(k, x, 1)").unwrap();
+        for (x, slope) in [(3.0, 0.0), (0.5, 1.0), (-2.0, 0.0)] {
+            assert_eq!(gradient(root, &arena, &vars(&[("x", x)])).partials["x"], slope, "x = {}", x);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Which names a formula needs and how its statements depend on each other.
+//!
+//! The analysis is static: both branches of a conditional count, and a
+//! user function's body is examined at each call, where its free names
+//! resolve (scoping is dynamic, so they may name the calling function's
+//! parameters). A function that is defined but never called needs nothing.
+
+use crate::lexer::Span;
+use crate::parser::{Arena, ExprKind, NodeId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Analysis {
+    /// Names read without a binding in scope, which the caller must supply,
+    /// with where each is first read.
+    pub free: BTreeMap<String, Span>,
+    /// Names bound by `let` or `=`.
+    pub assigned: BTreeSet<String>,
+    /// Builtin and user functions called, including from function bodies.
+    pub calls: BTreeSet<String>,
+    /// One entry per top-level statement, in order.
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub id: NodeId,
+    /// The name bound by `let` or `=`, or the function defined.
+    pub defines: Option<String>,
+    /// Every name read, including free names of user functions called.
+    pub reads: BTreeSet<String>,
+    /// Indices of earlier statements this one needs: the latest binding of
+    /// each name it reads and the definition of each user function it calls.
+    pub depends_on: BTreeSet<usize>,
+}
+
+impl Analysis {
+    /// Free names not in `provided`, in order.
+    pub fn missing<'a, V>(&'a self, provided: &HashMap<String, V>) -> Vec<&'a str> {
+        self.free.keys().filter(|name| !provided.contains_key(*name)).map(String::as_str).collect()
+    }
+}
+
+/// Analyze the program (or single expression) at `root`.
+pub fn analyze(arena: &Arena, root: NodeId) -> Analysis {
+    let statements = match arena.get(root).map(|e| &e.kind) {
+        Some(ExprKind::Program { statements }) => statements.clone(),
+        _ => vec![root],
+    };
+    let mut analyzer = Analyzer { arena, bindings: HashMap::new(), functions: HashMap::new(), out: Analysis::default() };
+    for (i, stmt) in statements.into_iter().enumerate() {
+        let info = analyzer.statement(i, stmt);
+        analyzer.out.statements.push(info);
+    }
+    analyzer.out
+}
+
+struct Analyzer<'a> {
+    arena: &'a Arena,
+    // Statement that last bound each name, and that defined each function.
+    bindings: HashMap<String, usize>,
+    functions: HashMap<String, usize>,
+    out: Analysis,
+}
+
+impl Analyzer<'_> {
+    fn statement(&mut self, index: usize, id: NodeId) -> Statement {
+        let mut info = Statement { id, defines: None, reads: BTreeSet::new(), depends_on: BTreeSet::new() };
+        match self.arena.get(id).map(|e| &e.kind) {
+            Some(ExprKind::Let { name, value } | ExprKind::Assign { name, value }) => {
+                self.expr(*value, &[], &mut info);
+                self.bindings.insert(name.clone(), index);
+                self.out.assigned.insert(name.clone());
+                info.defines = Some(name.clone());
+            }
+            Some(ExprKind::FnDef { name, .. }) => {
+                self.functions.insert(name.clone(), index);
+                info.defines = Some(name.clone());
+            }
+            _ => self.expr(id, &[], &mut info),
+        }
+        info
+    }
+
+    // Record what `idx` reads; `params` are those of every function call
+    // that `idx` is evaluated within.
+    fn expr(&mut self, idx: NodeId, params: &[String], info: &mut Statement) {
+        let Some(expr) = self.arena.get(idx) else { return };
+        match &expr.kind {
+            ExprKind::Identifier(name) if params.contains(name) => {}
+            ExprKind::Identifier(name) => {
+                info.reads.insert(name.clone());
+                match self.bindings.get(name) {
+                    Some(&i) => {
+                        info.depends_on.insert(i);
+                    }
+                    None => {
+                        self.out.free.entry(name.clone()).or_insert(expr.span);
+                    }
+                }
+            }
+            ExprKind::Call { name, args } => {
+                self.out.calls.insert(name.clone());
+                for &a in args {
+                    self.expr(a, params, info);
+                }
+                if let Some(&i) = self.functions.get(name) {
+                    info.depends_on.insert(i);
+                }
+                let def = self.arena.function(name).and_then(|f| self.arena.get(f));
+                if let Some(ExprKind::FnDef { params: own, body, .. }) = def.map(|d| &d.kind) {
+                    let visible = [params, own.as_slice()].concat();
+                    self.expr(*body, &visible, info);
+                }
+            }
+            kind => kind.for_each_child(|child| self.expr(child, params, info)),
+        }
+    }
+}
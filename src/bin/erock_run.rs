@@ -0,0 +1,45 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+// Run an eRock script: `cargo run --bin erock_run -- test.erock`
+
+use erock::script;
+use std::env;
+use std::fs;
+use std::io;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: erock_run <script.erock>");
+        return ExitCode::from(2);
+    };
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Err(e) = script::run(&source, &mut io::stdout().lock()) else {
+        return ExitCode::SUCCESS;
+    };
+    match e.span() {
+        Some(span) => {
+            eprintln!("{}:{}:{}: error: {}", path, span.start.line, span.start.column, e);
+            // Echo the offending line with a caret under the error. Nodes
+            // built rather than parsed have a default span, on line 0.
+            if let Some(text) = span.start.line.checked_sub(1).and_then(|i| source.lines().nth(i)) {
+                let same_line = span.end.line == span.start.line;
+                let width = if same_line { span.end.column.saturating_sub(span.start.column) } else { 1 };
+                eprintln!("{:>5} | {}", span.start.line, text);
+                eprintln!("      | {}{}", " ".repeat(span.start.column.saturating_sub(1)), "^".repeat(width.max(1)));
+            }
+        }
+        None => eprintln!("{}: error: {}", path, e),
+    }
+    ExitCode::FAILURE
+}
@@ -9,7 +9,7 @@ use std::collections::HashMap;
 fn main() {
     // 1) Build an AST from a formula
     let input = "sum = 3.14 + (x - 2) * 10";
-    let tokens = lexer::tokenize(input);
+    let tokens = lexer::tokenize(input).expect("lex error");
     let (arena, root) = parser::parse(tokens).expect("parse error");
 
     // 2) Scalar example (single x)
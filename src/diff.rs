@@ -0,0 +1,388 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Symbolic differentiation.
+//!
+//! [`differentiate`] first inlines `let`/`=` bindings and user-function
+//! calls, so the derivative is a single expression in the free variables,
+//! then applies the usual rules and simplifies the result with
+//! [`crate::optimize`]. Piecewise-constant operations (comparisons, `&&`,
+//! `||`, `!`, `floor`, `ceil`) have derivative 0; conditionals, `abs`,
+//! `min`, `max` and `clamp` differentiate the branch that is taken.
+
+use crate::lexer::{Span, Token};
+use crate::optimize::optimize;
+use crate::parser::{Arena, ExprKind, NodeId};
+use crate::visit::{copy_functions, copy_tree};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffError {
+    /// A node with no numeric meaning, such as a string or `print`.
+    Unsupported { what: String, span: Span },
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffError::Unsupported { what, span } => write!(f, "cannot differentiate {} at {}", what, span),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {}
+
+/// Derivative of the tree at `root` with respect to the variable `var`,
+/// allocated in `arena` next to the original; returns its root. For a
+/// program this is the derivative of the last statement's value.
+pub fn differentiate(arena: &mut Arena, root: NodeId, var: &str) -> Result<NodeId, DiffError> {
+    // Inlining and the raw derivative are built in a scratch arena, so only
+    // the simplified result is added to `arena`.
+    let mut scratch = Arena::new();
+    copy_functions(arena, &mut scratch);
+    let root = copy_tree(arena, root, &mut scratch);
+    let mut d = Differentiator { arena: &mut scratch, var, scope: 0, scopes: 0, inlined: HashMap::new(), derived: HashMap::new() };
+    let value = d.inline_program(root)?;
+    let raw = match d.derive(value)? {
+        Some(raw) => raw,
+        None => d.num(0.0),
+    };
+    let simplified = optimize(d.arena, raw);
+    Ok(copy_tree(&simplified.arena, simplified.root, arena))
+}
+
+struct Differentiator<'a> {
+    arena: &'a mut Arena,
+    var: &'a str,
+    /// Identifies the names in scope; keys `inlined`. Each new scope gets a
+    /// fresh id from `scopes`, so two calls of one function never share
+    /// an inlined body.
+    scope: u32,
+    scopes: u32,
+    inlined: HashMap<(u32, NodeId), NodeId>,
+    /// Derivatives already built; `None` is a structural zero.
+    derived: HashMap<NodeId, Option<NodeId>>,
+}
+
+impl Differentiator<'_> {
+    fn fresh_scope(&mut self) -> u32 {
+        self.scopes += 1;
+        self.scopes
+    }
+
+    fn unsupported(&self, what: &str, idx: NodeId) -> DiffError {
+        DiffError::Unsupported { what: what.to_string(), span: self.arena[idx].span }
+    }
+
+    // The value of the program at `root` as one expression without
+    // bindings or user-function calls.
+    fn inline_program(&mut self, root: NodeId) -> Result<NodeId, DiffError> {
+        let statements = match &self.arena[root].kind {
+            ExprKind::Program { statements } => statements.clone(),
+            _ => vec![root],
+        };
+        let mut scope = HashMap::new();
+        let mut value = None;
+        for stmt in statements {
+            value = match self.arena[stmt].kind.clone() {
+                ExprKind::Let { name, value } | ExprKind::Assign { name, value } => {
+                    let v = self.inline(value, &scope)?;
+                    scope.insert(name, v);
+                    self.scope = self.fresh_scope();
+                    Some(v)
+                }
+                ExprKind::FnDef { .. } | ExprKind::Print { .. } => None,
+                _ => Some(self.inline(stmt, &scope)?),
+            };
+        }
+        value.ok_or_else(|| self.unsupported("a program without a value", root))
+    }
+
+    // Copy of `idx` with names in `scope` replaced by their values and user
+    // functions inlined; untouched subtrees are reused as they are.
+    fn inline(&mut self, idx: NodeId, scope: &HashMap<String, NodeId>) -> Result<NodeId, DiffError> {
+        if let Some(&done) = self.inlined.get(&(self.scope, idx)) {
+            return Ok(done);
+        }
+        let kind = self.arena[idx].kind.clone();
+        let result = match &kind {
+            ExprKind::Identifier(name) => scope.get(name).copied().unwrap_or(idx),
+            ExprKind::Str(_) => return Err(self.unsupported("a string", idx)),
+            ExprKind::Vector { .. } | ExprKind::Index { .. } => return Err(self.unsupported("a vector", idx)),
+            ExprKind::Call { name, args } if self.arena.function(name).is_some() => {
+                let def = self.arena.function(name).unwrap();
+                let ExprKind::FnDef { params, body, .. } = self.arena[def].kind.clone() else {
+                    return Err(self.unsupported("a call", idx));
+                };
+                let mut inner = scope.clone();
+                for (param, &arg) in params.into_iter().zip(args) {
+                    let arg = self.inline(arg, scope)?;
+                    inner.insert(param, arg);
+                }
+                // The body sees different names than the caller.
+                let outer = self.scope;
+                self.scope = self.fresh_scope();
+                let body = self.inline(body, &inner);
+                self.scope = outer;
+                body?
+            }
+            ExprKind::Unary { .. }
+            | ExprKind::Binary { .. }
+            | ExprKind::Logical { .. }
+            | ExprKind::If { .. }
+            | ExprKind::Call { .. } => {
+                let mut changed = false;
+                let mut failed = None;
+                let mapped = kind.map_children(|child| match self.inline(child, scope) {
+                    Ok(new) => {
+                        changed |= new != child;
+                        new
+                    }
+                    Err(e) => {
+                        failed.get_or_insert(e);
+                        child
+                    }
+                });
+                if let Some(e) = failed {
+                    return Err(e);
+                }
+                if changed {
+                    self.arena.alloc_at(mapped, self.arena[idx].span)
+                } else {
+                    idx
+                }
+            }
+            ExprKind::Number(_) => idx,
+            _ => return Err(self.unsupported("a statement inside an expression", idx)),
+        };
+        self.inlined.insert((self.scope, idx), result);
+        Ok(result)
+    }
+
+    fn num(&mut self, v: f64) -> NodeId {
+        self.arena.alloc(ExprKind::Number(v))
+    }
+
+    fn bin(&mut self, left: NodeId, op: Token, right: NodeId) -> NodeId {
+        self.arena.alloc(ExprKind::Binary { left, op, right })
+    }
+
+    fn neg(&mut self, operand: NodeId) -> NodeId {
+        self.arena.alloc(ExprKind::Unary { op: Token::Minus, operand })
+    }
+
+    fn call(&mut self, name: &str, args: Vec<NodeId>) -> NodeId {
+        self.arena.alloc(ExprKind::Call { name: name.to_string(), args })
+    }
+
+    fn add(&mut self, a: Option<NodeId>, b: Option<NodeId>) -> Option<NodeId> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(self.bin(a, Token::Plus, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn sub(&mut self, a: Option<NodeId>, b: Option<NodeId>) -> Option<NodeId> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(self.bin(a, Token::Minus, b)),
+            (None, Some(b)) => Some(self.neg(b)),
+            (a, None) => a,
+        }
+    }
+
+    // `factor * d`, or zero.
+    fn scale(&mut self, factor: NodeId, d: Option<NodeId>) -> Option<NodeId> {
+        d.map(|d| self.bin(factor, Token::Star, d))
+    }
+
+    // `d / divisor`, or zero.
+    fn over(&mut self, d: Option<NodeId>, divisor: NodeId) -> Option<NodeId> {
+        d.map(|d| self.bin(d, Token::Slash, divisor))
+    }
+
+    fn choose(&mut self, cond: NodeId, then_d: Option<NodeId>, else_d: Option<NodeId>) -> Option<NodeId> {
+        if then_d.is_none() && else_d.is_none() {
+            return None;
+        }
+        let then_branch = then_d.unwrap_or_else(|| self.num(0.0));
+        let else_branch = else_d.unwrap_or_else(|| self.num(0.0));
+        Some(self.arena.alloc(ExprKind::If { cond, then_branch, else_branch }))
+    }
+
+    // Derivative of an inlined expression; `None` when it is identically 0.
+    fn derive(&mut self, idx: NodeId) -> Result<Option<NodeId>, DiffError> {
+        if let Some(&done) = self.derived.get(&idx) {
+            return Ok(done);
+        }
+        let d = match self.arena[idx].kind.clone() {
+            ExprKind::Number(_) => None,
+            ExprKind::Identifier(name) => (name == self.var).then(|| self.num(1.0)),
+            ExprKind::Unary { op: Token::Minus, operand } => {
+                let du = self.derive(operand)?;
+                du.map(|du| self.neg(du))
+            }
+            ExprKind::Unary { op: Token::Plus, operand } => self.derive(operand)?,
+            ExprKind::Unary { .. } | ExprKind::Logical { .. } => None,
+            ExprKind::Binary { left: u, op, right: v } => {
+                let (du, dv) = (self.derive(u)?, self.derive(v)?);
+                match op {
+                    Token::Plus => self.add(du, dv),
+                    Token::Minus => self.sub(du, dv),
+                    Token::Star => {
+                        let a = du.map(|du| self.bin(du, Token::Star, v));
+                        let b = self.scale(u, dv);
+                        self.add(a, b)
+                    }
+                    Token::Slash if dv.is_none() => self.over(du, v),
+                    Token::Slash => {
+                        let a = du.map(|du| self.bin(du, Token::Star, v));
+                        let b = self.scale(u, dv);
+                        let top = self.sub(a, b);
+                        let two = self.num(2.0);
+                        let bottom = self.bin(v, Token::Caret, two);
+                        self.over(top, bottom)
+                    }
+                    Token::Caret => self.derive_power(idx, u, v, du, dv),
+                    _ => None, // comparisons
+                }
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                let (dt, de) = (self.derive(then_branch)?, self.derive(else_branch)?);
+                self.choose(cond, dt, de)
+            }
+            ExprKind::Call { name, args } => {
+                let ds = args.iter().map(|&a| self.derive(a)).collect::<Result<Vec<_>, _>>()?;
+                self.derive_call(idx, &name, &args, &ds)
+            }
+            _ => return Err(self.unsupported("a statement inside an expression", idx)),
+        };
+        self.derived.insert(idx, d);
+        Ok(d)
+    }
+
+    fn derive_power(
+        &mut self,
+        node: NodeId,
+        u: NodeId,
+        v: NodeId,
+        du: Option<NodeId>,
+        dv: Option<NodeId>,
+    ) -> Option<NodeId> {
+        match (du, dv) {
+            (None, None) => None,
+            // v * u^(v - 1) * u'
+            (Some(du), None) => {
+                let one = self.num(1.0);
+                let exp = self.bin(v, Token::Minus, one);
+                let pow = self.bin(u, Token::Caret, exp);
+                let coef = self.bin(v, Token::Star, pow);
+                Some(self.bin(coef, Token::Star, du))
+            }
+            // u^v * ln(u) * v'
+            (None, Some(dv)) => {
+                let ln = self.call("ln", vec![u]);
+                let coef = self.bin(node, Token::Star, ln);
+                Some(self.bin(coef, Token::Star, dv))
+            }
+            // u^v * (v' * ln(u) + v * u' / u)
+            (Some(du), Some(dv)) => {
+                let ln = self.call("ln", vec![u]);
+                let a = self.bin(dv, Token::Star, ln);
+                let vdu = self.bin(v, Token::Star, du);
+                let b = self.bin(vdu, Token::Slash, u);
+                let sum = self.bin(a, Token::Plus, b);
+                Some(self.bin(node, Token::Star, sum))
+            }
+        }
+    }
+
+    // `node` is the call itself, reused where the derivative contains it.
+    fn derive_call(&mut self, node: NodeId, name: &str, args: &[NodeId], ds: &[Option<NodeId>]) -> Option<NodeId> {
+        let (a, da) = (args[0], ds[0]);
+        match name {
+            "sqrt" => {
+                let two = self.num(2.0);
+                let bottom = self.bin(two, Token::Star, node);
+                self.over(da, bottom)
+            }
+            "sin" => {
+                let cos = self.call("cos", vec![a]);
+                self.scale(cos, da)
+            }
+            "cos" => {
+                let sin = self.call("sin", vec![a]);
+                let neg = self.neg(sin);
+                self.scale(neg, da)
+            }
+            "tan" => {
+                let cos = self.call("cos", vec![a]);
+                let two = self.num(2.0);
+                let bottom = self.bin(cos, Token::Caret, two);
+                self.over(da, bottom)
+            }
+            "exp" => self.scale(node, da),
+            "ln" => self.over(da, a),
+            "log10" => {
+                let ten = self.num(10.0);
+                let ln10 = self.call("ln", vec![ten]);
+                let bottom = self.bin(a, Token::Star, ln10);
+                self.over(da, bottom)
+            }
+            "sum" | "mean" => da,
+            "dot" => {
+                let a = self.scale(args[1], ds[0]);
+                let b = self.scale(args[0], ds[1]);
+                self.add(a, b)
+            }
+            "abs" | "norm" => {
+                let zero = self.num(0.0);
+                let negative = self.bin(a, Token::Lt, zero);
+                let flipped = da.map(|da| self.neg(da));
+                self.choose(negative, flipped, da)
+            }
+            // d/dt atan2(y, x) = (x y' - y x') / (x^2 + y^2)
+            "atan2" => {
+                let (y, x, dy, dx) = (args[0], args[1], ds[0], ds[1]);
+                let a = self.scale(x, dy);
+                let b = self.scale(y, dx);
+                let top = self.sub(a, b);
+                let two = self.num(2.0);
+                let x2 = self.bin(x, Token::Caret, two);
+                let y2 = self.bin(y, Token::Caret, two);
+                let bottom = self.bin(x2, Token::Plus, y2);
+                self.over(top, bottom)
+            }
+            "hypot" => {
+                let a = self.scale(args[0], ds[0]);
+                let b = self.scale(args[1], ds[1]);
+                let top = self.add(a, b);
+                self.over(top, node)
+            }
+            // Pairwise, following the argument the running result came from.
+            "min" | "max" => {
+                let op = if name == "min" { Token::Le } else { Token::Ge };
+                let (mut best, mut d) = (a, da);
+                for (&arg, &darg) in args.iter().zip(ds).skip(1) {
+                    let keep = self.bin(best, op.clone(), arg);
+                    d = self.choose(keep, d, darg);
+                    best = self.call(name, vec![best, arg]);
+                }
+                d
+            }
+            // clamp(x, lo, hi) = min(max(x, lo), hi), so `hi` wins whenever
+            // max(x, lo) exceeds it, even if lo > hi.
+            "clamp" => {
+                let (x, lo, hi) = (args[0], args[1], args[2]);
+                let below = self.bin(x, Token::Lt, lo);
+                let inner = self.choose(below, ds[1], ds[0]);
+                let raised = self.call("max", vec![x, lo]);
+                let above = self.bin(raised, Token::Gt, hi);
+                self.choose(above, ds[2], inner)
+            }
+            _ => None, // floor, ceil, len, std, count_if
+        }
+    }
+}
@@ -0,0 +1,315 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Turn an [`Arena`] back into source text.
+//!
+//! Output is canonical: conditionals (including `?:` and `piecewise`) come
+//! out as `if ... then ... else ...`, statements are one per line, and
+//! parentheses appear only where the parser needs them. Feeding the result
+//! to [`crate::parser::parse_str`] gives a tree that evaluates the same.
+
+use crate::lexer::Token;
+use crate::parser::{Arena, ExprKind, NodeId};
+
+/// Style knobs for [`unparse_with`].
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Spaces around binary operators (except `^`), `=`, and after commas.
+    pub spaced: bool,
+    /// Parenthesize every operand that is not a number, name or call,
+    /// instead of only where precedence requires it.
+    pub explicit_parens: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions { spaced: true, explicit_parens: false }
+    }
+}
+
+// Binding strength, loosest first; mirrors the parser's descent order.
+const COND: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const EQUALITY: u8 = 3;
+const COMPARISON: u8 = 4;
+const TERM: u8 = 5;
+const FACTOR: u8 = 6;
+const UNARY: u8 = 7;
+const POWER: u8 = 8;
+const ATOM: u8 = 9;
+
+fn op_str(op: &Token) -> &'static str {
+    match op {
+        Token::Plus => "+",
+        Token::Minus => "-",
+        Token::Star => "*",
+        Token::Slash => "/",
+        Token::Caret => "^",
+        Token::Lt => "<",
+        Token::Le => "<=",
+        Token::Gt => ">",
+        Token::Ge => ">=",
+        Token::EqEq => "==",
+        Token::NotEq => "!=",
+        Token::Bang => "!",
+        Token::AndAnd => "&&",
+        Token::OrOr => "||",
+        _ => "?",
+    }
+}
+
+fn op_prec(op: &Token) -> u8 {
+    match op {
+        Token::OrOr => OR,
+        Token::AndAnd => AND,
+        Token::EqEq | Token::NotEq => EQUALITY,
+        Token::Lt | Token::Le | Token::Gt | Token::Ge => COMPARISON,
+        Token::Plus | Token::Minus => TERM,
+        Token::Star | Token::Slash => FACTOR,
+        Token::Caret => POWER,
+        _ => ATOM,
+    }
+}
+
+/// Text that lexes back to exactly `v`. Very large and very small
+/// magnitudes use an exponent; a negative value reads back as unary minus.
+pub fn format_number(v: f64) -> String {
+    if v.is_nan() {
+        return "nan".to_string();
+    }
+    if v.is_infinite() {
+        return if v > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let a = v.abs();
+    if a >= 1e16 || (a != 0.0 && a < 1e-5) {
+        format!("{:e}", v)
+    } else {
+        v.to_string()
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut out = String::from("'");
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\'' => out.push_str("\\'"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+struct Printer<'a> {
+    arena: &'a Arena,
+    options: &'a FormatOptions,
+    out: String,
+}
+
+/// Source text for the tree at `root` in the default style.
+pub fn unparse(arena: &Arena, root: NodeId) -> String {
+    unparse_with(arena, root, &FormatOptions::default())
+}
+
+pub fn unparse_with(arena: &Arena, root: NodeId, options: &FormatOptions) -> String {
+    let mut printer = Printer { arena, options, out: String::new() };
+    printer.node(root, COND, true);
+    printer.out
+}
+
+impl Printer<'_> {
+    fn prec(&self, idx: NodeId) -> u8 {
+        match self.arena.get(idx).map(|e| &e.kind) {
+            Some(ExprKind::Number(n)) if n.is_sign_negative() && !n.is_nan() => UNARY,
+            Some(ExprKind::Unary { .. }) => UNARY,
+            Some(ExprKind::Binary { op, .. } | ExprKind::Logical { op, .. }) => op_prec(op),
+            Some(ExprKind::If { .. }) => COND,
+            _ => ATOM,
+        }
+    }
+
+    fn spaced_op(&mut self, op: &str) {
+        if self.options.spaced {
+            self.out.push(' ');
+            self.out.push_str(op);
+            self.out.push(' ');
+        } else {
+            self.out.push_str(op);
+        }
+    }
+
+    fn comma(&mut self) {
+        self.out.push_str(if self.options.spaced { ", " } else { "," });
+    }
+
+    // Write `idx` where only something binding at least as tightly as `min`
+    // can appear without parentheses. `tail` means nothing follows it before
+    // a closing token, so a trailing `if`, whose else branch extends as far
+    // as it can, needs none.
+    fn node(&mut self, idx: NodeId, min: u8, tail: bool) {
+        let prec = self.prec(idx);
+        let operand = min > COND;
+        let needs_parens = if self.options.explicit_parens && operand {
+            prec < ATOM
+        } else {
+            prec < min && !(prec == COND && tail)
+        };
+        if needs_parens {
+            self.out.push('(');
+            self.bare(idx, true);
+            self.out.push(')');
+        } else {
+            self.bare(idx, tail);
+        }
+    }
+
+    fn bare(&mut self, idx: NodeId, tail: bool) {
+        let Some(expr) = self.arena.get(idx) else { return };
+        match &expr.kind {
+            ExprKind::Number(n) => self.out.push_str(&format_number(*n)),
+            ExprKind::Identifier(name) => self.out.push_str(name),
+            ExprKind::Str(text) => self.out.push_str(&quote(text)),
+            ExprKind::Unary { op, operand } => {
+                self.out.push_str(op_str(op));
+                self.node(*operand, UNARY, tail);
+            }
+            ExprKind::Binary { left, op: Token::Caret, right } => {
+                // Right-associative, and the exponent may carry a sign.
+                self.node(*left, ATOM, false);
+                self.out.push('^');
+                self.node(*right, UNARY, tail);
+            }
+            ExprKind::Binary { left, op, right } | ExprKind::Logical { left, op, right } => {
+                let prec = op_prec(op);
+                self.node(*left, prec, false);
+                self.spaced_op(op_str(op));
+                self.node(*right, prec + 1, tail);
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                self.out.push_str("if ");
+                self.node(*cond, COND, true);
+                self.out.push_str(" then ");
+                self.node(*then_branch, COND, true);
+                self.out.push_str(" else ");
+                self.node(*else_branch, COND, tail);
+            }
+            ExprKind::Call { name, args } => {
+                self.out.push_str(name);
+                self.out.push('(');
+                for (i, &arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.comma();
+                    }
+                    self.node(arg, COND, true);
+                }
+                self.out.push(')');
+            }
+            ExprKind::Vector { elements } => {
+                self.out.push('[');
+                for (i, &element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        self.comma();
+                    }
+                    self.node(element, COND, true);
+                }
+                self.out.push(']');
+            }
+            ExprKind::Index { target, index } => {
+                self.node(*target, ATOM, false);
+                self.out.push('[');
+                self.node(*index, COND, true);
+                self.out.push(']');
+            }
+            ExprKind::Assign { name, value } => {
+                self.out.push_str(name);
+                self.spaced_op("=");
+                self.node(*value, COND, true);
+            }
+            ExprKind::Let { name, value } => {
+                self.out.push_str("let ");
+                self.out.push_str(name);
+                self.spaced_op("=");
+                self.node(*value, COND, true);
+            }
+            ExprKind::Program { statements } => {
+                for (i, &stmt) in statements.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push('\n');
+                    }
+                    self.node(stmt, COND, true);
+                }
+            }
+            ExprKind::Print { args } => {
+                self.out.push_str("print");
+                for (i, &arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.comma();
+                    } else {
+                        self.out.push(' ');
+                    }
+                    self.node(arg, COND, true);
+                }
+            }
+            ExprKind::FnDef { name, params, body } => {
+                self.out.push_str(name);
+                self.out.push('(');
+                for (i, p) in params.iter().enumerate() {
+                    if i > 0 {
+                        self.comma();
+                    }
+                    self.out.push_str(p);
+                }
+                self.out.push(')');
+                self.spaced_op("=");
+                self.node(*body, COND, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::interpret;
+    use crate::lexer::tokenize;
+    use crate::parser::{parse_str, parse_with, ParseOptions};
+    use std::collections::HashMap;
+
+    fn parse_shared(src: &str) -> (Arena, NodeId) {
+        let options = ParseOptions { hash_cons: true, ..ParseOptions::default() };
+        parse_with(tokenize(src).unwrap(), &options).unwrap()
+    }
+
+    // A shared node is printed at each reference, with the parentheses that
+    // reference needs.
+    #[test]
+    fn hash_consed_trees_round_trip() {
+        let cases = [
+            ("(a - b) - (a - b)", "a - b - (a - b)"),
+            ("(a + b) * c + (a + b)", "(a + b) * c + (a + b)"),
+            ("-(x ^ 2) + x^2 * (x ^ 2)", "-x^2 + x^2 * x^2"),
+            ("f(t) = (t + 1) / (t + 1)^2\nf(a - b) + (a - b)", "f(t) = (t + 1) / (t + 1)^2\nf(a - b) + (a - b)"),
+            ("piecewise(a > b, a - b, b - a) * (a > b)", "(if a > b then a - b else b - a) * (a > b)"),
+        ];
+        let vars = || HashMap::from([("a".to_string(), 5.0), ("b".to_string(), 1.5), ("c".to_string(), -2.0), ("x".to_string(), 3.0)]);
+        for (src, expected) in cases {
+            let (arena, root) = parse_shared(src);
+            assert!(arena.has_shared(), "{}", src);
+            let text = unparse(&arena, root);
+            assert_eq!(text, expected, "{}", src);
+
+            let (plain, plain_root) = parse_str(src).unwrap();
+            assert_eq!(text, unparse(&plain, plain_root), "{}", src);
+            let (again, again_root) = parse_shared(&text);
+            assert_eq!(unparse(&again, again_root), text);
+            assert_eq!(again.len(), arena.len(), "{}", src);
+            assert_eq!(interpret(again_root, &again, &mut vars()), interpret(plain_root, &plain, &mut vars()), "{}", src);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use wide::{f64x4, CmpEq, CmpNe};
+
+/// Number of arguments a function accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    /// An odd count of at least the given size, e.g. `piecewise`'s
+    /// condition/value pairs plus a default.
+    OddAtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(self, n: usize) -> bool {
+        match self {
+            Arity::Exact(k) => n == k,
+            Arity::AtLeast(k) => n >= k,
+            Arity::OddAtLeast(k) => n >= k && n % 2 == 1,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(k) => write!(f, "{}", k),
+            Arity::AtLeast(k) => write!(f, "at least {}", k),
+            Arity::OddAtLeast(k) => write!(f, "an odd number (at least {}) of", k),
+        }
+    }
+}
+
+/// Arithmetic shared by the scalar (`f64`) and SIMD (`f64x4`) evaluators, so
+/// each builtin is written once.
+pub trait Lanes:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self>
+{
+    fn splat(v: f64) -> Self;
+    fn sqrt(self) -> Self;
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn tan(self) -> Self;
+    fn atan2(self, x: Self) -> Self;
+    fn exp(self) -> Self;
+    fn ln(self) -> Self;
+    fn log10(self) -> Self;
+    fn abs(self) -> Self;
+    fn floor(self) -> Self;
+    fn ceil(self) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn max(self, other: Self) -> Self;
+    fn hypot(self, other: Self) -> Self;
+    /// 1 where the value is true (neither 0 nor NaN), else 0.
+    fn truth(self) -> Self;
+}
+
+impl Lanes for f64 {
+    fn splat(v: f64) -> Self { v }
+    fn sqrt(self) -> Self { f64::sqrt(self) }
+    fn sin(self) -> Self { f64::sin(self) }
+    fn cos(self) -> Self { f64::cos(self) }
+    fn tan(self) -> Self { f64::tan(self) }
+    fn atan2(self, x: Self) -> Self { f64::atan2(self, x) }
+    fn exp(self) -> Self { f64::exp(self) }
+    fn ln(self) -> Self { f64::ln(self) }
+    fn log10(self) -> Self { f64::log10(self) }
+    fn abs(self) -> Self { f64::abs(self) }
+    fn floor(self) -> Self { f64::floor(self) }
+    fn ceil(self) -> Self { f64::ceil(self) }
+    fn min(self, other: Self) -> Self { f64::min(self, other) }
+    fn max(self, other: Self) -> Self { f64::max(self, other) }
+    fn hypot(self, other: Self) -> Self { f64::hypot(self, other) }
+    fn truth(self) -> Self { if self != 0.0 && !self.is_nan() { 1.0 } else { 0.0 } }
+}
+
+impl Lanes for f64x4 {
+    fn splat(v: f64) -> Self { f64x4::splat(v) }
+    fn sqrt(self) -> Self { f64x4::sqrt(self) }
+    fn sin(self) -> Self { f64x4::sin(self) }
+    fn cos(self) -> Self { f64x4::cos(self) }
+    fn tan(self) -> Self { f64x4::tan(self) }
+    fn atan2(self, x: Self) -> Self { f64x4::atan2(self, x) }
+    fn exp(self) -> Self { f64x4::exp(self) }
+    fn ln(self) -> Self { f64x4::ln(self) }
+    fn log10(self) -> Self { f64x4::log10(self) }
+    fn abs(self) -> Self { f64x4::abs(self) }
+    fn floor(self) -> Self { f64x4::floor(self) }
+    fn ceil(self) -> Self { f64x4::ceil(self) }
+    fn min(self, other: Self) -> Self { f64x4::min(self, other) }
+    fn max(self, other: Self) -> Self { f64x4::max(self, other) }
+    fn hypot(self, other: Self) -> Self {
+        // Lane-wise to keep f64::hypot's overflow-safe scaling.
+        let (a, b) = (self.to_array(), other.to_array());
+        f64x4::from([a[0].hypot(b[0]), a[1].hypot(b[1]), a[2].hypot(b[2]), a[3].hypot(b[3])])
+    }
+    fn truth(self) -> Self {
+        (self.cmp_ne(f64x4::ZERO) & self.cmp_eq(self)).blend(f64x4::ONE, f64x4::ZERO)
+    }
+}
+
+/// Functions callable from formulas as `name(arg, ...)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Sqrt,
+    Sin,
+    Cos,
+    Tan,
+    Atan2,
+    Exp,
+    Ln,
+    Log10,
+    Abs,
+    Floor,
+    Ceil,
+    Min,
+    Max,
+    Hypot,
+    Clamp,
+    Len,
+    Sum,
+    Mean,
+    Std,
+    Dot,
+    Norm,
+    CountIf,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 22] = [
+        Builtin::Sqrt,
+        Builtin::Sin,
+        Builtin::Cos,
+        Builtin::Tan,
+        Builtin::Atan2,
+        Builtin::Exp,
+        Builtin::Ln,
+        Builtin::Log10,
+        Builtin::Abs,
+        Builtin::Floor,
+        Builtin::Ceil,
+        Builtin::Min,
+        Builtin::Max,
+        Builtin::Hypot,
+        Builtin::Clamp,
+        Builtin::Len,
+        Builtin::Sum,
+        Builtin::Mean,
+        Builtin::Std,
+        Builtin::Dot,
+        Builtin::Norm,
+        Builtin::CountIf,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Builtin::ALL.iter().copied().find(|b| b.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Sqrt => "sqrt",
+            Builtin::Sin => "sin",
+            Builtin::Cos => "cos",
+            Builtin::Tan => "tan",
+            Builtin::Atan2 => "atan2",
+            Builtin::Exp => "exp",
+            Builtin::Ln => "ln",
+            Builtin::Log10 => "log10",
+            Builtin::Abs => "abs",
+            Builtin::Floor => "floor",
+            Builtin::Ceil => "ceil",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Hypot => "hypot",
+            Builtin::Clamp => "clamp",
+            Builtin::Len => "len",
+            Builtin::Sum => "sum",
+            Builtin::Mean => "mean",
+            Builtin::Std => "std",
+            Builtin::Dot => "dot",
+            Builtin::Norm => "norm",
+            Builtin::CountIf => "count_if",
+        }
+    }
+
+    pub fn arity(self) -> Arity {
+        match self {
+            Builtin::Atan2 | Builtin::Hypot | Builtin::Dot => Arity::Exact(2),
+            Builtin::Clamp => Arity::Exact(3),
+            // One argument reduces a vector; see `crate::reduce`.
+            Builtin::Min | Builtin::Max => Arity::AtLeast(1),
+            _ => Arity::Exact(1),
+        }
+    }
+
+    /// Apply to `args`, which must satisfy [`Builtin::arity`]; otherwise NaN.
+    pub fn eval<T: Lanes>(self, args: &[T]) -> T {
+        if !self.arity().accepts(args.len()) {
+            return T::splat(f64::NAN);
+        }
+        let a = args[0];
+        match self {
+            Builtin::Sqrt => a.sqrt(),
+            Builtin::Sin => a.sin(),
+            Builtin::Cos => a.cos(),
+            Builtin::Tan => a.tan(),
+            Builtin::Atan2 => a.atan2(args[1]),
+            Builtin::Exp => a.exp(),
+            Builtin::Ln => a.ln(),
+            Builtin::Log10 => a.log10(),
+            Builtin::Abs => a.abs(),
+            Builtin::Floor => a.floor(),
+            Builtin::Ceil => a.ceil(),
+            Builtin::Min => args[1..].iter().fold(a, |m, &v| m.min(v)),
+            Builtin::Max => args[1..].iter().fold(a, |m, &v| m.max(v)),
+            Builtin::Hypot => a.hypot(args[1]),
+            // Not f64::clamp, which panics when lo > hi.
+            Builtin::Clamp => a.max(args[1]).min(args[2]),
+            // Only vectors have a length; see `crate::value`.
+            Builtin::Len => T::splat(f64::NAN),
+            // Reductions of a scalar, as a vector of one element.
+            Builtin::Sum | Builtin::Mean => a,
+            Builtin::Std => a.abs() * T::splat(0.0),
+            Builtin::Dot => a * args[1],
+            Builtin::Norm => a.abs(),
+            Builtin::CountIf => a.truth(),
+        }
+    }
+}
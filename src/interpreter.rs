@@ -3,56 +3,109 @@ SPDX-FileCopyrightText: 2025 Eric Waller
 SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
 */
 
+use crate::functions::Builtin;
 use crate::lexer::Token;
-use crate::parser::{Arena, ExprKind};
+use crate::optimize::optimize;
+use crate::parser::{Arena, ExprKind, NodeId};
+use crate::value::{self, interpret_value};
 use std::collections::HashMap;
 
 // ========== Scalar interpreter ==========
-pub fn interpret(root_idx: usize, arena: &Arena, variables: &mut HashMap<String, f64>) -> f64 {
-    interpret_node(root_idx, arena, variables)
+// Truth semantics for predicates: comparisons and logical operators yield
+// 1.0 or 0.0; any operand that is neither 0.0 nor NaN counts as true.
+#[inline]
+pub fn truthy(v: f64) -> bool {
+    v != 0.0 && !v.is_nan()
 }
 
-fn interpret_node(idx: usize, arena: &Arena, variables: &mut HashMap<String, f64>) -> f64 {
-    if let Some(expr) = arena.get(idx) {
-        match &expr.kind {
-            ExprKind::Number(n) => *n,
-            ExprKind::Identifier(name) => variables.get(name).copied().unwrap_or(0.0),
-            ExprKind::Binary { left, op, right } => {
-                let l = interpret_node(*left, arena, variables);
-                let r = interpret_node(*right, arena, variables);
-                match op {
-                    Token::Plus  => l + r,
-                    Token::Minus => l - r,
-                    Token::Star  => l * r,
-                    Token::Slash => if r != 0.0 { l / r } else { f64::NAN },
-                    _ => f64::NAN,
-                }
-            }
-            ExprKind::Assign { name, value } => {
-                let v = interpret_node(*value, arena, variables);
-                variables.insert(name.clone(), v);
-                v
-            }
+#[inline]
+pub(crate) fn from_bool(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
+
+pub(crate) fn binary_op(op: &Token, l: f64, r: f64) -> f64 {
+    match op {
+        Token::Plus  => l + r,
+        Token::Minus => l - r,
+        Token::Star  => l * r,
+        Token::Slash => if r != 0.0 { l / r } else { f64::NAN },
+        Token::Caret => l.powf(r),
+        Token::Lt    => from_bool(l < r),
+        Token::Le    => from_bool(l <= r),
+        Token::Gt    => from_bool(l > r),
+        Token::Ge    => from_bool(l >= r),
+        Token::EqEq  => from_bool(l == r),
+        Token::NotEq => from_bool(l != r),
+        _ => f64::NAN,
+    }
+}
+
+// Values of shared nodes (see `Arena::is_shared`) already computed during
+// one evaluation, so each is computed once. A binding change can alter what
+// a shared node means, so every `let`, `=` and user-function call starts a
+// new epoch, which drops them all.
+pub(crate) struct Memo<T> {
+    epoch: u32,
+    slots: Vec<(u32, T)>,
+}
+
+impl<T: Clone> Memo<T> {
+    pub(crate) fn new(arena: &Arena, fill: T) -> Self {
+        let len = if arena.has_shared() { arena.len() } else { 0 };
+        Memo { epoch: 1, slots: vec![(0, fill); len] }
+    }
+
+    pub(crate) fn get(&self, id: NodeId) -> Option<T> {
+        self.slots.get(id.index()).filter(|(epoch, _)| *epoch == self.epoch).map(|(_, v)| v.clone())
+    }
+
+    pub(crate) fn set(&mut self, id: NodeId, v: T) {
+        if let Some(slot) = self.slots.get_mut(id.index()) {
+            *slot = (self.epoch, v);
+        }
+    }
+
+    pub(crate) fn invalidate(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        if self.epoch == 0 {
+            self.slots.iter_mut().for_each(|slot| slot.0 = 0);
+            self.epoch = 1;
         }
-    } else {
-        f64::NAN
     }
 }
 
-// ========== Batch (simple) ==========
-pub fn batch_interpret(root_indices: &[usize], arena: &Arena, variables: &mut HashMap<String, f64>) -> Vec<f64> {
-    let mut out = Vec::with_capacity(root_indices.len());
-    for &idx in root_indices {
-        out.push(interpret_node(idx, arena, variables));
+// Parameters and body of the user-defined function `name`.
+pub(crate) fn user_function<'a>(arena: &'a Arena, name: &str) -> Option<(&'a [String], NodeId)> {
+    match &arena.get(arena.function(name)?)?.kind {
+        ExprKind::FnDef { params, body, .. } => Some((params, *body)),
+        _ => None,
     }
-    out
 }
 
-// ========== Cranelift JIT (demo) ==========
+/// Evaluate the tree at `root_idx` with numeric variables; unbound names
+/// read as `0` and `=` writes back to `variables`. This is
+/// [`interpret_value`] with every value a number: a vector result, or a
+/// vector assigned to a variable, comes out as NaN.
+pub fn interpret(root_idx: NodeId, arena: &Arena, variables: &mut HashMap<String, f64>) -> f64 {
+    let mut values: HashMap<String, value::Value> = variables.iter().map(|(name, &v)| (name.clone(), v.into())).collect();
+    let v = interpret_value(root_idx, arena, &mut values);
+    for (name, value) in values {
+        variables.insert(name, value.as_scalar().unwrap_or(f64::NAN));
+    }
+    v.as_scalar().unwrap_or(f64::NAN)
+}
+
+// ========== Batch (simple) ==========
+pub fn batch_interpret(root_indices: &[NodeId], arena: &Arena, variables: &mut HashMap<String, f64>) -> Vec<f64> {
+    root_indices.iter().map(|&idx| interpret(idx, arena, variables)).collect()
+}
+
+// ========== Cranelift JIT ==========
 use cranelift::prelude::*;
+use cranelift_codegen::ir::FuncRef;
 use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{Linkage, Module};
+use cranelift_module::{FuncId, Linkage, Module};
 use cranelift_codegen::isa::CallConv;
 
 #[inline]
@@ -63,35 +116,92 @@ fn target_callconv() -> CallConv {
     { CallConv::SystemV }
 }
 
+// Cranelift has no pow instruction; compiled code calls back into Rust.
+extern "C" fn jit_pow(base: f64, exp: f64) -> f64 {
+    base.powf(exp)
+}
+
+// Builtins without a Cranelift instruction go through these, keyed by the
+// builtin's index in `Builtin::ALL`.
+extern "C" fn jit_builtin1(code: i64, a: f64) -> f64 {
+    Builtin::ALL[code as usize].eval(&[a])
+}
+
+extern "C" fn jit_builtin2(code: i64, a: f64, b: f64) -> f64 {
+    Builtin::ALL[code as usize].eval(&[a, b])
+}
+
+extern "C" fn jit_builtin3(code: i64, a: f64, b: f64, c: f64) -> f64 {
+    Builtin::ALL[code as usize].eval(&[a, b, c])
+}
+
+// Rust helpers callable from compiled code: (symbol, address, f64 arity,
+// whether a leading i64 builtin code is passed).
+fn jit_symbols() -> Vec<(&'static str, *const u8, usize, bool)> {
+    vec![
+        ("jit_pow", jit_pow as *const u8, 2, false),
+        ("jit_builtin1", jit_builtin1 as *const u8, 1, true),
+        ("jit_builtin2", jit_builtin2 as *const u8, 2, true),
+        ("jit_builtin3", jit_builtin3 as *const u8, 3, true),
+    ]
+}
+
 pub struct ExprJit {
     _module: JITModule,
-    func: extern "C" fn() -> f64,
+    func: extern "C" fn(*const f64) -> f64,
+    params: Vec<String>,
 }
 
 impl ExprJit {
     pub fn new(left: f64, op: Token, right: f64) -> Result<Self, String> {
-        let builder = JITBuilder::new(cranelift_module::default_libcall_names())
+        let mut arena = Arena::new();
+        let l = arena.alloc(ExprKind::Number(left));
+        let r = arena.alloc(ExprKind::Number(right));
+        let root = arena.alloc(ExprKind::Binary { left: l, op, right: r });
+        Self::compile(root, &arena, &[])
+    }
+
+    /// Compile the tree at `root` to native code. Identifiers listed in
+    /// `params` become the arguments of [`ExprJit::eval_with`], in order;
+    /// any other identifier is a compile error.
+    pub fn compile(root_idx: NodeId, arena: &Arena, params: &[&str]) -> Result<Self, String> {
+        let mut builder = JITBuilder::new(cranelift_module::default_libcall_names())
             .map_err(|e| e.to_string())?;
+        for (name, addr, _, _) in jit_symbols() {
+            builder.symbol(name, addr);
+        }
         let mut module = JITModule::new(builder);
+        let ptr_ty = module.target_config().pointer_type();
 
         let mut ctx = module.make_context();
         ctx.func.signature = Signature::new(target_callconv());
+        ctx.func.signature.params.push(AbiParam::new(ptr_ty));
         ctx.func.signature.returns.push(AbiParam::new(types::F64));
 
         let mut fctx = FunctionBuilderContext::new();
         let mut fb = FunctionBuilder::new(&mut ctx.func, &mut fctx);
         let entry = fb.create_block();
+        fb.append_block_params_for_function_params(entry);
         fb.switch_to_block(entry);
         fb.seal_block(entry);
 
-        let l = fb.ins().f64const(left);
-        let r = fb.ins().f64const(right);
-        let res = match op {
-            Token::Plus  => fb.ins().fadd(l, r),
-            Token::Minus => fb.ins().fsub(l, r),
-            Token::Star  => fb.ins().fmul(l, r),
-            Token::Slash => fb.ins().fdiv(l, r),
-            _ => l,
+        let args_ptr = fb.block_params(entry)[0];
+        let mut scope = HashMap::new();
+        for (i, name) in params.iter().enumerate() {
+            let v = fb.ins().load(types::F64, MemFlags::trusted(), args_ptr, (i * 8) as i32);
+            scope.insert(name.to_string(), v);
+        }
+
+        let res = {
+            let mut cg = JitCodegen {
+                fb: &mut fb,
+                module: &mut module,
+                arena,
+                scope,
+                imports: HashMap::new(),
+                shared: vec![HashMap::new()],
+            };
+            cg.expr(root_idx)?
         };
         fb.ins().return_(&[res]);
         fb.finalize();
@@ -104,52 +214,456 @@ impl ExprJit {
         module.finalize_definitions().map_err(|e| e.to_string())?;
 
         let code_ptr = module.get_finalized_function(func_id);
-        let func: extern "C" fn() -> f64 =
-            unsafe { std::mem::transmute::<*const u8, extern "C" fn() -> f64>(code_ptr) };
+        let func: extern "C" fn(*const f64) -> f64 =
+            unsafe { std::mem::transmute::<*const u8, extern "C" fn(*const f64) -> f64>(code_ptr) };
+
+        let params = params.iter().map(|p| p.to_string()).collect();
+        Ok(ExprJit { _module: module, func, params })
+    }
+
+    pub fn eval(&self) -> f64 { self.eval_with(&[]) }
+
+    /// Run the compiled code; `args` must match the `params` it was compiled with.
+    pub fn eval_with(&self, args: &[f64]) -> f64 {
+        assert_eq!(args.len(), self.params.len(), "ExprJit expects {} argument(s)", self.params.len());
+        (self.func)(args.as_ptr())
+    }
+
+    pub fn params(&self) -> &[String] { &self.params }
+}
+
+struct JitCodegen<'a, 'b> {
+    fb: &'a mut FunctionBuilder<'b>,
+    module: &'a mut JITModule,
+    arena: &'a Arena,
+    scope: HashMap<String, Value>,
+    imports: HashMap<&'static str, FuncRef>,
+    /// Values of shared nodes already emitted, one frame per enclosing
+    /// branch; a frame is dropped when its branch ends, since its values
+    /// do not dominate the code after it.
+    shared: Vec<HashMap<NodeId, Value>>,
+}
+
+impl JitCodegen<'_, '_> {
+    fn call_helper(&mut self, name: &'static str, args: &[Value]) -> Result<Value, String> {
+        let func = match self.imports.get(name) {
+            Some(f) => *f,
+            None => {
+                let (arity, coded) = jit_symbols()
+                    .into_iter()
+                    .find(|(n, _, _, _)| *n == name)
+                    .map(|(_, _, arity, coded)| (arity, coded))
+                    .ok_or_else(|| format!("no JIT helper named {}", name))?;
+                let mut sig = Signature::new(target_callconv());
+                if coded {
+                    sig.params.push(AbiParam::new(types::I64));
+                }
+                for _ in 0..arity {
+                    sig.params.push(AbiParam::new(types::F64));
+                }
+                sig.returns.push(AbiParam::new(types::F64));
+                let id: FuncId = self
+                    .module
+                    .declare_function(name, Linkage::Import, &sig)
+                    .map_err(|e| e.to_string())?;
+                let f = self.module.declare_func_in_func(id, self.fb.func);
+                self.imports.insert(name, f);
+                f
+            }
+        };
+        let call = self.fb.ins().call(func, args);
+        Ok(self.fb.inst_results(call)[0])
+    }
+
+    fn expr(&mut self, idx: NodeId) -> Result<Value, String> {
+        if !self.arena.is_shared(idx) {
+            return self.emit(idx);
+        }
+        if let Some(&v) = self.shared.iter().rev().find_map(|frame| frame.get(&idx)) {
+            return Ok(v);
+        }
+        let v = self.emit(idx)?;
+        self.shared.last_mut().expect("outermost frame").insert(idx, v);
+        Ok(v)
+    }
+
+    // Compile `idx` inside a conditionally executed block.
+    fn branch(&mut self, idx: NodeId) -> Result<Value, String> {
+        self.shared.push(HashMap::new());
+        let v = self.expr(idx);
+        self.shared.pop();
+        v
+    }
+
+    // Bindings changed, so no shared value can be reused.
+    fn forget_shared(&mut self) {
+        self.shared.iter_mut().for_each(HashMap::clear);
+    }
+
+    fn emit(&mut self, idx: NodeId) -> Result<Value, String> {
+        let expr = self.arena.get(idx).ok_or_else(|| format!("node {} out of bounds", idx))?;
+        match &expr.kind {
+            ExprKind::Number(n) => Ok(self.fb.ins().f64const(*n)),
+            ExprKind::Identifier(name) => self
+                .scope
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("unbound identifier '{}' at {}", name, expr.span)),
+            ExprKind::Unary { op, operand } => {
+                let v = self.expr(*operand)?;
+                match op {
+                    Token::Minus => Ok(self.fb.ins().fneg(v)),
+                    Token::Plus => Ok(v),
+                    Token::Bang => {
+                        let t = self.truthy(v);
+                        let f = self.fb.ins().bxor_imm(t, 1);
+                        Ok(self.bool_to_f64(f))
+                    }
+                    other => Err(format!("unsupported unary operator {}", other)),
+                }
+            }
+            ExprKind::Binary { left, op, right } => {
+                let l = self.expr(*left)?;
+                let r = self.expr(*right)?;
+                match op {
+                    Token::Plus  => Ok(self.fb.ins().fadd(l, r)),
+                    Token::Minus => Ok(self.fb.ins().fsub(l, r)),
+                    Token::Star  => Ok(self.fb.ins().fmul(l, r)),
+                    Token::Slash => Ok(self.fb.ins().fdiv(l, r)),
+                    Token::Caret => self.call_helper("jit_pow", &[l, r]),
+                    Token::Lt    => Ok(self.compare(FloatCC::LessThan, l, r)),
+                    Token::Le    => Ok(self.compare(FloatCC::LessThanOrEqual, l, r)),
+                    Token::Gt    => Ok(self.compare(FloatCC::GreaterThan, l, r)),
+                    Token::Ge    => Ok(self.compare(FloatCC::GreaterThanOrEqual, l, r)),
+                    Token::EqEq  => Ok(self.compare(FloatCC::Equal, l, r)),
+                    Token::NotEq => Ok(self.compare(FloatCC::NotEqual, l, r)),
+                    other => Err(format!("unsupported binary operator {}", other)),
+                }
+            }
+            ExprKind::Logical { left, op, right } => {
+                // Branch around the right side so it is only evaluated when needed.
+                let l = self.expr(*left)?;
+                let lt = self.truthy(l);
+                let rhs_block = self.fb.create_block();
+                let merge = self.fb.create_block();
+                self.fb.append_block_param(merge, types::F64);
+                let short = self.fb.ins().f64const(if *op == Token::OrOr { 1.0 } else { 0.0 });
+                match op {
+                    Token::AndAnd => self.fb.ins().brif(lt, rhs_block, &[], merge, &[short]),
+                    Token::OrOr => self.fb.ins().brif(lt, merge, &[short], rhs_block, &[]),
+                    other => return Err(format!("unsupported logical operator {}", other)),
+                };
+                self.fb.seal_block(rhs_block);
+
+                self.fb.switch_to_block(rhs_block);
+                let r = self.branch(*right)?;
+                let rt = self.truthy(r);
+                let rv = self.bool_to_f64(rt);
+                self.fb.ins().jump(merge, &[rv]);
+                self.fb.seal_block(merge);
 
-        Ok(ExprJit { _module: module, func })
+                self.fb.switch_to_block(merge);
+                Ok(self.fb.block_params(merge)[0])
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                let c = self.expr(*cond)?;
+                let ct = self.truthy(c);
+                let then_block = self.fb.create_block();
+                let else_block = self.fb.create_block();
+                let merge = self.fb.create_block();
+                self.fb.append_block_param(merge, types::F64);
+                self.fb.ins().brif(ct, then_block, &[], else_block, &[]);
+                self.fb.seal_block(then_block);
+                self.fb.seal_block(else_block);
+
+                self.fb.switch_to_block(then_block);
+                let t = self.branch(*then_branch)?;
+                self.fb.ins().jump(merge, &[t]);
+
+                self.fb.switch_to_block(else_block);
+                let e = self.branch(*else_branch)?;
+                self.fb.ins().jump(merge, &[e]);
+                self.fb.seal_block(merge);
+
+                self.fb.switch_to_block(merge);
+                Ok(self.fb.block_params(merge)[0])
+            }
+            ExprKind::Call { name, args } => {
+                let vals = args.iter().map(|&a| self.expr(a)).collect::<Result<Vec<_>, _>>()?;
+                if let Some(builtin) = Builtin::from_name(name) {
+                    return self.builtin(builtin, &vals);
+                }
+                // User functions are inlined at each call site.
+                let (params, body) = user_function(self.arena, name)
+                    .ok_or_else(|| format!("unknown function '{}'", name))?;
+                if params.len() != vals.len() {
+                    return Err(format!("'{}' takes {} argument(s)", name, params.len()));
+                }
+                let saved = self.scope.clone();
+                self.scope.extend(params.iter().cloned().zip(vals));
+                self.forget_shared();
+                let v = self.expr(body);
+                self.scope = saved;
+                self.forget_shared();
+                v
+            }
+            ExprKind::FnDef { .. } | ExprKind::Str(_) | ExprKind::Print { .. } => {
+                Ok(self.fb.ins().f64const(f64::NAN))
+            }
+            ExprKind::Vector { .. } | ExprKind::Index { .. } => {
+                Err(format!("vectors are not supported by the JIT (at {})", expr.span))
+            }
+            ExprKind::Assign { name, value } | ExprKind::Let { name, value } => {
+                let v = self.expr(*value)?;
+                self.scope.insert(name.clone(), v);
+                self.forget_shared();
+                Ok(v)
+            }
+            ExprKind::Program { statements } => {
+                let saved = self.scope.clone();
+                let mut v = None;
+                for &stmt in statements {
+                    v = Some(self.expr(stmt)?);
+                }
+                self.scope = saved;
+                self.forget_shared();
+                v.ok_or_else(|| "empty program".to_string())
+            }
+        }
     }
 
-    pub fn eval(&self) -> f64 { (self.func)() }
+    // i8 flag: 1 when `v` is truthy (neither 0.0 nor NaN).
+    fn truthy(&mut self, v: Value) -> Value {
+        let zero = self.fb.ins().f64const(0.0);
+        self.fb.ins().fcmp(FloatCC::OrderedNotEqual, v, zero)
+    }
+
+    fn bool_to_f64(&mut self, flag: Value) -> Value {
+        let one = self.fb.ins().f64const(1.0);
+        let zero = self.fb.ins().f64const(0.0);
+        self.fb.ins().select(flag, one, zero)
+    }
+
+    fn compare(&mut self, cc: FloatCC, l: Value, r: Value) -> Value {
+        let flag = self.fb.ins().fcmp(cc, l, r);
+        self.bool_to_f64(flag)
+    }
+
+    fn builtin(&mut self, builtin: Builtin, vals: &[Value]) -> Result<Value, String> {
+        if !builtin.arity().accepts(vals.len()) {
+            return Err(format!("'{}' takes {} argument(s)", builtin.name(), builtin.arity()));
+        }
+        let code = Builtin::ALL.iter().position(|&b| b == builtin).unwrap() as i64;
+        match (builtin, vals) {
+            (Builtin::Sqrt, [a]) => Ok(self.fb.ins().sqrt(*a)),
+            (Builtin::Abs, [a]) => Ok(self.fb.ins().fabs(*a)),
+            (Builtin::Floor, [a]) => Ok(self.fb.ins().floor(*a)),
+            (Builtin::Ceil, [a]) => Ok(self.fb.ins().ceil(*a)),
+            // Variadic min/max fold pairwise through the helper so NaN
+            // handling matches the interpreter.
+            (Builtin::Min | Builtin::Max, [first, rest @ ..]) => {
+                let mut acc = *first;
+                for &v in rest {
+                    let code = self.fb.ins().iconst(types::I64, code);
+                    acc = self.call_helper("jit_builtin2", &[code, acc, v])?;
+                }
+                Ok(acc)
+            }
+            (_, [a]) => {
+                let code = self.fb.ins().iconst(types::I64, code);
+                self.call_helper("jit_builtin1", &[code, *a])
+            }
+            (_, [a, b]) => {
+                let code = self.fb.ins().iconst(types::I64, code);
+                self.call_helper("jit_builtin2", &[code, *a, *b])
+            }
+            (_, [a, b, c]) => {
+                let code = self.fb.ins().iconst(types::I64, code);
+                self.call_helper("jit_builtin3", &[code, *a, *b, *c])
+            }
+            _ => Err(format!("unsupported call to '{}'", builtin.name())),
+        }
+    }
 }
 
 // ========== Stable SIMD using wide::f64x4 ==========
-use wide::f64x4;
+use wide::{f64x4, CmpEq, CmpGe, CmpGt, CmpLe, CmpLt, CmpNe};
 type Vf64 = f64x4;
 
-// SIMD evaluator for a given x vector. Other identifiers are splats.
-fn interpret_node_simd(idx: usize, arena: &Arena, variables: &HashMap<String, f64>, x: Vf64) -> Vf64 {
+// Lane mask (all bits set = true) of the lanes that are truthy.
+#[inline]
+pub(crate) fn truthy_mask(v: Vf64) -> Vf64 {
+    v.cmp_ne(Vf64::ZERO) & v.cmp_eq(v)
+}
+
+// Lane mask to 1.0 / 0.0 values.
+#[inline]
+fn mask_to_f64(m: Vf64) -> Vf64 {
+    m.blend(Vf64::ONE, Vf64::ZERO)
+}
+
+// Lane-wise `f64::powf`: `wide`'s `pow_f64x4` goes through `exp(ln)` and is
+// wrong for a negative base (`(-2)^3`) and for `0^0.5`.
+#[inline]
+fn pow_lanes(base: Vf64, exp: Vf64) -> Vf64 {
+    let (b, e) = (base.to_array(), exp.to_array());
+    Vf64::from([b[0].powf(e[0]), b[1].powf(e[1]), b[2].powf(e[2]), b[3].powf(e[3])])
+}
+
+pub(crate) fn binary_op_simd(op: &Token, l: Vf64, r: Vf64) -> Vf64 {
+    match op {
+        Token::Plus  => l + r,
+        Token::Minus => l - r,
+        Token::Star  => l * r,
+        Token::Slash => l / r,
+        Token::Caret => pow_lanes(l, r),
+        Token::Lt    => mask_to_f64(l.cmp_lt(r)),
+        Token::Le    => mask_to_f64(l.cmp_le(r)),
+        Token::Gt    => mask_to_f64(l.cmp_gt(r)),
+        Token::Ge    => mask_to_f64(l.cmp_ge(r)),
+        Token::EqEq  => mask_to_f64(l.cmp_eq(r)),
+        Token::NotEq => mask_to_f64(l.cmp_ne(r)),
+        _ => l,
+    }
+}
+
+// SIMD evaluator for a given x vector. Other identifiers are splats, unless
+// bound earlier in the program by `let` or `=` (kept in `locals`, never
+// written back to `variables`).
+struct SimdEnv<'v> {
+    variables: &'v HashMap<String, f64>,
+    locals: Vec<(String, Vf64)>,
+    x: Vf64,
+    memo: Memo<Vf64>,
+}
+
+impl SimdEnv<'_> {
+    fn get(&self, name: &str) -> Vf64 {
+        match self.locals.iter().rev().find(|(n, _)| n == name) {
+            Some((_, v)) => *v,
+            None if name == "x" => self.x,
+            None => Vf64::splat(*self.variables.get(name).unwrap_or(&0.0)),
+        }
+    }
+}
+
+fn interpret_node_simd(idx: NodeId, arena: &Arena, env: &mut SimdEnv) -> Vf64 {
+    if !arena.is_shared(idx) {
+        return eval_node_simd(idx, arena, env);
+    }
+    if let Some(v) = env.memo.get(idx) {
+        return v;
+    }
+    let v = eval_node_simd(idx, arena, env);
+    env.memo.set(idx, v);
+    v
+}
+
+fn eval_node_simd(idx: NodeId, arena: &Arena, env: &mut SimdEnv) -> Vf64 {
     if let Some(expr) = arena.get(idx) {
         match &expr.kind {
             ExprKind::Number(n) => Vf64::splat(*n),
-            ExprKind::Identifier(name) => {
-                if name == "x" { x } else { Vf64::splat(*variables.get(name).unwrap_or(&0.0)) }
+            ExprKind::Identifier(name) => env.get(name),
+            ExprKind::Unary { op, operand } => {
+                let v = interpret_node_simd(*operand, arena, env);
+                match op {
+                    Token::Minus => -v,
+                    Token::Bang  => mask_to_f64(!truthy_mask(v)),
+                    _ => v,
+                }
             }
             ExprKind::Binary { left, op, right } => {
-                let l = interpret_node_simd(*left, arena, variables, x);
-                let r = interpret_node_simd(*right, arena, variables, x);
-                match op {
-                    Token::Plus  => l + r,
-                    Token::Minus => l - r,
-                    Token::Star  => l * r,
-                    Token::Slash => l / r,
-                    _ => l,
+                let l = interpret_node_simd(*left, arena, env);
+                let r = interpret_node_simd(*right, arena, env);
+                binary_op_simd(op, l, r)
+            }
+            ExprKind::Logical { left, op, right } => {
+                let l = truthy_mask(interpret_node_simd(*left, arena, env));
+                // Skip the right side when the left decides every lane.
+                let m = match op {
+                    Token::AndAnd if l.none() => l,
+                    Token::OrOr if l.all() => l,
+                    Token::AndAnd => l & truthy_mask(interpret_node_simd(*right, arena, env)),
+                    _ => l | truthy_mask(interpret_node_simd(*right, arena, env)),
+                };
+                mask_to_f64(m)
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                // Blend per lane, skipping a branch no lane takes.
+                let m = truthy_mask(interpret_node_simd(*cond, arena, env));
+                if m.all() {
+                    interpret_node_simd(*then_branch, arena, env)
+                } else if m.none() {
+                    interpret_node_simd(*else_branch, arena, env)
+                } else {
+                    let t = interpret_node_simd(*then_branch, arena, env);
+                    let e = interpret_node_simd(*else_branch, arena, env);
+                    m.blend(t, e)
+                }
+            }
+            ExprKind::Call { name, args } => {
+                let vals: Vec<Vf64> = args.iter().map(|&a| interpret_node_simd(a, arena, env)).collect();
+                if let Some(builtin) = Builtin::from_name(name) {
+                    return builtin.eval(&vals);
+                }
+                let Some((params, body)) = user_function(arena, name) else { return Vf64::splat(f64::NAN) };
+                if params.len() != vals.len() {
+                    return Vf64::splat(f64::NAN);
                 }
+                let scope = env.locals.len();
+                env.locals.extend(params.iter().cloned().zip(vals));
+                env.memo.invalidate();
+                let v = interpret_node_simd(body, arena, env);
+                env.locals.truncate(scope);
+                env.memo.invalidate();
+                v
+            }
+            ExprKind::Assign { name, value } | ExprKind::Let { name, value } => {
+                // Bind for later statements only; the caller's map is read-only here.
+                let v = interpret_node_simd(*value, arena, env);
+                env.locals.push((name.clone(), v));
+                env.memo.invalidate();
+                v
             }
-            ExprKind::Assign { value, .. } => {
-                // Pure-eval (no mutation) for throughput
-                interpret_node_simd(*value, arena, variables, x)
+            ExprKind::Program { statements } => {
+                let scope = env.locals.len();
+                let mut v = Vf64::splat(f64::NAN);
+                for &stmt in statements {
+                    v = interpret_node_simd(stmt, arena, env);
+                }
+                env.locals.truncate(scope);
+                env.memo.invalidate();
+                v
             }
+            // Vectors never get here: see `simd_eval_over_x`.
+            ExprKind::FnDef { .. }
+            | ExprKind::Str(_)
+            | ExprKind::Print { .. }
+            | ExprKind::Vector { .. }
+            | ExprKind::Index { .. } => Vf64::splat(f64::NAN),
         }
     } else {
         Vf64::splat(f64::NAN)
     }
 }
 
-// Evaluate across a slice of x values using 4‑wide lanes.
-pub fn simd_eval_over_x(root_idx: usize, arena: &Arena, variables: &HashMap<String, f64>, xs: &[f64]) -> Vec<f64> {
+// Evaluate across a slice of x values using 4‑wide lanes. Lanes hold one
+// number each, so an arena with vector literals or indexing is evaluated
+// one x at a time by `interpret` instead.
+pub fn simd_eval_over_x(root_idx: NodeId, arena: &Arena, variables: &HashMap<String, f64>, xs: &[f64]) -> Vec<f64> {
+    if arena.iter().any(|(_, e)| matches!(e.kind, ExprKind::Vector { .. } | ExprKind::Index { .. })) {
+        return xs
+            .iter()
+            .map(|&x| {
+                let mut vars = variables.clone();
+                vars.insert("x".to_string(), x);
+                interpret(root_idx, arena, &mut vars)
+            })
+            .collect();
+    }
     let n = xs.len();
     let mut out = Vec::with_capacity(n);
+    let mut env = SimdEnv { variables, locals: Vec::new(), x: Vf64::ZERO, memo: Memo::new(arena, Vf64::ZERO) };
 
     let mut i = 0;
     while i < n {
@@ -161,42 +675,27 @@ pub fn simd_eval_over_x(root_idx: usize, arena: &Arena, variables: &HashMap<Stri
         }
         if count < 4 {
             let pad = if count > 0 { buf[count - 1] } else { *xs.last().unwrap_or(&0.0) };
-            for j in count..4 { buf[j] = pad; }
+            buf[count..].fill(pad);
         }
 
-        let x = Vf64::from(buf);
-        let v = interpret_node_simd(root_idx, arena, variables, x);
+        // Shared nodes are computed once per block of lanes.
+        env.x = Vf64::from(buf);
+        env.locals.clear();
+        env.memo.invalidate();
+        let v = interpret_node_simd(root_idx, arena, &mut env);
         let arr: [f64; 4] = v.into(); // wide 0.7 supports Into<[f64;4]>
-        for j in 0..count { out.push(arr[j]); }
+        out.extend_from_slice(&arr[..count]);
         i += count;
     }
     out
 }
 
 // ========== Legacy micro‑JIT (const fold to closure) ==========
-pub fn jit_eval(root_idx: usize, arena: &Arena) -> Option<Box<dyn Fn(&mut HashMap<String, f64>) -> f64 + Send + Sync + 'static>> {
-    let expr = arena.get(root_idx)?;
-    match &expr.kind {
-        ExprKind::Number(n) => {
-            let v = *n;
-            Some(Box::new(move |_| v))
-        }
-        ExprKind::Binary { left, op, right } => {
-            let l = arena.get(*left)?;
-            let r = arena.get(*right)?;
-            if let (ExprKind::Number(a), ExprKind::Number(b)) = (&l.kind, &r.kind) {
-                let (a, b) = (*a, *b);
-                match op {
-                    Token::Plus  => Some(Box::new(move |_| a + b)),
-                    Token::Minus => Some(Box::new(move |_| a - b)),
-                    Token::Star  => Some(Box::new(move |_| a * b)),
-                    Token::Slash => Some(Box::new(move |_| if b != 0.0 { a / b } else { f64::NAN })),
-                    _ => None,
-                }
-            } else {
-                None
-            }
-        }
-        _ => None,
-    }
+pub type JitFn = Box<dyn Fn(&mut HashMap<String, f64>) -> f64 + Send + Sync + 'static>;
+
+pub fn jit_eval(root_idx: NodeId, arena: &Arena) -> Option<JitFn> {
+    arena.get(root_idx)?;
+    let folded = optimize(arena, root_idx);
+    let ExprKind::Number(v) = folded.arena[folded.root].kind else { return None };
+    Some(Box::new(move |_| v))
 }
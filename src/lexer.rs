@@ -3,6 +3,10 @@ SPDX-FileCopyrightText: 2025 Eric Waller
 SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
 */
 
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Number(f64),
@@ -11,75 +15,476 @@ pub enum Token {
     Minus,
     Star,
     Slash,
+    /// `^`, also produced for the `**` alias.
+    Caret,
     LParen,
     RParen,
-    Unknown(char),
+    LBracket,
+    RBracket,
+    Comma,
+    Assign,
+    Semicolon,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    EqEq,
+    NotEq,
+    Bang,
+    AndAnd,
+    OrOr,
+    Question,
+    Colon,
+    If,
+    Then,
+    Else,
+    Let,
+    Print,
+    /// `'...'` or `"..."`, with escapes already resolved.
+    Str(String),
+    /// Line break outside parentheses; separates statements like `;`.
+    Newline,
+    /// A custom operator symbol passed to [`tokenize_with`].
+    Operator(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "number {}", n),
+            Token::Identifier(name) => write!(f, "identifier '{}'", name),
+            Token::Plus => f.write_str("'+'"),
+            Token::Minus => f.write_str("'-'"),
+            Token::Star => f.write_str("'*'"),
+            Token::Slash => f.write_str("'/'"),
+            Token::Caret => f.write_str("'^'"),
+            Token::LParen => f.write_str("'('"),
+            Token::RParen => f.write_str("')'"),
+            Token::LBracket => f.write_str("'['"),
+            Token::RBracket => f.write_str("']'"),
+            Token::Comma => f.write_str("','"),
+            Token::Assign => f.write_str("'='"),
+            Token::Semicolon => f.write_str("';'"),
+            Token::Lt => f.write_str("'<'"),
+            Token::Le => f.write_str("'<='"),
+            Token::Gt => f.write_str("'>'"),
+            Token::Ge => f.write_str("'>='"),
+            Token::EqEq => f.write_str("'=='"),
+            Token::NotEq => f.write_str("'!='"),
+            Token::Bang => f.write_str("'!'"),
+            Token::AndAnd => f.write_str("'&&'"),
+            Token::OrOr => f.write_str("'||'"),
+            Token::Question => f.write_str("'?'"),
+            Token::Colon => f.write_str("':'"),
+            Token::If => f.write_str("'if'"),
+            Token::Then => f.write_str("'then'"),
+            Token::Else => f.write_str("'else'"),
+            Token::Let => f.write_str("'let'"),
+            Token::Print => f.write_str("'print'"),
+            Token::Str(text) => write!(f, "string {:?}", text),
+            Token::Newline => f.write_str("newline"),
+            Token::Operator(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+/// A point in the source text. `offset` is a byte offset; `line` and
+/// `column` are 1-based, with columns counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Half-open source range `[start, end)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Span { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        let start = if other.start.offset < self.start.offset { other.start } else { self.start };
+        let end = if other.end.offset > self.end.offset { other.end } else { self.end };
+        Span { start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.offset - self.start.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The slice of `source` this span covers.
+    pub fn slice<'s>(&self, source: &'s str) -> &'s str {
+        &source[self.start.offset..self.end.offset]
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start.line, self.start.column)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    /// A character that cannot start any token.
+    UnexpectedChar { ch: char, span: Span },
+    /// A numeric literal that does not form a valid number, e.g. `1.2.3`.
+    MalformedNumber { text: String, span: Span },
+    /// A string literal with no closing quote before the end of the line.
+    UnterminatedString { span: Span },
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedChar { span, .. }
+            | LexError::MalformedNumber { span, .. }
+            | LexError::UnterminatedString { span } => *span,
+        }
+    }
 }
 
-pub fn tokenize(input: &str) -> Vec<Token> {
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnexpectedChar { ch, span } => write!(f, "unexpected character '{}' at {}", ch, span),
+            LexError::MalformedNumber { text, span } => write!(f, "malformed number '{}' at {}", text, span),
+            LexError::UnterminatedString { span } => write!(f, "unterminated string at {}", span),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+struct Lexer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+    len: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Lexer { input, chars: input.char_indices().peekable(), line: 1, column: 1, len: input.len() }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn pos(&mut self) -> Position {
+        let offset = self.chars.peek().map(|&(i, _)| i).unwrap_or(self.len);
+        Position { offset, line: self.line, column: self.column }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    // Skip a comment up to, but not including, the end of the line.
+    fn skip_line(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.bump();
+        }
+    }
+
+    // Body of a string literal whose opening `quote` has been consumed.
+    // `\n`, `\t`, `\\` and escaped quotes are resolved; any other escape is
+    // kept as written.
+    fn string(&mut self, quote: char, start: Position) -> Result<String, LexError> {
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some(c) if c == quote => {
+                    self.bump();
+                    return Ok(text);
+                }
+                Some('\\') => {
+                    self.bump();
+                    match self.peek() {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some(c @ ('\\' | '\'' | '"')) => text.push(c),
+                        Some(c) if c != '\n' => {
+                            text.push('\\');
+                            text.push(c);
+                        }
+                        _ => continue,
+                    }
+                    self.bump();
+                }
+                Some(c) if c != '\n' => {
+                    text.push(c);
+                    self.bump();
+                }
+                _ => return Err(LexError::UnterminatedString { span: Span::new(start, self.pos()) }),
+            }
+        }
+    }
+}
+
+// Digits of `radix` with optional `_` separators between them; returns the
+// digits without separators. An empty group is allowed here and rejected by
+// the caller where it matters.
+fn digit_group(text: &str, radix: u32) -> Option<String> {
+    if text.starts_with('_') || text.ends_with('_') || text.contains("__") {
+        return None;
+    }
+    let digits: String = text.chars().filter(|&c| c != '_').collect();
+    digits.chars().all(|c| c.is_digit(radix)).then_some(digits)
+}
+
+/// Value of a numeric literal: decimal with optional fraction and exponent
+/// (`6.674E-11`, `.5`, `5.`, `1_000_000`) or a `0x` hexadecimal integer.
+fn parse_number(text: &str) -> Option<f64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        let digits = digit_group(hex, 16).filter(|d| !d.is_empty())?;
+        return u64::from_str_radix(&digits, 16).ok().map(|v| v as f64);
+    }
+
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let (int_part, frac_part) = match mantissa.split_once('.') {
+        Some((int_part, frac_part)) => (int_part, Some(frac_part)),
+        None => (mantissa, None),
+    };
+
+    let mut clean = digit_group(int_part, 10)?;
+    if let Some(frac) = frac_part {
+        let frac = digit_group(frac, 10)?;
+        if clean.is_empty() && frac.is_empty() {
+            return None; // a lone `.`
+        }
+        clean.push('.');
+        clean.push_str(&frac);
+    }
+    if clean.is_empty() {
+        return None;
+    }
+    if let Some(exp) = exponent {
+        let (sign, digits) = match exp.strip_prefix(['+', '-']) {
+            Some(rest) => (&exp[..1], rest),
+            None => ("", exp),
+        };
+        let digits = digit_group(digits, 10).filter(|d| !d.is_empty())?;
+        clean.push('e');
+        clean.push_str(sign);
+        clean.push_str(&digits);
+    }
+    clean.parse().ok()
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<SpannedToken>, LexError> {
+    tokenize_with(input, &[])
+}
+
+/// Like [`tokenize`], also recognizing each of `operators` (symbols such as
+/// `%` or `<>`) as a [`Token::Operator`]. The longest symbol that matches
+/// wins, including over the standard operators; symbols starting with a
+/// letter, digit or `_` are ignored, since those lex as identifiers.
+pub fn tokenize_with(input: &str, operators: &[&str]) -> Result<Vec<SpannedToken>, LexError> {
+    let (tokens, errors) = tokenize_recovering(input, operators);
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(tokens),
+    }
+}
+
+/// Like [`tokenize_with`], but carries on past errors, returning every one
+/// along with the tokens that did lex. The offending text is skipped: a
+/// stray character, a whole malformed number, or an unterminated string up
+/// to the end of its line.
+pub fn tokenize_recovering(input: &str, operators: &[&str]) -> (Vec<SpannedToken>, Vec<LexError>) {
     let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+    let mut errors = Vec::new();
+    let mut lx = Lexer::new(input);
+    // Newlines inside parentheses or brackets are plain whitespace.
+    let mut depth = 0usize;
+    let mut operators: Vec<&str> =
+        operators.iter().copied().filter(|s| s.starts_with(|c: char| !c.is_alphanumeric() && c != '_')).collect();
+    operators.sort_by_key(|s| std::cmp::Reverse(s.len()));
 
-    while let Some(&ch) = chars.peek() {
-        match ch {
-            '0'..='9' | '.' => {
+    while let Some(ch) = lx.peek() {
+        let start = lx.pos();
+        let rest = &lx.input[start.offset..];
+        if let Some(symbol) = operators.iter().find(|s| rest.starts_with(**s)) {
+            symbol.chars().for_each(|_| {
+                lx.bump();
+            });
+            let span = Span::new(start, lx.pos());
+            tokens.push(SpannedToken { token: Token::Operator(symbol.to_string()), span });
+            continue;
+        }
+        let token = match ch {
+            // A `.` starts a number only before a digit, as in `.5`; in
+            // `a.b` it is an unexpected character.
+            c if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|d: char| d.is_ascii_digit())) => {
+                // Take the whole alphanumeric run so `1.2.3` or `12abc` is
+                // reported as one malformed literal rather than split up.
                 let mut num = String::new();
-                while let Some(&c) = chars.peek() {
-                    if c.is_ascii_digit() || c == '.' {
+                while let Some(c) = lx.peek() {
+                    let exp_sign = (c == '+' || c == '-')
+                        && num.ends_with(['e', 'E'])
+                        && !num.starts_with("0x")
+                        && !num.starts_with("0X");
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '.' || exp_sign {
                         num.push(c);
-                        chars.next();
+                        lx.bump();
                     } else {
                         break;
                     }
                 }
-                if let Ok(n) = num.parse() {
-                    tokens.push(Token::Number(n));
+                match parse_number(&num) {
+                    Some(n) => Token::Number(n),
+                    None => {
+                        let span = Span::new(start, lx.pos());
+                        errors.push(LexError::MalformedNumber { text: num, span });
+                        continue;
+                    }
                 }
             }
             'a'..='z' | 'A'..='Z' | '_' => {
                 let mut ident = String::new();
-                while let Some(&c) = chars.peek() {
+                while let Some(c) = lx.peek() {
                     if c.is_ascii_alphanumeric() || c == '_' {
                         ident.push(c);
-                        chars.next();
+                        lx.bump();
                     } else {
                         break;
                     }
                 }
-                tokens.push(Token::Identifier(ident));
-            }
-            '+' => {
-                tokens.push(Token::Plus);
-                chars.next();
-            }
-            '-' => {
-                tokens.push(Token::Minus);
-                chars.next();
-            }
-            '*' => {
-                tokens.push(Token::Star);
-                chars.next();
-            }
-            '/' => {
-                tokens.push(Token::Slash);
-                chars.next();
+                match ident.as_str() {
+                    "inf" => Token::Number(f64::INFINITY),
+                    "nan" => Token::Number(f64::NAN),
+                    "if" => Token::If,
+                    "then" => Token::Then,
+                    "else" => Token::Else,
+                    "let" => Token::Let,
+                    "print" => Token::Print,
+                    _ => Token::Identifier(ident),
+                }
             }
-            '(' => {
-                tokens.push(Token::LParen);
-                chars.next();
+            '\n' if depth == 0 => {
+                lx.bump();
+                Token::Newline
             }
-            ')' => {
-                tokens.push(Token::RParen);
-                chars.next();
+            '#' => {
+                lx.skip_line();
+                continue;
             }
             c if c.is_whitespace() => {
-                chars.next(); // Skip whitespace
+                lx.bump(); // Skip whitespace
+                continue;
             }
             other => {
-                tokens.push(Token::Unknown(other));
-                chars.next();
+                lx.bump();
+                match other {
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' if lx.peek() == Some('*') => {
+                        lx.bump();
+                        Token::Caret
+                    }
+                    '*' => Token::Star,
+                    '^' => Token::Caret,
+                    '/' if lx.peek() == Some('/') => {
+                        lx.skip_line();
+                        continue;
+                    }
+                    '/' => Token::Slash,
+                    '\'' | '"' => match lx.string(other, start) {
+                        Ok(text) => Token::Str(text),
+                        Err(e) => {
+                            errors.push(e);
+                            continue;
+                        }
+                    },
+                    '(' => {
+                        depth += 1;
+                        Token::LParen
+                    }
+                    ')' => {
+                        depth = depth.saturating_sub(1);
+                        Token::RParen
+                    }
+                    '[' => {
+                        depth += 1;
+                        Token::LBracket
+                    }
+                    ']' => {
+                        depth = depth.saturating_sub(1);
+                        Token::RBracket
+                    }
+                    ',' => Token::Comma,
+                    '?' => Token::Question,
+                    ':' => Token::Colon,
+                    '=' if lx.peek() == Some('=') => {
+                        lx.bump();
+                        Token::EqEq
+                    }
+                    '=' => Token::Assign,
+                    '!' if lx.peek() == Some('=') => {
+                        lx.bump();
+                        Token::NotEq
+                    }
+                    '!' => Token::Bang,
+                    '<' if lx.peek() == Some('=') => {
+                        lx.bump();
+                        Token::Le
+                    }
+                    '<' => Token::Lt,
+                    '>' if lx.peek() == Some('=') => {
+                        lx.bump();
+                        Token::Ge
+                    }
+                    '>' => Token::Gt,
+                    '&' if lx.peek() == Some('&') => {
+                        lx.bump();
+                        Token::AndAnd
+                    }
+                    '|' if lx.peek() == Some('|') => {
+                        lx.bump();
+                        Token::OrOr
+                    }
+                    ';' => Token::Semicolon,
+                    _ => {
+                        let span = Span::new(start, lx.pos());
+                        errors.push(LexError::UnexpectedChar { ch: other, span });
+                        continue;
+                    }
+                }
             }
-        }
+        };
+        let span = Span::new(start, lx.pos());
+        tokens.push(SpannedToken { token, span });
     }
-    tokens
+    (tokens, errors)
 }
@@ -3,9 +3,20 @@ SPDX-FileCopyrightText: 2025 Eric Waller
 SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
 */
 
+pub mod functions;
 pub mod lexer;
 pub mod parser;
 pub mod interpreter;
+pub mod script;
+pub mod format;
+pub mod visit;
+pub mod optimize;
+pub mod diff;
+pub mod ad;
+pub mod analysis;
+pub mod serialize;
+pub mod value;
+pub mod reduce;
 
 #[cfg(test)]
 mod tests {
@@ -18,7 +29,7 @@ mod tests {
     #[test]
     fn test_simple_addition() {
         let input = "y = 10 + 5;";
-        let tokens = tokenize(input);
+        let tokens = tokenize(input).expect("Lexing failed");
         let (arena, root_idx) = parse(tokens).expect("Parsing failed");
         
         let mut variables = HashMap::new();
@@ -31,7 +42,7 @@ mod tests {
     #[test]
     fn test_variable_evaluation() {
         let input = "y = a + b;";
-        let tokens = tokenize(input);
+        let tokens = tokenize(input).expect("Lexing failed");
         let (arena, root_idx) = parse(tokens).expect("Parsing failed");
         
         let mut variables = HashMap::new();
@@ -47,7 +58,7 @@ mod tests {
     #[test]
     fn test_operator_precedence() {
         let input = "y = 2 + 3 * 4;";
-        let tokens = tokenize(input);
+        let tokens = tokenize(input).expect("Lexing failed");
         let (arena, root_idx) = parse(tokens).expect("Parsing failed");
         
         let mut variables = HashMap::new();
@@ -60,7 +71,7 @@ mod tests {
     #[test]
     fn test_parentheses() {
         let input = "y = (2 + 3) * 4;";
-        let tokens = tokenize(input);
+        let tokens = tokenize(input).expect("Lexing failed");
         let (arena, root_idx) = parse(tokens).expect("Parsing failed");
         
         let mut variables = HashMap::new();
@@ -69,4 +80,1226 @@ mod tests {
         // Assert that the result of "(2 + 3) * 4" is 20.0
         assert_eq!(result, 20.0);
     }
+
+    #[test]
+    fn test_spans() {
+        let input = "y = a +\n  (b * 2)";
+        let tokens = tokenize(input).expect("Lexing failed");
+        let star = tokens.iter().find(|t| t.token == lexer::Token::Star).unwrap();
+        assert_eq!((star.span.start.line, star.span.start.column), (2, 6));
+        assert_eq!(star.span.slice(input), "*");
+
+        let (arena, root_idx) = parse(tokens).expect("Parsing failed");
+        let root = arena.get(root_idx).unwrap();
+        assert_eq!(root.span.slice(input), input);
+        if let parser::ExprKind::Assign { value, .. } = root.kind {
+            if let parser::ExprKind::Binary { right, .. } = arena.get(value).unwrap().kind {
+                assert_eq!(arena.span(right).unwrap().slice(input), "(b * 2)");
+                return;
+            }
+        }
+        panic!("unexpected tree shape");
+    }
+
+    #[test]
+    fn test_errors() {
+        use crate::lexer::LexError;
+        use crate::parser::{parse_str, ParseError};
+
+        let err = tokenize("1.2.3 + x").unwrap_err();
+        assert!(matches!(&err, LexError::MalformedNumber { text, .. } if text == "1.2.3"));
+        assert!(matches!(tokenize("a @ b"), Err(LexError::UnexpectedChar { ch: '@', .. })));
+
+        let err = parse_str("(1 + 2").unwrap_err();
+        assert_eq!(err, ParseError::UnbalancedParen { span: err.span() });
+        assert_eq!(err.span().start.column, 1);
+
+        let err = parse_str("2 * * 3").unwrap_err();
+        assert!(matches!(&err, ParseError::UnexpectedToken { found: lexer::Token::Star, .. }));
+        assert_eq!(err.span().start.column, 5);
+        assert_eq!(err.to_string(), "unexpected '*' at 1:5, expected number, identifier, '(', '-', '+', '!' or 'if'");
+
+        assert!(matches!(parse_str("y = 1 +"), Err(ParseError::UnexpectedEof { .. })));
+    }
+
+    #[test]
+    fn test_trailing_input() {
+        use crate::parser::{parse_str, parse_with, ParseError, ParseOptions};
+
+        let err = parse_str("2 + 3 4").unwrap_err();
+        assert!(matches!(err, ParseError::TrailingInput { found: lexer::Token::Number(n), .. } if n == 4.0));
+        let err = parse_str("x y z").unwrap_err();
+        assert_eq!(err.span().slice("x y z"), "y z");
+        assert!(matches!(parse_str("(1+2))"), Err(ParseError::UnbalancedParen { .. })));
+        assert!(parse_str("y = 1 + 2;;").is_ok());
+
+        let tokens = tokenize("2 + 3 4").unwrap();
+        let (arena, root) = parse_with(tokens, &ParseOptions::lenient()).expect("lenient parse");
+        assert_eq!(interpret(root, &arena, &mut HashMap::new()), 5.0);
+    }
+
+    #[test]
+    fn test_power() {
+        use crate::interpreter::{jit_eval, simd_eval_over_x, ExprJit};
+        use crate::parser::parse_str;
+
+        let eval = |src: &str| {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            interpret(root, &arena, &mut HashMap::new())
+        };
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("2 ** 3 ** 2"), 512.0);
+        assert_eq!(eval("(2 ^ 3) ^ 2"), 64.0);
+        assert_eq!(eval("3 * 2 ^ 2"), 12.0);
+
+        let (arena, root) = parse_str("x ^ 2").unwrap();
+        assert_eq!(simd_eval_over_x(root, &arena, &HashMap::new(), &[-3.0, 0.5, 4.0]), vec![9.0, 0.25, 16.0]);
+        let (arena, root) = parse_str("x ^ 3 + x ^ -1 + 0 ^ 0.5").unwrap();
+        assert_eq!(simd_eval_over_x(root, &arena, &HashMap::new(), &[-2.0, 0.5]), vec![-8.5, 2.125]);
+
+        let (arena, root) = parse_str("2 ^ 10").unwrap();
+        assert_eq!(jit_eval(root, &arena).unwrap()(&mut HashMap::new()), 1024.0);
+        assert_eq!(ExprJit::new(2.0, lexer::Token::Caret, 0.5).unwrap().eval(), 2f64.sqrt());
+    }
+
+    #[test]
+    fn test_unary() {
+        use crate::interpreter::{jit_eval, simd_eval_over_x, ExprJit};
+        use crate::parser::parse_str;
+
+        let eval = |src: &str| {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            let mut vars = HashMap::from([("x".to_string(), 3.0)]);
+            interpret(root, &arena, &mut vars)
+        };
+        assert_eq!(eval("-x"), -3.0);
+        assert_eq!(eval("-(x + 1)"), -4.0);
+        assert_eq!(eval("3 * -2"), -6.0);
+        assert_eq!(eval("-x ^ 2"), -9.0);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+        assert_eq!(eval("- -+x"), 3.0);
+
+        let (arena, root) = parse_str("-x ^ 2 + 1").unwrap();
+        assert_eq!(simd_eval_over_x(root, &arena, &HashMap::new(), &[2.0, -3.0]), vec![-3.0, -8.0]);
+        let jit = ExprJit::compile(root, &arena, &["x"]).expect("JIT compile failed");
+        assert_eq!(jit.eval_with(&[2.0]), -3.0);
+        assert!(ExprJit::compile(root, &arena, &[]).is_err());
+
+        let (arena, root) = parse_str("-2.5").unwrap();
+        assert_eq!(jit_eval(root, &arena).unwrap()(&mut HashMap::new()), -2.5);
+    }
+
+    #[test]
+    fn test_numeric_literals() {
+        use crate::lexer::{LexError, Token};
+
+        let number = |src: &str| match tokenize(src).map(|t| t[0].token.clone()) {
+            Ok(Token::Number(n)) => n,
+            other => panic!("{:?} for {}", other, src),
+        };
+        assert_eq!(number("1e-9"), 1e-9);
+        assert_eq!(number("6.674E-11"), 6.674e-11);
+        assert_eq!(number("2.5e+3"), 2500.0);
+        assert_eq!(number("1_000_000"), 1_000_000.0);
+        assert_eq!(number(".5"), 0.5);
+        assert_eq!(number("5."), 5.0);
+        assert_eq!(number("0xFF"), 255.0);
+        assert_eq!(number("0x1_f"), 31.0);
+        assert_eq!(number("inf"), f64::INFINITY);
+        assert!(number("nan").is_nan());
+
+        for bad in ["1.2.3", "1e", "1e+", "1__0", "1_", "0x", "0x1.5", "12abc", "1._5"] {
+            match tokenize(bad) {
+                Err(LexError::MalformedNumber { text, .. }) => assert_eq!(text, bad),
+                other => panic!("{} lexed as {:?}", bad, other),
+            }
+        }
+        // A `.` not followed by a digit is not a number at all.
+        for (bad, column) in [(".", 1), ("a.b", 2), ("2 + .", 5)] {
+            match tokenize(bad) {
+                Err(LexError::UnexpectedChar { ch: '.', span }) => assert_eq!(span.start.column, column, "{}", bad),
+                other => panic!("{} lexed as {:?}", bad, other),
+            }
+        }
+
+        // Exponent signs only attach directly after `e`.
+        let tokens = tokenize("2e3-1").unwrap();
+        assert_eq!(tokens.len(), 3);
+    }
+
+    #[test]
+    fn test_builtin_calls() {
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::{parse_str, ParseError};
+
+        let src = "clamp(hypot(x, 4), 0, 10) + max(sqrt(x * x), 1, 2) + atan2(0, -1) + min(floor(2.5), ceil(0.2))";
+        let (arena, root) = parse_str(src).expect("Parsing failed");
+        let expected = |x: f64| x.hypot(4.0).clamp(0.0, 10.0) + x.abs().max(2.0) + std::f64::consts::PI + 1.0;
+
+        let mut vars = HashMap::from([("x".to_string(), 3.0)]);
+        assert_eq!(interpret(root, &arena, &mut vars), expected(3.0));
+
+        let xs = [3.0, -12.0, 0.0, 1.5, 7.0];
+        let ys = simd_eval_over_x(root, &arena, &HashMap::new(), &xs);
+        let jit = ExprJit::compile(root, &arena, &["x"]).expect("JIT compile failed");
+        for (&x, &y) in xs.iter().zip(&ys) {
+            assert!((y - expected(x)).abs() < 1e-12, "simd x={}", x);
+            assert_eq!(jit.eval_with(&[x]), expected(x), "jit x={}", x);
+        }
+
+        let (arena, root) = parse_str("exp(ln(2)) + log10(1000) + sin(0) * cos(0) - abs(-1)").unwrap();
+        assert!((interpret(root, &arena, &mut HashMap::new()) - 4.0).abs() < 1e-12);
+
+        assert!(matches!(parse_str("sqrt(1, 2)"), Err(ParseError::ArityMismatch { found: 2, .. })));
+        assert!(matches!(parse_str("min()"), Err(ParseError::ArityMismatch { found: 0, .. })));
+        assert!(matches!(parse_str("nope(1)"), Err(ParseError::UnknownFunction { .. })));
+        assert!(matches!(parse_str("sqrt(1"), Err(ParseError::UnbalancedParen { .. })));
+    }
+
+    #[test]
+    fn test_predicates() {
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::parse_str;
+
+        let eval = |src: &str, x: f64| {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            let mut vars = HashMap::from([("x".to_string(), x), ("limit".to_string(), 5.0)]);
+            interpret(root, &arena, &mut vars)
+        };
+        assert_eq!(eval("x > limit && x <= 8", 6.0), 1.0);
+        assert_eq!(eval("x > limit && x <= 8", 9.0), 0.0);
+        assert_eq!(eval("x < 0 || x == 3", 3.0), 1.0);
+        assert_eq!(eval("!(x == 3) || x != x", 3.0), 0.0);
+        assert_eq!(eval("1 + 2 < 4 == 1", 0.0), 1.0);
+        assert_eq!(eval("nan && 1", 0.0), 0.0);
+        assert_eq!(eval("!nan", 0.0), 1.0);
+
+        let src = "x >= 2 && !(x > 4) || x == -1";
+        let (arena, root) = parse_str(src).unwrap();
+        let xs = [-1.0, 0.0, 2.0, 3.0, 4.0, 4.5, f64::NAN];
+        let ys = simd_eval_over_x(root, &arena, &HashMap::new(), &xs);
+        let jit = ExprJit::compile(root, &arena, &["x"]).expect("JIT compile failed");
+        for (&x, &y) in xs.iter().zip(&ys) {
+            assert_eq!(y, eval(src, x), "simd x={}", x);
+            assert_eq!(jit.eval_with(&[x]), y, "jit x={}", x);
+        }
+    }
+
+    #[test]
+    fn test_conditionals() {
+        use crate::interpreter::{jit_eval, simd_eval_over_x, ExprJit};
+        use crate::parser::{parse_str, ParseError};
+
+        let eval = |src: &str, x: f64| {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            interpret(root, &arena, &mut HashMap::from([("x".to_string(), x)]))
+        };
+        assert_eq!(eval("if x > 0 then x else -x", -4.0), 4.0);
+        assert_eq!(eval("1 + if x > 0 then 10 else 20 * 2", 1.0), 11.0);
+        assert_eq!(eval("x < 0 ? -1 : x == 0 ? 0 : 1", 0.0), 0.0);
+        assert_eq!(eval("x < 0 ? -1 : x == 0 ? 0 : 1", 7.0), 1.0);
+
+        let src = "piecewise(x < 1, 10, x < 2, 20, x < 3, 30, 40)";
+        assert_eq!([0.5, 1.5, 2.5, 9.0].map(|x| eval(src, x)), [10.0, 20.0, 30.0, 40.0]);
+        assert!(matches!(parse_str("piecewise(x < 1, 10)"), Err(ParseError::ArityMismatch { found: 2, .. })));
+        assert!(matches!(parse_str("if x then 1"), Err(ParseError::UnexpectedEof { .. })));
+
+        let src = "if x >= 0 then sqrt(x) else piecewise(x < -10, 0, -x)";
+        let (arena, root) = parse_str(src).unwrap();
+        let xs = [4.0, -3.0, -20.0, 9.0, 0.0];
+        let ys = simd_eval_over_x(root, &arena, &HashMap::new(), &xs);
+        let jit = ExprJit::compile(root, &arena, &["x"]).expect("JIT compile failed");
+        for (&x, &y) in xs.iter().zip(&ys) {
+            assert_eq!(y, eval(src, x), "simd x={}", x);
+            assert_eq!(jit.eval_with(&[x]), y, "jit x={}", x);
+        }
+
+        let (arena, root) = parse_str("1 ? 2 : 3").unwrap();
+        assert_eq!(jit_eval(root, &arena).unwrap()(&mut HashMap::new()), 2.0);
+    }
+
+    #[test]
+    fn test_programs() {
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::{parse_str, ExprKind, ParseError};
+
+        let src = "let rate = 0.05\nlet years = 10; growth = (1 + rate) ^\n    years\n\nprincipal * growth";
+        let (arena, root) = parse_str(src).expect("Parsing failed");
+        assert!(matches!(&arena.get(root).unwrap().kind, ExprKind::Program { statements } if statements.len() == 4));
+
+        let mut vars = HashMap::from([("principal".to_string(), 1000.0)]);
+        let expected = 1000.0 * 1.05f64.powf(10.0);
+        assert!((interpret(root, &arena, &mut vars) - expected).abs() < 1e-9);
+        // `let` stays inside the program; `=` writes through to the caller.
+        assert!(!vars.contains_key("rate") && !vars.contains_key("years"));
+        assert!(vars.contains_key("growth"));
+
+        let src = "let a = x * 2\nlet b = (a +\n 1)\nif b > 5\n  then b\n  else -b";
+        let (arena, root) = parse_str(src).unwrap();
+        let xs = [0.0, 1.0, 2.0, 3.0, 4.0];
+        let ys = simd_eval_over_x(root, &arena, &HashMap::new(), &xs);
+        let jit = ExprJit::compile(root, &arena, &["x"]).expect("JIT compile failed");
+        for (&x, &y) in xs.iter().zip(&ys) {
+            let b = x * 2.0 + 1.0;
+            assert_eq!(y, if b > 5.0 { b } else { -b }, "simd x={}", x);
+            assert_eq!(jit.eval_with(&[x]), y, "jit x={}", x);
+        }
+
+        assert!(matches!(parse_str("let = 3"), Err(ParseError::UnexpectedToken { .. })));
+        assert!(matches!(parse_str("let a = 1\na b"), Err(ParseError::TrailingInput { .. })));
+        assert!(matches!(parse_str("\n;\n"), Err(ParseError::UnexpectedEof { .. })));
+        // A line that starts with an operator is its own statement, so its
+        // value, and the one before, would be silently dropped.
+        let Err(ParseError::UnusedValue { span }) = parse_str("base\n- discount") else { panic!("expected an unused value") };
+        assert_eq!(span.to_string(), "1:1");
+        assert!(parse_str("let a = 1; a; a + 1").is_err());
+
+        // Each root of a batch gets its own `let` bindings; `=` still
+        // writes through to the shared variables.
+        use crate::interpreter::batch_interpret;
+        let (mut arena, first) = parse_str("let t = 2; s = t * 3; t").unwrap();
+        let second = arena.alloc(ExprKind::Identifier("t".to_string()));
+        let third = arena.alloc(ExprKind::Identifier("s".to_string()));
+        let mut vars = HashMap::new();
+        assert_eq!(batch_interpret(&[first, second, third], &arena, &mut vars), [2.0, 0.0, 6.0]);
+    }
+
+    #[test]
+    fn test_user_functions() {
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::{parse_str, ParseError};
+
+        let src = "drag(v, cd) = 0.5 * rho * cd * v^2\nlet rho = 1.2\nsq(t) = t * t\ndrag(x, 0.3) + drag(sq(2), 1)";
+        let (arena, root) = parse_str(src).expect("Parsing failed");
+        let expected = |x: f64| 0.5 * 1.2 * 0.3 * x * x + 0.5 * 1.2 * 16.0;
+
+        let mut vars = HashMap::from([("x".to_string(), 10.0)]);
+        assert!((interpret(root, &arena, &mut vars) - expected(10.0)).abs() < 1e-9);
+        // Parameters do not leak into the caller's variables.
+        assert!(!vars.contains_key("v") && !vars.contains_key("t"));
+
+        let xs = [0.0, 1.0, 2.5, -4.0, 10.0];
+        let ys = simd_eval_over_x(root, &arena, &HashMap::new(), &xs);
+        let jit = ExprJit::compile(root, &arena, &["x"]).expect("JIT compile failed");
+        for (&x, &y) in xs.iter().zip(&ys) {
+            assert!((y - expected(x)).abs() < 1e-9, "simd x={}", x);
+            assert!((jit.eval_with(&[x]) - expected(x)).abs() < 1e-9, "jit x={}", x);
+        }
+
+        assert!(matches!(parse_str("f(a) = a\nf(1, 2)"), Err(ParseError::ArityMismatch { found: 2, .. })));
+        assert!(matches!(parse_str("f(n) = n * f(n - 1)"), Err(ParseError::RecursiveFunction { .. })));
+        assert!(matches!(parse_str("f(a) = a\nf(b) = b"), Err(ParseError::DuplicateFunction { .. })));
+        assert!(matches!(parse_str("sqrt(a) = a"), Err(ParseError::DuplicateFunction { .. })));
+        let Err(ParseError::DuplicateParameter { name, span }) = parse_str("f(x, y, x) = x") else { panic!("expected a duplicate parameter") };
+        assert_eq!((name.as_str(), span.start.offset), ("x", 8));
+        assert!(matches!(parse_str("g(1) + f(2)"), Err(ParseError::UnknownFunction { .. })));
+    }
+
+    #[test]
+    fn test_scripts() {
+        use crate::lexer::{LexError, Token};
+        use crate::script::{run, ScriptError};
+
+        let toks: Vec<Token> = tokenize("print 'it\\'s' // note\n# whole line\n\"a\\tb\"")
+            .expect("Lexing failed")
+            .into_iter()
+            .map(|t| t.token)
+            .collect();
+        assert_eq!(
+            toks,
+            vec![Token::Print, Token::Str("it's".into()), Token::Newline, Token::Newline, Token::Str("a\tb".into())]
+        );
+        assert!(matches!(tokenize("x = 'open\nprint x"), Err(LexError::UnterminatedString { .. })));
+
+        let output = |src: &str| {
+            let mut out = Vec::new();
+            run(src, &mut out).map(|_| String::from_utf8(out).unwrap())
+        };
+        assert_eq!(output(include_str!("../test.erock")).unwrap(), "Hello eRock!\n");
+        let src = "let v = 12.5 # m/s\nf(v) = v * 2\nname = \"f\"\nprint '{}({:.1}) = {}', name, v, f(v), 'ok'\nprint\nprint '{{}}'";
+        assert_eq!(output(src).unwrap(), "f(12.5) = 25 ok\n\n{}\n");
+
+        let err = output("let s = 'a'\nprint 1\nprint s * 2").unwrap_err();
+        assert!(matches!(err, ScriptError::StringInExpression { .. }));
+        assert_eq!(err.span().unwrap().start.line, 3);
+        let err = output("print 1\n\nprint y + 1").unwrap_err();
+        assert!(matches!(err, ScriptError::UndefinedVariable { ref name, .. } if name == "y"));
+        assert_eq!(err.span().unwrap().start.line, 3);
+        assert!(matches!(output("print '{} {}', 1"), Err(ScriptError::Format { .. })));
+        assert!(matches!(output("print 'a' + 1"), Err(ScriptError::Parse(_))));
+    }
+
+    #[test]
+    fn test_unparse() {
+        use crate::format::{unparse, unparse_with, FormatOptions};
+        use crate::parser::parse_str;
+
+        let cases = [
+            ("((x + 1)) * (2 - y)", "(x + 1) * (2 - y)"),
+            ("a - (b - c) + (d + e) - (d - e)", "a - (b - c) + (d + e) - (d - e)"),
+            ("(a - b) - c + (a * b)", "a - b - c + a * b"),
+            ("2 ** 3 ^ 2 + (2 ^ 3) ^ 2", "2^3^2 + (2^3)^2"),
+            ("-x ^ 2 + (-x) ^ 2 + 2 ^ -x", "-x^2 + (-x)^2 + 2^-x"),
+            ("a < b == (c || !d) && e", "a < b == (c || !d) && e"),
+            ("x > 0 ? 1 : -1", "if x > 0 then 1 else -1"),
+            ("(c ? a : b) + 1 + (c ? a : b)", "(if c then a else b) + 1 + if c then a else b"),
+            ("clamp(x, 0, piecewise(x < 1, 2, 3))", "clamp(x, 0, if x < 1 then 2 else 3)"),
+            ("1e21 + 0.000001 + 6.674E-11 + 0x10", "1e21 + 1e-6 + 6.674e-11 + 16"),
+            ("f(a,b)=a*b; let k = 2\ny = f(k, 3)", "f(a, b) = a * b\nlet k = 2\ny = f(k, 3)"),
+            ("print 'it\\'s', x", "print 'it\\'s', x"),
+        ];
+        let mut vars = HashMap::from([
+            ("x".to_string(), 1.5),
+            ("y".to_string(), -2.0),
+            ("a".to_string(), 1.0),
+            ("b".to_string(), 2.0),
+            ("c".to_string(), 0.0),
+            ("d".to_string(), 0.0),
+            ("e".to_string(), 3.0),
+        ]);
+        for (src, expected) in cases {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            let text = unparse(&arena, root);
+            assert_eq!(text, expected, "unparse of {:?}", src);
+
+            // Round trip: same canonical text and the same value.
+            let (arena2, root2) = parse_str(&text).expect("Reparsing failed");
+            assert_eq!(unparse(&arena2, root2), text);
+            let v1 = interpret(root, &arena, &mut vars.clone());
+            let v2 = interpret(root2, &arena2, &mut vars);
+            assert!(v1 == v2 || (v1.is_nan() && v2.is_nan()), "{:?}: {} vs {}", src, v1, v2);
+        }
+
+        let (arena, root) = parse_str("a + b * -c ^ 2 < 3").unwrap();
+        let compact = FormatOptions { spaced: false, ..FormatOptions::default() };
+        assert_eq!(unparse_with(&arena, root, &compact), "a+b*-c^2<3");
+        let explicit = FormatOptions { explicit_parens: true, ..FormatOptions::default() };
+        let text = unparse_with(&arena, root, &explicit);
+        assert_eq!(text, "(a + (b * (-(c^2)))) < 3");
+        let (arena2, root2) = parse_str(&text).unwrap();
+        assert_eq!(unparse(&arena2, root2), "a + b * -c^2 < 3");
+    }
+
+    #[test]
+    fn test_arena_api() {
+        use crate::format::unparse;
+        use crate::lexer::Token;
+        use crate::parser::{parse_str, Arena, ExprKind, NodeId};
+        use crate::visit::{copy_tree, fold, walk, Fold, Visitor};
+
+        let (arena, root) = parse_str("y = a * (b + 1) - sqrt(a)").expect("Parsing failed");
+        assert_eq!(arena.len(), 9);
+        assert_eq!(arena.tree_len(root), 9);
+        // y = (- (* a (+ b 1)) (sqrt a))
+        assert_eq!(arena.depth(root), 5);
+        assert_eq!(arena.iter().count(), arena.len());
+        assert!(arena.ids().all(|id| arena.children(id).iter().all(|c| c.index() < id.index())));
+        let ExprKind::Assign { value, .. } = arena[root].kind else { panic!("expected an assignment") };
+        assert_eq!(arena.children(root), vec![value]);
+        assert_eq!(arena.children(value).len(), 2);
+
+        // Ids are tied to the arena that allocated them.
+        let mut other = Arena::new();
+        let foreign = other.alloc(ExprKind::Number(1.0));
+        assert!(arena.get(foreign).is_none() && !arena.contains(foreign));
+        assert!(other.contains(foreign) && !other.contains(root));
+
+        struct Names(Vec<String>);
+        impl Visitor for Names {
+            fn enter(&mut self, _: &Arena, _: NodeId, kind: &ExprKind) -> bool {
+                if let ExprKind::Identifier(name) = kind {
+                    self.0.push(name.clone());
+                }
+                // Don't look inside calls.
+                !matches!(kind, ExprKind::Call { .. })
+            }
+        }
+        let mut names = Names(Vec::new());
+        walk(&arena, root, &mut names);
+        assert_eq!(names.0, ["a", "b"]);
+
+        struct Leaves;
+        impl Fold for Leaves {
+            type Output = usize;
+            fn fold(&mut self, _: &Arena, _: NodeId, _: &ExprKind, children: Vec<usize>) -> usize {
+                children.iter().sum::<usize>().max(1)
+            }
+        }
+        assert_eq!(fold(&arena, root, &mut Leaves), 4);
+
+        let copy = copy_tree(&arena, root, &mut other);
+        assert_eq!(unparse(&other, copy), unparse(&arena, root));
+        assert_eq!(other.tree_len(copy), arena.tree_len(root));
+
+        // Each node adds the one before to itself: sizes and folds are
+        // computed once per node, not once per path.
+        let mut shared = Arena::new();
+        let mut top = shared.alloc(ExprKind::Identifier("x".to_string()));
+        for _ in 0..100 {
+            top = shared.alloc(ExprKind::Binary { left: top, op: Token::Plus, right: top });
+        }
+        assert_eq!(shared.depth(top), 101);
+        assert_eq!(shared.tree_len(top), usize::MAX);
+        struct Visits(usize);
+        impl Fold for Visits {
+            type Output = ();
+            fn fold(&mut self, _: &Arena, _: NodeId, _: &ExprKind, _: Vec<()>) {
+                self.0 += 1;
+            }
+        }
+        let mut visits = Visits(0);
+        fold(&shared, top, &mut visits);
+        assert_eq!(visits.0, 101);
+        let copy = copy_tree(&shared, top, &mut other);
+        assert_eq!(other.depth(copy), 101);
+    }
+
+    #[test]
+    fn test_optimize() {
+        use crate::format::unparse;
+        use crate::interpreter::{jit_eval, simd_eval_over_x, ExprJit};
+        use crate::optimize::{optimize, optimize_with, OptimizeOptions, Rule};
+        use crate::parser::parse_str;
+
+        let cases = [
+            ("2 * 3 + x * 1 - 0", "6 + x"),
+            ("(x + 2) + 3 - 10", "x - 5"),
+            ("(x - 2) + 2", "x - 2 + 2"),
+            ("2 * x * 3 / 4", "x * 1.5"),
+            ("10 - x - 4", "6 - x"),
+            ("if 1 > 0 then x^1 else y", "x"),
+            ("(0 && y) + (1 || y) + (2 && 0) + --x + +x^0", "1 + x + x^0"),
+            ("sqrt(16) + max(1, 2, 3) * x", "4 + 3 * x"),
+            ("x - x + x / x + 0 * x", "x - x + x / x + 0 * x"),
+            ("f(a) = a * 1 + (2 + 3)\ny = f(x) - 0", "f(a) = a + 5\ny = f(x)"),
+        ];
+        let merge = OptimizeOptions { reassociate: true, ..OptimizeOptions::default() };
+        for (src, expected) in cases {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            let opt = optimize_with(&arena, root, &merge);
+            assert_eq!(unparse(&opt.arena, opt.root), expected, "optimize {:?}", src);
+
+            // Same results on every backend.
+            let xs = [-3.0, -0.5, 0.0, 0.5, 1.0, 2.5, 7.0, 10.0];
+            let vars = HashMap::from([("y".to_string(), 4.0)]);
+            let before = simd_eval_over_x(root, &arena, &vars, &xs);
+            let after = simd_eval_over_x(opt.root, &opt.arena, &vars, &xs);
+            let jit = ExprJit::compile(opt.root, &opt.arena, &["x", "y"]).expect("JIT compile failed");
+            for (i, &x) in xs.iter().enumerate() {
+                let (b, a) = (before[i], after[i]);
+                let mut scalar_vars = HashMap::from([("x".to_string(), x), ("y".to_string(), 4.0)]);
+                let s = interpret(opt.root, &opt.arena, &mut scalar_vars);
+                for v in [a, s, jit.eval_with(&[x, 4.0])] {
+                    assert!((v - b).abs() < 1e-12 || (v.is_nan() && b.is_nan()), "{:?} at x={}: {} vs {}", src, x, v, b);
+                }
+            }
+        }
+
+        let (arena, root) = parse_str("x - x + x / x + 0 * x + x^0").unwrap();
+        let fast = optimize_with(&arena, root, &OptimizeOptions { fast_math: true, ..OptimizeOptions::default() });
+        assert_eq!(unparse(&fast.arena, fast.root), "2");
+        assert!(fast.changes.iter().any(|c| c.rule == Rule::FastMath));
+
+        // Constants are not merged across a division by zero, which is NaN,
+        // nor into one that overflows, underflows or cancels.
+        for src in ["(x / 0) / 2", "(x * 1e200) * 1e200", "(x * 1e-200) * 1e-200", "(x + 1e16) - 1e16", "(x - 1e-320) - 1e-320"] {
+            let (arena, root) = parse_str(src).unwrap();
+            let opt = optimize_with(&arena, root, &merge);
+            assert!(opt.changes.is_empty(), "{}", src);
+            for x in [0.0, 1.0, 1e200] {
+                let (a, b) = (interpret(opt.root, &opt.arena, &mut HashMap::from([("x".to_string(), x)])), interpret(root, &arena, &mut HashMap::from([("x".to_string(), x)])));
+                assert!(a == b || (a.is_nan() && b.is_nan()), "{} at x={}", src, x);
+            }
+        }
+
+        // Merging is off by default.
+        let (arena, root) = parse_str("(x + 1) + 2").unwrap();
+        let opt = optimize(&arena, root);
+        assert_eq!(unparse(&opt.arena, opt.root), "x + 1 + 2");
+        assert!(opt.changes.is_empty());
+
+        let (arena, root) = parse_str("y = x * (2 + 3)").unwrap();
+        let opt = optimize(&arena, root);
+        assert_eq!(opt.changes.len(), 1);
+        assert_eq!(opt.changes[0].rule, Rule::ConstantFold);
+        assert_eq!(opt.changes[0].to_string(), "folded constant at 1:9");
+
+        // The legacy constant JIT folds whole constant trees now.
+        let (arena, root) = parse_str("(2 < 3 && 1) * hypot(3, 4) + (if 0 then 1 else 2)").unwrap();
+        assert_eq!(jit_eval(root, &arena).unwrap()(&mut HashMap::new()), 7.0);
+
+        // A statement calling a function defined outside it keeps working.
+        let (arena, root) = parse_str("f(t) = t * 2 + 0\nf(3) * (1 + 1)").unwrap();
+        let last = arena.children(root)[1];
+        let opt = optimize(&arena, last);
+        assert_eq!(unparse(&opt.arena, opt.root), "f(3) * 2");
+        assert_eq!(interpret(opt.root, &opt.arena, &mut HashMap::new()), 12.0);
+        assert!(jit_eval(last, &arena).is_none());
+    }
+
+    #[test]
+    fn test_hash_consing() {
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::{parse_str, parse_with, Arena, ParseOptions};
+        use crate::visit::copy_tree;
+
+        let shared = ParseOptions { hash_cons: true, ..ParseOptions::default() };
+        let parse_shared = |src: &str| parse_with(tokenize(src).unwrap(), &shared).expect("Parsing failed");
+
+        let (arena, root) = parse_shared("(a + b) * (a + b)");
+        assert!(arena.is_hash_consed());
+        assert_eq!(arena.len(), 4);
+        assert_eq!(arena.tree_len(root), 7);
+        let sum = arena.children(root)[0];
+        assert_eq!(arena.children(root), vec![sum, sum]);
+        assert!(arena.is_shared(sum) && !arena.is_shared(root));
+        assert!(!parse_str("(a + b) * (a + b)").unwrap().0.has_shared());
+        // A later parenthesized occurrence does not move the shared span.
+        let (arena, root) = parse_shared("a * 2 + (a * 2)");
+        let product = arena.children(root)[0];
+        assert!(arena.is_shared(product));
+        assert_eq!((arena[product].span.start.offset, arena[product].span.end.offset), (0, 5));
+
+        // Shared nodes must see bindings made between their uses, including
+        // a function parameter that shadows a global of the same name.
+        let programs = [
+            "(x + 1) * (x + 1) - sqrt(x + 1)",
+            "let t = x * 2\nx = 10\nt + x * 2",
+            "f(x) = x * 2 + k\nf(3) + (x * 2 + k) + f(x * 2 + k)",
+            "if x > 1 then (x - 1) ^ 2 else (x - 1) * 3 + ((x - 1) > 0 && (x - 1) < 5)",
+        ];
+        let xs = [0.0, 0.5, 1.5, 2.0, 3.0, 8.0];
+        for src in programs {
+            let (plain, plain_root) = parse_str(src).unwrap();
+            let (arena, root) = parse_shared(src);
+            assert!(arena.has_shared(), "{:?} should share nodes", src);
+            assert!(arena.len() < plain.len());
+
+            let vars = HashMap::from([("k".to_string(), 0.25)]);
+            let expected = simd_eval_over_x(plain_root, &plain, &vars, &xs);
+            let simd = simd_eval_over_x(root, &arena, &vars, &xs);
+            let jit = ExprJit::compile(root, &arena, &["x", "k"]).expect("JIT compile failed");
+            for (i, &x) in xs.iter().enumerate() {
+                let mut scalar_vars = HashMap::from([("x".to_string(), x), ("k".to_string(), 0.25)]);
+                let scalar = interpret(root, &arena, &mut scalar_vars);
+                for v in [simd[i], scalar, jit.eval_with(&[x, 0.25])] {
+                    assert!((v - expected[i]).abs() < 1e-12, "{:?} at x={}: {} vs {}", src, x, v, expected[i]);
+                }
+            }
+        }
+
+        // Copying into a hash-consed arena deduplicates an existing tree.
+        let (plain, root) = parse_str("sin(t) * sin(t) + cos(t) * cos(t)").unwrap();
+        let mut cse = Arena::hash_consed();
+        let copy = copy_tree(&plain, root, &mut cse);
+        assert_eq!((plain.len(), cse.len()), (11, 6));
+        let mut vars = HashMap::from([("t".to_string(), 0.7)]);
+        assert!((interpret(copy, &cse, &mut vars) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_differentiate() {
+        use crate::diff::{differentiate, DiffError};
+        use crate::format::unparse;
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::parse_str;
+
+        let simple = [
+            ("x^3 + 2 * x", "3 * x^2 + 2"),
+            ("a * x + b", "a"),
+            ("sin(x) * k", "cos(x) * k"),
+            ("y = 5", "0"),
+            ("let t = x * 2\nt * t", "2 * (x * 2) + x * 2 * 2"),
+        ];
+        for (src, expected) in simple {
+            let (mut arena, root) = parse_str(src).expect("Parsing failed");
+            let d = differentiate(&mut arena, root, "x").expect("differentiate failed");
+            assert_eq!(unparse(&arena, d), expected, "d/dx {:?}", src);
+        }
+
+        // Compare every backend against a central difference; xs avoid kinks.
+        let programs = [
+            "x^2 * sin(x) / (1 + x)",
+            "sqrt(x) + exp(-x) + ln(x) + log10(x) + tan(x / 4)",
+            "x^x + 2^x + cos(x)^2",
+            "atan2(x, k) + hypot(x, k) + abs(x - 2) + floor(x)",
+            "min(x, 2, k * x) + max(1, x^2) + clamp(x, 1, 3)",
+            "if x > 2 then x^3 else -x * k",
+            "f(u, v) = u * v + sin(u)\nlet t = x * k\nf(t, x) + t",
+            "let t = x\nx = 3\nt * x",
+            "f(t) = t^2\nf(x) + f(3 * x)",
+        ];
+        let xs = [0.3, 0.7, 1.4, 2.6, 3.7, 5.2];
+        let h = 1e-6;
+        for src in programs {
+            let (mut arena, root) = parse_str(src).expect("Parsing failed");
+            let d = differentiate(&mut arena, root, "x").expect("differentiate failed");
+            let vars = HashMap::from([("k".to_string(), 1.5)]);
+            let simd = simd_eval_over_x(d, &arena, &vars, &xs);
+            let jit = ExprJit::compile(d, &arena, &["x", "k"]).expect("JIT compile failed");
+            for (i, &x) in xs.iter().enumerate() {
+                let at = |x: f64| {
+                    let mut vars = HashMap::from([("x".to_string(), x), ("k".to_string(), 1.5)]);
+                    interpret(root, &arena, &mut vars)
+                };
+                let numeric = (at(x + h) - at(x - h)) / (2.0 * h);
+                let mut scalar_vars = HashMap::from([("x".to_string(), x), ("k".to_string(), 1.5)]);
+                let scalar = interpret(d, &arena, &mut scalar_vars);
+                assert!((scalar - numeric).abs() < 1e-4 * (1.0 + numeric.abs()), "{:?} at x={}: {} vs {}", src, x, scalar, numeric);
+                for v in [simd[i], jit.eval_with(&[x, 1.5])] {
+                    assert!((v - scalar).abs() < 1e-9 * (1.0 + scalar.abs()), "{:?} at x={}: {} vs {}", src, x, v, scalar);
+                }
+            }
+        }
+
+        // Each call of a function inlines its own arguments.
+        let (mut arena, root) = parse_str("f(t) = t^2\nf(x) + f(3 * x)").unwrap();
+        let d = differentiate(&mut arena, root, "x").unwrap();
+        assert_eq!(interpret(d, &arena, &mut HashMap::from([("x".to_string(), 1.0)])), 20.0);
+
+        // Only the simplified derivative is added to the arena, and a
+        // statement can call a function defined outside it.
+        let (mut arena, root) = parse_str("f(t) = t^2 * k\nf(x) + 1").unwrap();
+        let (before, last) = (arena.len(), arena.children(root)[1]);
+        let d = differentiate(&mut arena, last, "x").unwrap();
+        assert_eq!(unparse(&arena, d), "2 * x * k");
+        assert_eq!(arena.len() - before, arena.tree_len(d));
+
+        // `clamp` is `max` then `min`, so `hi` wins even below `lo`.
+        let (mut arena, root) = parse_str("clamp(k, x, 1)").unwrap();
+        let d = differentiate(&mut arena, root, "x").unwrap();
+        for (x, slope) in [(3.0, 0.0), (0.5, 1.0), (-2.0, 0.0)] {
+            assert_eq!(interpret(d, &arena, &mut HashMap::from([("x".to_string(), x)])), slope, "x = {}", x);
+        }
+
+        let (mut arena, root) = parse_str("let s = 'hi'\nx").unwrap();
+        let err = differentiate(&mut arena, root, "x").unwrap_err();
+        assert!(matches!(err, DiffError::Unsupported { .. }));
+        assert_eq!(err.to_string(), "cannot differentiate a string at 1:9");
+    }
+
+    #[test]
+    fn test_forward_ad() {
+        use crate::ad::{interpret_dual, simd_dual_over_x};
+        use crate::diff::differentiate;
+        use crate::parser::{parse_str, parse_with, ParseOptions};
+
+        // Partials must match the symbolic derivative on both backends.
+        let programs = [
+            "x^2 * sin(k * x) / (1 + x) + k^3",
+            "sqrt(x) + exp(-k * x) + ln(x + k) + log10(x) + tan(x / 4)",
+            "x^k + k^x + atan2(x, k) + hypot(x, k) + abs(x - 2) + floor(x * k)",
+            "min(x, 2, k * x) + max(1, x^2) + clamp(x, 1, k * 2)",
+            "if x > 2 && k > 0 then x^3 * k else -x * k + (x < 1 || !k)",
+            "f(u, v) = u * v + sin(u)\nlet t = x * k\nf(t, x) + t",
+            "(x - 2)^3 * k - (x - 4)^-1",
+        ];
+        let xs = [0.3, 0.7, 1.4, 2.6, 3.7, 5.2];
+        let k = 1.5;
+        for src in programs {
+            let (mut arena, root) = parse_str(src).expect("Parsing failed");
+            let dx = differentiate(&mut arena, root, "x").expect("differentiate failed");
+            let dk = differentiate(&mut arena, root, "k").expect("differentiate failed");
+            let vars = HashMap::from([("k".to_string(), k)]);
+            let simd = simd_dual_over_x(root, &arena, &vars, &xs, &["x", "k"]);
+            assert_eq!(simd.partials.len(), 2);
+            for (i, &x) in xs.iter().enumerate() {
+                let mut scalar_vars = HashMap::from([("x".to_string(), x), ("k".to_string(), k)]);
+                let g = interpret_dual(root, &arena, &scalar_vars, &["x", "k"]);
+                let expected = [
+                    interpret(root, &arena, &mut scalar_vars.clone()),
+                    interpret(dx, &arena, &mut scalar_vars.clone()),
+                    interpret(dk, &arena, &mut scalar_vars),
+                ];
+                let forward = [g.value, g.partials[0], g.partials[1]];
+                let lanes = [simd.values[i], simd.partials[0][i], simd.partials[1][i]];
+                for j in 0..3 {
+                    let tol = 1e-9 * (1.0 + expected[j].abs());
+                    assert!((forward[j] - expected[j]).abs() < tol, "{:?} at x={} [{}]: {} vs {}", src, x, j, forward[j], expected[j]);
+                    assert!((lanes[j] - expected[j]).abs() < tol, "{:?} at x={} [{}] (SIMD): {} vs {}", src, x, j, lanes[j], expected[j]);
+                }
+            }
+        }
+
+        // A constant exponent is fine for a negative base.
+        let (arena, root) = parse_str("x^2 + 2^k").unwrap();
+        let vars = HashMap::from([("x".to_string(), -3.0), ("k".to_string(), 2.0)]);
+        let g = interpret_dual(root, &arena, &vars, &["x", "k", "unused"]);
+        assert_eq!((g.value, g.partials.clone()), (13.0, vec![-6.0, 4.0 * 2f64.ln(), 0.0]));
+
+        // Shared nodes in a hash-consed arena give the same partials.
+        let shared = ParseOptions { hash_cons: true, ..ParseOptions::default() };
+        let src = "let t = x * k\n(t + 1) * (t + 1) + sin(t + 1)";
+        let (plain, plain_root) = parse_str(src).unwrap();
+        let (arena, root) = parse_with(tokenize(src).unwrap(), &shared).unwrap();
+        assert!(arena.has_shared());
+        let vars = HashMap::from([("k".to_string(), 0.5)]);
+        let a = simd_dual_over_x(plain_root, &plain, &vars, &xs, &["x", "k"]);
+        let b = simd_dual_over_x(root, &arena, &vars, &xs, &["x", "k"]);
+        assert_eq!(a, b);
+        assert!(interpret_dual(root, &arena, &vars, &[]).partials.is_empty());
+    }
+
+    #[test]
+    fn test_reverse_ad() {
+        use crate::ad::{gradient, gradient_rows, interpret_dual};
+        use crate::parser::{parse_str, parse_with, ParseOptions};
+
+        // One backward sweep agrees with forward mode for every input.
+        let programs = [
+            "x^2 * sin(k * x) / (1 + x) + k^3 - w",
+            "sqrt(x) + exp(-k * x) + ln(x + k) + log10(x) + tan(x / 4) + cos(w)",
+            "x^k + k^x + atan2(x, k) + hypot(x, w) + abs(x - 2) + floor(x * k)",
+            "min(x, 2, k * x) + max(1, x^2, w) + clamp(x, 1, k * 2)",
+            "if x > 2 && k > 0 then x^3 * k else -x * w + (x < 1 || !k)",
+            "f(u, v) = u * v + sin(u)\nlet t = x * k\nt = f(t, w) + t\nt * x",
+        ];
+        let names = ["x", "k", "w"];
+        let rows: Vec<Vec<f64>> = [0.3, 0.7, 1.4, 2.6, 3.7].iter().map(|&x| vec![x, 1.5, x - 1.0]).collect();
+        for src in programs {
+            let (arena, root) = parse_str(src).expect("Parsing failed");
+            let batch = gradient_rows(root, &arena, &names, &rows);
+            for (row, from_batch) in rows.iter().zip(&batch) {
+                let vars: HashMap<String, f64> = names.iter().map(|n| n.to_string()).zip(row.iter().copied()).collect();
+                let forward = interpret_dual(root, &arena, &vars, &names);
+                let reverse = gradient(root, &arena, &vars);
+                assert_eq!(reverse.value, forward.value);
+                assert_eq!(from_batch.value, forward.value);
+                for (k, name) in names.iter().enumerate() {
+                    let expected = forward.partials[k];
+                    let tol = 1e-12 * (1.0 + expected.abs());
+                    let r = reverse.partials.get(*name).copied().unwrap_or(0.0);
+                    assert!((r - expected).abs() < tol, "{:?} d/d{} at {:?}: {} vs {}", src, name, row, r, expected);
+                    assert!((from_batch.partials[k] - expected).abs() < tol, "{:?} batch d/d{} at {:?}", src, name, row);
+                }
+            }
+        }
+
+        // Names read are reported even when missing from `variables`; names
+        // only in an untaken branch are not.
+        let (arena, root) = parse_str("if a > 0 then a * b else c").unwrap();
+        let g = gradient(root, &arena, &HashMap::from([("a".to_string(), 2.0), ("b".to_string(), 5.0)]));
+        assert_eq!(g.value, 10.0);
+        assert_eq!(g.partials, HashMap::from([("a".to_string(), 5.0), ("b".to_string(), 2.0)]));
+        let g = gradient(root, &arena, &HashMap::new());
+        assert_eq!(g.partials, HashMap::from([("a".to_string(), 0.0), ("c".to_string(), 1.0)]));
+
+        // A shared node is recorded once and its adjoint accumulates.
+        let shared = ParseOptions { hash_cons: true, ..ParseOptions::default() };
+        let (arena, root) = parse_with(tokenize("(a * b) * (a * b) + (a * b)").unwrap(), &shared).unwrap();
+        let batch = gradient_rows(root, &arena, &["a", "b"], &[vec![2.0, 3.0], vec![1.0]]);
+        assert_eq!(batch[0].value, 42.0);
+        assert_eq!(batch[0].partials, vec![39.0, 26.0]);
+        assert_eq!(batch[1].value, 0.0);
+        assert_eq!(batch[1].partials, vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_analysis() {
+        use crate::analysis::analyze;
+        use crate::parser::parse_str;
+        use std::collections::BTreeSet;
+
+        let output_of = |src: &str| {
+            let mut out = Vec::new();
+            crate::script::run(src, &mut out).expect("script failed");
+            String::from_utf8(out).unwrap()
+        };
+
+        let names = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<BTreeSet<_>>();
+        let src = "drag(v, cd) = 0.5 * rho * cd * v^2\nlet m = mass * g\ny = y + 1\nm + drag(speed, 0.3) + (if on then sqrt(m) else 0)";
+        let (arena, root) = parse_str(src).expect("Parsing failed");
+        let a = analyze(&arena, root);
+
+        assert_eq!(a.free.keys().cloned().collect::<BTreeSet<_>>(), names(&["g", "mass", "on", "rho", "speed", "y"]));
+        assert_eq!(a.free["rho"].to_string(), "1:21");
+        assert_eq!(a.free["y"].to_string(), "3:5");
+        assert_eq!(a.assigned, names(&["m", "y"]));
+        assert_eq!(a.calls, names(&["drag", "sqrt"]));
+
+        assert_eq!(a.statements.len(), 4);
+        let defines: Vec<_> = a.statements.iter().map(|s| s.defines.as_deref()).collect();
+        assert_eq!(defines, [Some("drag"), Some("m"), Some("y"), None]);
+        assert!(a.statements[0].reads.is_empty() && a.statements[0].depends_on.is_empty());
+        assert_eq!(a.statements[1].reads, names(&["g", "mass"]));
+        assert!(a.statements[1].depends_on.is_empty());
+        assert_eq!(a.statements[3].reads, names(&["m", "on", "rho", "speed"]));
+        assert_eq!(a.statements[3].depends_on, BTreeSet::from([0, 1]));
+
+        let provided = HashMap::from([("g".to_string(), 9.81), ("mass".to_string(), 2.0), ("y".to_string(), 0.0)]);
+        assert_eq!(a.missing(&provided), ["on", "rho", "speed"]);
+
+        // A rebinding makes later reads depend on the newest statement, and a
+        // name bound before a call resolves the function's free name.
+        let (arena, root) = parse_str("f(a) = a * k\nlet k = 2\nk = k + 1\nf(x) + k").unwrap();
+        let a = analyze(&arena, root);
+        assert_eq!(a.free.keys().collect::<Vec<_>>(), ["x"]);
+        assert_eq!(a.statements[2].depends_on, BTreeSet::from([1]));
+        assert_eq!(a.statements[3].depends_on, BTreeSet::from([0, 2]));
+        assert_eq!(a.statements[3].id, arena.children(root)[3]);
+        // Scoping is dynamic: a function's free name can be its caller's
+        // parameter, as every evaluator resolves it.
+        let (arena, root) = parse_str("g(a) = a + k\nf(k) = g(1)\nf(2) + g(0)").unwrap();
+        assert_eq!(analyze(&arena, root).free.keys().collect::<Vec<_>>(), ["k"]);
+        assert_eq!(interpret(root, &arena, &mut HashMap::from([("k".to_string(), 10.0)])), 13.0);
+        let (arena, root) = parse_str("g(a) = a + k\nf(k) = g(1)\nf(2)").unwrap();
+        assert!(analyze(&arena, root).free.is_empty());
+        assert_eq!(output_of("g(a) = a + k\nf(k) = g(1)\nprint f(2)"), "3\n");
+
+        let (arena, root) = parse_str("a * 2").unwrap();
+        let a = analyze(&arena, root);
+        assert_eq!((a.statements.len(), a.statements[0].id), (1, root));
+        assert!(a.assigned.is_empty());
+    }
+
+    #[test]
+    fn test_serialize() {
+        use crate::format::unparse;
+        use crate::parser::{parse_str, parse_with, ParseOptions};
+        use crate::serialize::{from_binary, from_json, to_binary, to_json, LoadError, MAX_DEPTH};
+
+        let shared = ParseOptions { hash_cons: true, ..ParseOptions::default() };
+        let sources = [
+            "y = 2 * x^2 - sin(x) / 3",
+            "drag(v, cd) = 0.5 * rho * cd * v^2\nlet s = 'fast'\nprint 'drag {}', drag(x, 0.3)\nif !(x < 1 || x >= 4) && k != 0 then drag(x, k) else -inf",
+            "piecewise(x < 0, nan, x < 1, 0xFF, max(x, 2, 3)) + 1e-9",
+        ];
+        for src in sources {
+            for options in [ParseOptions::default(), shared.clone()] {
+                let (arena, root) = parse_with(tokenize(src).unwrap(), &options).expect("Parsing failed");
+                let json = to_json(&arena, root);
+                let binary = to_binary(&arena, root);
+                assert!(binary.len() < json.len() / 3, "{:?}: {} vs {} bytes", src, binary.len(), json.len());
+                for (copy, copy_root) in [from_json(&json).expect("JSON load failed"), from_binary(&binary).expect("binary load failed")] {
+                    assert_eq!(unparse(&copy, copy_root), unparse(&arena, root));
+                    assert_eq!((copy.len(), copy.is_hash_consed(), copy.has_shared()), (arena.len(), arena.is_hash_consed(), arena.has_shared()));
+                    assert!(copy.iter().zip(arena.iter()).all(|((_, a), (_, b))| a.span == b.span));
+                    for x in [-1.0, 0.5, 2.0, 5.0] {
+                        let vars = || HashMap::from([("x".to_string(), x), ("k".to_string(), 2.0), ("rho".to_string(), 1.2)]);
+                        let (a, b) = (interpret(copy_root, &copy, &mut vars()), interpret(root, &arena, &mut vars()));
+                        assert!(a == b || (a.is_nan() && b.is_nan()), "{:?} at x={}: {} vs {}", src, x, a, b);
+                    }
+                }
+            }
+        }
+
+        let (arena, root) = parse_str("f(a) = a + 1\nf(x) * nan").unwrap();
+        let json = to_json(&arena, root);
+        assert!(json.contains("\"version\": 1") && json.contains("\"value\": \"nan\"") && json.contains("\"op\": \"fn_def\""));
+
+        // Hand-written documents that must be rejected.
+        let doc = |nodes: &str, root: usize, functions: &str| {
+            format!(r#"{{"format": "erock-ast", "version": 1, "root": {}, "functions": {{{}}}, "nodes": [{}]}}"#, root, functions, nodes)
+        };
+        let x = r#"{"op": "identifier", "name": "x"}"#;
+        let cases = [
+            (doc(x, 0, ""), None),
+            (doc(&format!(r#"{}, {{"op": "binary", "left": 0, "operator": "+", "right": 2}}"#, x), 1, ""), Some(LoadError::OutOfBounds { node: 1, child: 2 })),
+            (doc(r#"{"op": "unary", "operator": "-", "operand": 1}, {"op": "unary", "operator": "-", "operand": 0}"#, 0, ""), Some(LoadError::Cycle { node: 0 })),
+            (doc(r#"{"op": "lambda"}"#, 0, ""), Some(LoadError::UnknownNode { node: 0, op: "lambda".to_string() })),
+            (doc(&format!(r#"{}, {{"op": "unary", "operator": "*", "operand": 0}}"#, x), 1, ""), Some(LoadError::UnknownOperator { node: 1, op: "*".to_string() })),
+            (doc(&format!(r#"{}, {{"op": "logical", "left": 0, "operator": "+", "right": 0}}"#, x), 1, ""), Some(LoadError::UnknownOperator { node: 1, op: "+".to_string() })),
+            (doc(r#"{"op": "number", "value": "lots"}"#, 0, ""), Some(LoadError::BadNumber { node: 0 })),
+            (doc(x, 3, ""), Some(LoadError::BadRoot { root: 3 })),
+            (doc(&format!(r#"{}, {{"op": "call", "name": "sqrt", "args": [0, 0]}}"#, x), 1, ""), Some(LoadError::BadCall { node: 1, name: "sqrt".to_string() })),
+            (doc(&format!(r#"{}, {{"op": "call", "name": "g", "args": [0]}}"#, x), 1, ""), Some(LoadError::BadCall { node: 1, name: "g".to_string() })),
+            (doc(x, 0, r#""g": 0"#), Some(LoadError::BadFunction { name: "g".to_string() })),
+            (
+                doc(&format!(r#"{}, {{"op": "call", "name": "g", "args": [0]}}, {{"op": "fn_def", "name": "g", "params": ["x"], "body": 1}}"#, x), 2, r#""g": 2"#),
+                Some(LoadError::RecursiveFunction { name: "g".to_string() }),
+            ),
+        ];
+        for (text, expected) in cases {
+            match expected {
+                None => assert!(from_json(&text).is_ok(), "{}", text),
+                Some(e) => assert_eq!(from_json(&text).unwrap_err(), e, "{}", text),
+            }
+        }
+        // Nesting is limited, counting through calls into function bodies.
+        let negate = |from: usize, count: usize| {
+            (from..from + count).map(|i| format!(r#", {{"op": "unary", "operator": "-", "operand": {}}}"#, i)).collect::<String>()
+        };
+        let deepest = doc(&format!("{}{}", x, negate(0, MAX_DEPTH - 1)), MAX_DEPTH - 1, "");
+        let (arena, root) = from_json(&deepest).unwrap();
+        assert_eq!(interpret(root, &arena, &mut HashMap::from([("x".to_string(), 2.0)])), -2.0);
+        let too_deep = doc(&format!("{}{}", x, negate(0, MAX_DEPTH)), MAX_DEPTH, "");
+        assert_eq!(from_json(&too_deep).unwrap_err(), LoadError::TooDeep { node: MAX_DEPTH });
+        let def = r#", {"op": "fn_def", "name": "f", "params": ["x"], "body": 599}, {"op": "call", "name": "f", "args": [0]}"#;
+        let calls = |count: usize| doc(&format!("{}{}{}{}", x, negate(0, 599), def, negate(601, count)), 601 + count, r#""f": 600"#);
+        assert!(from_json(&calls(398)).is_ok());
+        assert_eq!(from_json(&calls(399)).unwrap_err(), LoadError::TooDeep { node: 601 + 399 });
+
+        let wrong = doc(x, 0, "").replace("\"version\": 1", "\"version\": 2");
+        assert_eq!(from_json(&wrong).unwrap_err().to_string(), "unsupported document 'erock-ast' version 2, expected 'erock-ast' version 1");
+        assert!(matches!(from_json("[1, 2]"), Err(LoadError::Json(_))));
+
+        let (arena, root) = parse_str("x * 2 + 1").unwrap();
+        let binary = to_binary(&arena, root);
+        assert!(matches!(from_binary(&binary[..binary.len() - 3]), Err(LoadError::Malformed(_))));
+        assert!(matches!(from_binary(&[binary.as_slice(), &[0]].concat()), Err(LoadError::Malformed(_))));
+        assert!(matches!(from_binary(b"ROCK"), Err(LoadError::Malformed(_))));
+        let mut future = binary.clone();
+        future[4] = 9;
+        assert!(matches!(from_binary(&future), Err(LoadError::UnsupportedVersion { version: 9, .. })));
+    }
+
+    #[test]
+    fn test_vectors() {
+        use crate::format::unparse;
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::optimize::{optimize, optimize_with, OptimizeOptions};
+        use crate::parser::parse_str;
+        use crate::serialize::{from_binary, to_binary};
+        use crate::value::{interpret_value, Value};
+
+        let eval = |src: &str, vars: &[(&str, Value)]| {
+            let (arena, root) = parse_str(src).expect("parse failed");
+            let mut vars = vars.iter().map(|(n, v)| (n.to_string(), v.clone())).collect();
+            interpret_value(root, &arena, &mut vars)
+        };
+        let v = || ("v", Value::from(vec![4.0, 5.0, 6.0]));
+        let cases = [
+            ("[1, 2, 3] * 2 + [10, 20, 30]", Value::from(vec![12.0, 24.0, 36.0])),
+            ("v - 1", Value::from(vec![3.0, 4.0, 5.0])),
+            ("-v[0]^2", Value::from(-16.0)),
+            ("v[len(v) - 1] + v[\n0]", Value::from(10.0)),
+            ("v[[2, 0]]", Value::from(vec![6.0, 4.0])),
+            ("[v, 1]", Value::from(vec![f64::NAN, 1.0])),
+            ("sqrt([4, 9, 16]) + min(v, 5)", Value::from(vec![6.0, 8.0, 9.0])),
+            ("if v > 4 then v else [0, 1, 2]", Value::from(vec![0.0, 5.0, 6.0])),
+            ("v > 4 && [1, 0, 1]", Value::from(vec![0.0, 0.0, 1.0])),
+            ("0 && v", Value::from(0.0)),
+            ("let w = v / 2; scale(a) = a * w; scale([1, 2, 3])", Value::from(vec![2.0, 5.0, 9.0])),
+            ("len([])", Value::from(0.0)),
+        ];
+        for (src, expected) in cases {
+            let got = eval(src, &[v()]);
+            assert_eq!(got.to_string(), expected.to_string(), "{}", src);
+        }
+        for src in ["v[3]", "v[1.5]", "v[-1]", "v + [1, 2]", "len(3)", "3[0]"] {
+            assert!(eval(src, &[v()]).as_scalar().is_some_and(f64::is_nan), "{}", src);
+        }
+        assert_eq!(format!("{:.1}", Value::from(vec![1.0, 2.25])), "[1.0, 2.2]");
+
+        // Vectors print back as written and survive serialization; the
+        // optimizer folds indexing and `len` of constant literals.
+        let (arena, root) = parse_str("-v[i + 1]^2 + [a, b * 2][0] + (-3)[0] + [[1]][0][0]").unwrap();
+        assert_eq!(unparse(&arena, root), "-v[i + 1]^2 + [a, b * 2][0] + (-3)[0] + [[1]][0][0]");
+        let (copy, copy_root) = from_binary(&to_binary(&arena, root)).unwrap();
+        assert_eq!(unparse(&copy, copy_root), unparse(&arena, root));
+        let (arena, root) = parse_str("[1, 2, 3][2] + len([1, 2]) + [1, x][0]").unwrap();
+        let folded = optimize(&arena, root);
+        assert_eq!(unparse(&folded.arena, folded.root), "5 + [1, x][0]");
+        // Nor does it turn a possible vector into a scalar constant;
+        // `fast_math` only assumes variables hold numbers.
+        let fast = OptimizeOptions { fast_math: true, ..OptimizeOptions::default() };
+        let cases = [
+            ("v ^ 0 + f(1) ^ 0", OptimizeOptions::default()),
+            ("[1, 2] ^ 0", fast.clone()),
+            ("[a, b] - [a, b]", fast.clone()),
+            ("[a, 1][0] * 0 + v[0] / v[0]", fast),
+        ];
+        for (src, options) in cases {
+            let (arena, root) = parse_str(&format!("f(a) = [a, a, a]\n{}", src)).unwrap();
+            let opt = optimize_with(&arena, root, &options);
+            let env = || HashMap::from([("v".to_string(), v().1)]);
+            assert_eq!(interpret_value(opt.root, &opt.arena, &mut env()), interpret_value(root, &arena, &mut env()), "{}", src);
+        }
+
+        // The numeric evaluators agree with `interpret_value` where the
+        // result is a number and give NaN for a vector; the JIT refuses them.
+        let (arena, root) = parse_str("let w = [x, 2] * 3; y = w; w[1] - w[0]").unwrap();
+        let mut vars = HashMap::from([("x".to_string(), 1.0)]);
+        assert_eq!(interpret(root, &arena, &mut vars), 3.0);
+        assert!(vars["y"].is_nan());
+        assert_eq!(simd_eval_over_x(root, &arena, &HashMap::new(), &[1.0, 2.0, 0.5]), [3.0, 0.0, 4.5]);
+        let (arena, root) = parse_str("[1, 2]").unwrap();
+        assert!(interpret(root, &arena, &mut HashMap::new()).is_nan());
+        assert!(ExprJit::compile(root, &arena, &[]).err().is_some_and(|e| e.contains("vectors are not supported")));
+        assert!(parse_str("[1, 2").is_err() && parse_str("v[1").is_err() && parse_str("[1 2]").is_err());
+
+        let mut out = Vec::new();
+        crate::script::run("let v = [1.5, 2]\nprint 'v = {:.1}', v * 2, len(v)", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "v = [3.0, 4.0] 2\n");
+    }
+
+    #[test]
+    fn test_reductions() {
+        use crate::functions::Builtin;
+        use crate::interpreter::{simd_eval_over_x, ExprJit};
+        use crate::parser::parse_str;
+        use crate::reduce;
+        use crate::value::{interpret_value, Value};
+
+        let tenths = vec![0.1; 10];
+        assert_eq!(tenths.iter().sum::<f64>(), 0.9999999999999999);
+        assert_eq!(reduce::sum(&tenths), 1.0);
+        assert_eq!(reduce::sum(&[1e16, 1.0, -1e16, 3.0, -2.0]), 2.0);
+        assert_eq!(reduce::sum(&[1.0, f64::INFINITY]), f64::INFINITY);
+        assert!((reduce::norm(&[3e200, 4e200]) / 5e200 - 1.0).abs() < 1e-15);
+        assert_eq!(reduce::norm(&[f64::NAN, f64::INFINITY]), f64::INFINITY);
+        assert!(reduce::norm(&[0.0, f64::NAN]).is_nan());
+        assert_eq!((reduce::min(&[f64::NAN, 2.0, -1.0, 7.0, 3.0]), reduce::max(&[f64::NAN, 2.0, -1.0, 7.0, 3.0])), (-1.0, 7.0));
+        assert!(reduce::mean(&[]).is_nan() && reduce::std(&[]).is_nan() && reduce::max(&[]).is_nan());
+        assert_eq!((reduce::sum(&[]), reduce::norm(&[]), reduce::count_if(&[])), (0.0, 0.0, 0.0));
+        assert!(reduce::dot(&[1.0], &[1.0, 2.0]).is_nan());
+        // A large offset does not swamp the spread.
+        let shifted: Vec<f64> = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].iter().map(|v| v + 1e9).collect();
+        assert_eq!(reduce::std(&shifted), 2.0);
+
+        let v = (1..=9).map(f64::from).collect::<Vec<_>>();
+        let eval = |src: &str| {
+            let (arena, root) = parse_str(src).expect("parse failed");
+            let mut vars = HashMap::from([("v".to_string(), Value::from(v.clone())), ("w".to_string(), Value::from(vec![1.0; 9]))]);
+            interpret_value(root, &arena, &mut vars)
+        };
+        let cases = [
+            ("sum(v)", 45.0),
+            ("mean(v) + len(v)", 14.0),
+            ("std([2, 4, 4, 4, 5, 5, 7, 9])", 2.0),
+            ("dot(v, w) + dot(2, v)", 135.0),
+            ("norm([3, 4]) + norm(-2)", 7.0),
+            ("count_if(v > 3 && v <= 7) + count_if(v == 100)", 4.0),
+            ("min(v) * 10 + max(v)", 19.0),
+            ("sum(v * v) - dot(v, v)", 0.0),
+            ("let c = v - mean(v); sqrt(sum(c^2) / len(v)) - std(v)", 0.0),
+            ("max(min(v, 4))", 4.0),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(eval(src), Value::from(expected), "{}", src);
+        }
+        assert!(eval("dot(v, [1, 2])").as_scalar().is_some_and(f64::is_nan));
+
+        // A scalar reduces like a vector of one element in every evaluator.
+        let src = "sum(x) + mean(x) + std(x) + norm(x) + dot(x, 2) + count_if(x) + min(x) + max(x)";
+        let expected = |x: f64| 6.0 * x + x.abs() + if x != 0.0 { 1.0 } else { 0.0 };
+        let (arena, root) = parse_str(src).unwrap();
+        let jit = ExprJit::compile(root, &arena, &["x"]).unwrap();
+        let xs = [-2.0, 0.0, 0.5, 3.0];
+        let simd = simd_eval_over_x(root, &arena, &HashMap::new(), &xs);
+        for (i, &x) in xs.iter().enumerate() {
+            let mut vars = HashMap::from([("x".to_string(), x)]);
+            assert_eq!(interpret(root, &arena, &mut vars), expected(x));
+            assert_eq!((jit.eval_with(&[x]), simd[i]), (expected(x), expected(x)));
+        }
+        assert_eq!(Builtin::from_name("count_if").map(|b| b.arity().to_string()), Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_operator_table() {
+        use crate::format::{unparse, unparse_with, FormatOptions};
+        use crate::parser::{parse_str, parse_str_with, Assoc, OperatorTable, ParseError, ParseOptions};
+
+        // The default table keeps the standard grouping.
+        let explicit = FormatOptions { spaced: true, explicit_parens: true };
+        let cases = [
+            ("-x^2*3 + 2^-1^2", "((-(x^2)) * 3) + (2^(-(1^2)))"),
+            ("a - b - c < d == !e && f || g", "(((((a - b) - c) < d) == (!e)) && f) || g"),
+            ("-a * b ^ c / d", "((-a) * (b^c)) / d"),
+            ("a || b && c ? 1 : 2", "if a || (b && c) then 1 else 2"),
+        ];
+        for (src, expected) in cases {
+            let (arena, root) = parse_str(src).unwrap();
+            assert_eq!(unparse_with(&arena, root, &explicit), expected, "{}", src);
+        }
+        assert!(matches!(parse_str("7 % 3"), Err(ParseError::Lex(_))));
+
+        let options = ParseOptions {
+            operators: OperatorTable::default()
+                .infix("%", OperatorTable::PRODUCT, Assoc::Left, "fmod")
+                .infix("<>", OperatorTable::SUM + 5, Assoc::Right, "atan2")
+                .infix("dot", OperatorTable::PRODUCT, Assoc::Left, "dot")
+                .prefix("~", "abs"),
+            ..ParseOptions::default()
+        };
+        let parse = |src: &str| parse_str_with(src, &options);
+        let run = |src: &str| {
+            let (arena, root) = parse(src).expect("parse failed");
+            (unparse(&arena, root), interpret(root, &arena, &mut HashMap::from([("x".to_string(), -3.0)])))
+        };
+        let fmod = "fmod(a, b) = a - b * floor(a / b)\n";
+        let (text, v) = run(&format!("{}2 * 7 % 4 + 1", fmod));
+        assert_eq!((text.lines().last().unwrap(), v), ("fmod(2 * 7, 4) + 1", 3.0));
+        assert_eq!(run("1 + ~x^2 * 2 dot 3"), ("1 + dot(abs(x^2) * 2, 3)".to_string(), 55.0));
+        assert_eq!(run("1 <> 0 <> 1 - 1").0, "atan2(1, atan2(0, 1)) - 1");
+        assert_eq!(run("~-x <= 3 && x<>1 < 0").0, "abs(-x) <= 3 && atan2(x, 1) < 0");
+        // `<>` wins over `<`; the standard operators still work around it.
+        assert_eq!(run("x<>-1<x").0, "atan2(x, -1) < x");
+
+        let err = |src: &str| parse(src).unwrap_err();
+        assert!(matches!(err("2 % 3"), ParseError::UnknownFunction { ref name, span } if name == "fmod" && span.start.column == 3));
+        assert!(parse("g(a) = a dot 1\ng(2) % 1").is_err() && parse("g(a) = a dot 1\ng(2)").is_ok());
+        assert!(matches!(err("fmod(a, b) = a % b"), ParseError::RecursiveFunction { .. }));
+        let options = ParseOptions { operators: OperatorTable::default().prefix("~", "atan2"), ..ParseOptions::default() };
+        assert!(matches!(parse_str_with("~1", &options), Err(ParseError::ArityMismatch { found: 1, .. })));
+        assert_eq!(options.operators.symbols(), ["~"]);
+
+        // The top precedence still groups to the left.
+        let options = ParseOptions { operators: OperatorTable::default().infix("%%", u8::MAX, Assoc::Left, "atan2"), ..ParseOptions::default() };
+        let (arena, root) = parse_str_with("1 %% 2 %% 3 ^ 2", &options).unwrap();
+        assert_eq!(unparse(&arena, root), "atan2(atan2(1, 2), 3)^2");
+
+        // A standard symbol is replaced in its own role only: binary `-`
+        // becomes `max`, unary `-` is kept.
+        let options = ParseOptions {
+            operators: OperatorTable::default().infix("-", OperatorTable::SUM, Assoc::Left, "max").infix("**", OperatorTable::POWER, Assoc::Right, "hypot"),
+            ..ParseOptions::default()
+        };
+        let (arena, root) = parse_str_with("-a - b ** 2 - -c", &options).unwrap();
+        assert_eq!(unparse(&arena, root), "max(max(-a, hypot(b, 2)), -c)");
+        assert!(options.operators.symbols().is_empty());
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        use crate::format::unparse;
+        use crate::parser::{parse_str, parse_str_recovering, ParseOptions, Severity};
+
+        let src = "a = (1 + ) * 2\n\
+                   b = sqrt(4, 5) + nope(1)\n\
+                   c = 3 @ 4\n\
+                   d = 2 * [1, 2\n\
+                   e = 5\n\
+                   a + e\n\
+                   e";
+        let out = parse_str_recovering(src, &ParseOptions::default());
+        let found: Vec<(Severity, usize)> = out.diagnostics.iter().map(|d| (d.severity, d.span.start.line)).collect();
+        use Severity::{Error, Warning};
+        assert_eq!(found, [(Error, 1), (Error, 2), (Error, 2), (Error, 3), (Error, 5), (Warning, 6)]);
+        assert!(out.has_errors());
+        assert_eq!(out.diagnostics[0].to_string(), "error: unexpected ')' at 1:10, expected number, identifier, '(', '-', '+', '!' or 'if'");
+        assert!(out.diagnostics[3].message.starts_with("unexpected character '@'"));
+        assert_eq!(out.diagnostics[5].to_string(), "warning: value of the statement at 6:1 is never used");
+
+        // Failed groups become NaN placeholders; only `d` is lost.
+        let root = out.root.unwrap();
+        assert_eq!(unparse(&out.arena, root), "a = nan * 2\nb = nan + nan\nc = 3\ne = 5\na + e\ne");
+        let mut vars = HashMap::new();
+        assert_eq!(interpret(root, &out.arena, &mut vars), 5.0);
+        assert!(vars["a"].is_nan() && !vars.contains_key("d"));
+
+        // Valid input parses as usual, and one statement still gets errors
+        // for each bad group.
+        for src in ["x = (1 +\n 2) * [3, 4][0]; print x", "f(a) = a^2\nf(3)"] {
+            let out = parse_str_recovering(src, &ParseOptions::default());
+            let (arena, root) = parse_str(src).unwrap();
+            assert!(out.diagnostics.is_empty(), "{}", src);
+            assert_eq!(unparse(&out.arena, out.root.unwrap()), unparse(&arena, root));
+        }
+        let out = parse_str_recovering("min() + (2 *) - [1,,2][0]", &ParseOptions::default());
+        assert_eq!(out.diagnostics.len(), 3);
+        assert!(out.root.is_some());
+        let out = parse_str_recovering("", &ParseOptions::default());
+        assert!(out.root.is_none() && out.diagnostics.len() == 1);
+
+        // A definition with trailing input is dropped and not registered,
+        // so a corrected one can follow.
+        let out = parse_str_recovering("f(x) = x 1\nf(x) = x + 1\nf(2)", &ParseOptions::default());
+        assert_eq!(out.diagnostics.len(), 1);
+        let root = out.root.unwrap();
+        assert_eq!(unparse(&out.arena, root), "f(x) = x + 1\nf(2)");
+        assert_eq!(interpret(root, &out.arena, &mut HashMap::new()), 3.0);
+        let out = parse_str_recovering("f(x) = x 1\nf(2)", &ParseOptions::default());
+        assert!(out.arena.function("f").is_none() && out.diagnostics.len() == 2);
+    }
 }
@@ -0,0 +1,376 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Constant folding and algebraic simplification.
+//!
+//! [`optimize`] rebuilds a tree bottom-up into a fresh [`Arena`], so the
+//! result feeds any evaluator: the scalar interpreter, SIMD, or the JIT.
+//! Folding uses the interpreter's own semantics (`x / 0` is NaN, predicates
+//! give 1 or 0), so it never changes a result. Identities are the ones that
+//! hold for every IEEE value, apart from the sign of a zero result for
+//! `x + 0`; rewrites that are wrong for NaN or infinity, like `x - x = 0`,
+//! need [`OptimizeOptions::fast_math`]. Identities that replace an
+//! operand by a constant, like `x ^ 0 = 1`, only apply when the operand
+//! cannot be a vector (see [`crate::value`]), whose result would be one.
+
+use crate::functions::Builtin;
+use crate::interpreter::{binary_op, from_bool, truthy};
+use crate::lexer::{Span, Token};
+use crate::parser::{Arena, ExprKind, NodeId};
+use crate::value::{call_builtin, element, Value};
+use crate::visit::{copy_tree, fold, Fold};
+use std::fmt;
+
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeOptions {
+    /// Merge constants across an operand, so `(x + 2) + 3` becomes
+    /// `x + 5`. Off by default: rounding can differ, and `(x + 1e16) - 1`
+    /// does not round like `x + (1e16 - 1)`. Constants are only merged into
+    /// a finite, normal, non-zero one, so `(x * 1e200) * 1e200` and
+    /// `(x + 1e16) - 1e16` are left alone.
+    pub reassociate: bool,
+    /// Also apply rewrites that assume finite, non-NaN operands: `x - x`,
+    /// `x * 0`, `0 / x` and `x / x`. Variables are assumed to hold numbers
+    /// rather than vectors, so these and `x ^ 0` apply to them.
+    pub fast_math: bool,
+}
+
+/// Which kind of rewrite a [`Change`] records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// An operator or builtin applied to constants, replaced by its value.
+    ConstantFold,
+    /// A conditional or `&&`/`||` whose outcome is known from a constant.
+    DeadBranch,
+    /// `x * 1`, `x + 0`, `x ^ 1`, `--x` and the like.
+    Identity,
+    /// Constants on both sides of an operand merged into one.
+    Reassociate,
+    /// An identity that only holds for finite, non-NaN values.
+    FastMath,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rule::ConstantFold => "folded constant",
+            Rule::DeadBranch => "removed dead branch",
+            Rule::Identity => "applied identity",
+            Rule::Reassociate => "reassociated constants",
+            Rule::FastMath => "applied fast-math identity",
+        })
+    }
+}
+
+/// One rewrite, located by the span of the source it replaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub rule: Rule,
+    pub span: Span,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.rule, self.span)
+    }
+}
+
+/// Output of [`optimize`]: the simplified tree and what was done to get it.
+#[derive(Debug)]
+pub struct Optimized {
+    pub arena: Arena,
+    pub root: NodeId,
+    /// Rewrites in the order they were applied, innermost first.
+    pub changes: Vec<Change>,
+}
+
+pub fn optimize(arena: &Arena, root: NodeId) -> Optimized {
+    optimize_with(arena, root, &OptimizeOptions::default())
+}
+
+/// [`optimize`] with `options`. Functions defined outside the tree at
+/// `root` are copied into the result unchanged, so calls to them still
+/// resolve.
+pub fn optimize_with(arena: &Arena, root: NodeId, options: &OptimizeOptions) -> Optimized {
+    let mut optimizer = Optimizer { out: Arena::new(), options, changes: Vec::new() };
+    let root = fold(arena, root, &mut optimizer);
+    let mut out = optimizer.out;
+    let mut outside: Vec<NodeId> = arena.functions().filter(|(name, _)| out.function(name).is_none()).map(|(_, def)| def).collect();
+    outside.sort_by_key(|def| def.index());
+    for def in outside {
+        copy_tree(arena, def, &mut out);
+    }
+    Optimized { arena: out, root, changes: optimizer.changes }
+}
+
+// Result of one rewrite step: an existing node, or a new kind that may
+// simplify further.
+enum Rewritten {
+    Node(NodeId),
+    Kind(ExprKind),
+}
+
+struct Optimizer<'a> {
+    out: Arena,
+    options: &'a OptimizeOptions,
+    changes: Vec<Change>,
+}
+
+impl Fold for Optimizer<'_> {
+    type Output = NodeId;
+
+    fn fold(&mut self, arena: &Arena, id: NodeId, kind: &ExprKind, children: Vec<NodeId>) -> NodeId {
+        let mut children = children.into_iter();
+        let mut kind = kind.map_children(|_| children.next().expect("one result per child"));
+        let span = arena[id].span;
+        loop {
+            let Some((rule, rewritten)) = self.rewrite(&kind, span) else {
+                let node = self.out.alloc_at(kind, span);
+                if let ExprKind::FnDef { name, .. } = &self.out[node].kind {
+                    let name = name.clone();
+                    self.out.define_function(&name, node);
+                }
+                return node;
+            };
+            self.changes.push(Change { rule, span });
+            match rewritten {
+                Rewritten::Node(node) => return node,
+                Rewritten::Kind(next) => kind = next,
+            }
+        }
+    }
+}
+
+impl Optimizer<'_> {
+    fn num(&self, id: NodeId) -> Option<f64> {
+        match self.out[id].kind {
+            ExprKind::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn constant(&mut self, v: f64, span: Span) -> NodeId {
+        self.out.alloc_at(ExprKind::Number(v), span)
+    }
+
+    // One simplification of `kind`, whose children are already optimized.
+    fn rewrite(&mut self, kind: &ExprKind, span: Span) -> Option<(Rule, Rewritten)> {
+        use Rewritten::{Kind, Node};
+        match kind {
+            ExprKind::Unary { op, operand } => {
+                if let Some(v) = self.num(*operand) {
+                    let v = match op {
+                        Token::Minus => -v,
+                        Token::Plus => v,
+                        Token::Bang => from_bool(!truthy(v)),
+                        _ => return None,
+                    };
+                    return Some((Rule::ConstantFold, Kind(ExprKind::Number(v))));
+                }
+                match (op, &self.out[*operand].kind) {
+                    (Token::Plus, _) => Some((Rule::Identity, Node(*operand))),
+                    (Token::Minus, ExprKind::Unary { op: Token::Minus, operand: inner }) => {
+                        Some((Rule::Identity, Node(*inner)))
+                    }
+                    _ => None,
+                }
+            }
+            ExprKind::Binary { left, op, right } => {
+                let (l, r) = (self.num(*left), self.num(*right));
+                if let (Some(a), Some(b)) = (l, r) {
+                    return Some((Rule::ConstantFold, Kind(ExprKind::Number(binary_op(op, a, b)))));
+                }
+                if let Some(node) = identity(op, *left, l, *right, r) {
+                    return Some((Rule::Identity, node));
+                }
+                let names_are_scalar = self.options.fast_math;
+                if *op == Token::Caret && r == Some(0.0) && !self.may_be_vector(*left, names_are_scalar) {
+                    // pow(x, 0) is 1 even for NaN.
+                    return Some((Rule::Identity, Kind(ExprKind::Number(1.0))));
+                }
+                if self.options.fast_math && !self.may_be_vector(*left, true) && !self.may_be_vector(*right, true) {
+                    if let Some(v) = self.fast_math(op, *left, l, *right, r) {
+                        return Some((Rule::FastMath, Kind(ExprKind::Number(v))));
+                    }
+                }
+                if self.options.reassociate {
+                    if let Some(kind) = self.reassociate(*left, op, *right, span) {
+                        return Some((Rule::Reassociate, Kind(kind)));
+                    }
+                }
+                None
+            }
+            ExprKind::Logical { left, op, right } => {
+                let (rule, v) = match (op, self.num(*left).map(truthy)) {
+                    (Token::AndAnd, Some(false)) => (Rule::DeadBranch, 0.0),
+                    (Token::OrOr, Some(true)) => (Rule::DeadBranch, 1.0),
+                    (_, Some(_)) => (Rule::ConstantFold, from_bool(truthy(self.num(*right)?))),
+                    _ => return None,
+                };
+                Some((rule, Kind(ExprKind::Number(v))))
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                let taken = if truthy(self.num(*cond)?) { *then_branch } else { *else_branch };
+                Some((Rule::DeadBranch, Node(taken)))
+            }
+            ExprKind::Index { target, index } => {
+                let i = self.num(*index)?;
+                let items = self.constant_vector(*target)?;
+                let v = element(items.len(), i).map_or(f64::NAN, |k| items[k]);
+                Some((Rule::ConstantFold, Kind(ExprKind::Number(v))))
+            }
+            ExprKind::Call { name, args } => {
+                let builtin = Builtin::from_name(name)?;
+                let vals = args.iter().map(|&a| self.value(a)).collect::<Option<Vec<_>>>()?;
+                let v = call_builtin(builtin, &vals).as_scalar()?;
+                Some((Rule::ConstantFold, Kind(ExprKind::Number(v))))
+            }
+            _ => None,
+        }
+    }
+
+    // A constant, or a vector literal of constants.
+    fn value(&self, id: NodeId) -> Option<Value> {
+        match self.num(id) {
+            Some(v) => Some(Value::Scalar(v)),
+            None => self.constant_vector(id).map(Value::Vector),
+        }
+    }
+
+    // Elements of a vector literal whose elements are all constants.
+    fn constant_vector(&self, id: NodeId) -> Option<Vec<f64>> {
+        match &self.out[id].kind {
+            ExprKind::Vector { elements } => elements.iter().map(|&e| self.num(e)).collect(),
+            _ => None,
+        }
+    }
+
+    // Whether the tree at `id` could evaluate to a vector: it holds a vector
+    // literal, an index, a call to a user function, or unless
+    // `names_are_scalar` a variable.
+    fn may_be_vector(&self, id: NodeId, names_are_scalar: bool) -> bool {
+        let kind = &self.out[id].kind;
+        let here = match kind {
+            ExprKind::Vector { .. } | ExprKind::Index { .. } => true,
+            ExprKind::Identifier(_) => !names_are_scalar,
+            ExprKind::Call { name, .. } => Builtin::from_name(name).is_none(),
+            _ => false,
+        };
+        here || kind.children().into_iter().any(|c| self.may_be_vector(c, names_are_scalar))
+    }
+
+    fn fast_math(&self, op: &Token, left: NodeId, l: Option<f64>, right: NodeId, r: Option<f64>) -> Option<f64> {
+        match op {
+            Token::Minus if same_tree(&self.out, left, right) => Some(0.0),
+            Token::Slash if same_tree(&self.out, left, right) => Some(1.0),
+            Token::Star if l == Some(0.0) || r == Some(0.0) => Some(0.0),
+            Token::Slash if l == Some(0.0) => Some(0.0),
+            _ => None,
+        }
+    }
+
+    // `(a op1 c1) op c2` or `(c1 op1 a) op c2` with `op`/`op1` from the same
+    // family (`+ -` or `* /`) becomes a single operation between `a` and one
+    // constant.
+    fn reassociate(&mut self, left: NodeId, op: &Token, right: NodeId, span: Span) -> Option<ExprKind> {
+        let c2 = self.num(right)?;
+        let ExprKind::Binary { left: ll, op: op1, right: lr } = self.out[left].kind.clone() else { return None };
+        let additive = |t: &Token| matches!(t, Token::Plus | Token::Minus);
+        let multiplicative = |t: &Token| matches!(t, Token::Star | Token::Slash);
+        if !(additive(op) && additive(&op1) || multiplicative(op) && multiplicative(&op1)) {
+            return None;
+        }
+        // `x / 0` is NaN here but the merged constant would be infinite.
+        let divides = *op == Token::Slash || op1 == Token::Slash;
+        if divides && (c2 == 0.0 || self.num(ll) == Some(0.0) || self.num(lr) == Some(0.0)) {
+            return None;
+        }
+        use Token::{Minus, Plus, Slash, Star};
+        let (a, result_op, k) = match (self.num(ll), self.num(lr)) {
+            (None, Some(c1)) => match (&op1, op) {
+                (Plus, Plus) => (ll, Plus, c1 + c2),
+                (Plus, Minus) => (ll, Plus, c1 - c2),
+                (Minus, Plus) => (ll, Minus, c1 - c2),
+                (Minus, Minus) => (ll, Minus, c1 + c2),
+                (Star, Star) => (ll, Star, c1 * c2),
+                (Star, Slash) => (ll, Star, c1 / c2),
+                (Slash, Star) => (ll, Slash, c1 / c2),
+                (Slash, Slash) => (ll, Slash, c1 * c2),
+                _ => return None,
+            },
+            // `c1 - a` and `c1 / a` keep the constant on the left.
+            (Some(c1), None) => match (&op1, op) {
+                (Plus, Plus) => (lr, Plus, c1 + c2),
+                (Plus, Minus) => (lr, Plus, c1 - c2),
+                (Star, Star) => (lr, Star, c1 * c2),
+                (Star, Slash) => (lr, Star, c1 / c2),
+                (Minus, Plus | Minus) | (Slash, Star | Slash) => {
+                    let k = match op {
+                        Plus => c1 + c2,
+                        Minus => c1 - c2,
+                        Star => c1 * c2,
+                        _ => c1 / c2,
+                    };
+                    if !k.is_normal() {
+                        return None;
+                    }
+                    let k = self.constant(k, span);
+                    return Some(ExprKind::Binary { left: k, op: op1, right: lr });
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // An infinite, zero or subnormal constant would overflow or drop
+        // what the two steps kept.
+        if !k.is_normal() {
+            return None;
+        }
+        // Prefer `x - 5` to `x + -5`.
+        let (result_op, k) = match result_op {
+            Plus if k < 0.0 => (Minus, -k),
+            Minus if k < 0.0 => (Plus, -k),
+            _ => (result_op, k),
+        };
+        let k = self.constant(k, span);
+        Some(ExprKind::Binary { left: a, op: result_op, right: k })
+    }
+}
+
+// Operand that `left op right` reduces to when one side is a neutral
+// constant; exact for every IEEE value except that `-0 + 0` gives `+0`.
+fn identity(op: &Token, left: NodeId, l: Option<f64>, right: NodeId, r: Option<f64>) -> Option<Rewritten> {
+    let keep = match op {
+        Token::Star if r == Some(1.0) => left,
+        Token::Star if l == Some(1.0) => right,
+        Token::Slash | Token::Caret if r == Some(1.0) => left,
+        Token::Plus | Token::Minus if r == Some(0.0) => left,
+        Token::Plus if l == Some(0.0) => right,
+        _ => return None,
+    };
+    Some(Rewritten::Node(keep))
+}
+
+/// Whether the trees at `a` and `b` are structurally identical.
+pub fn same_tree(arena: &Arena, a: NodeId, b: NodeId) -> bool {
+    if a == b {
+        return true;
+    }
+    let (ka, kb) = (&arena[a].kind, &arena[b].kind);
+    let same_node = match (ka, kb) {
+        (ExprKind::Number(x), ExprKind::Number(y)) => x.to_bits() == y.to_bits(),
+        (ExprKind::Identifier(x), ExprKind::Identifier(y)) => x == y,
+        (ExprKind::Unary { op: x, .. }, ExprKind::Unary { op: y, .. })
+        | (ExprKind::Binary { op: x, .. }, ExprKind::Binary { op: y, .. })
+        | (ExprKind::Logical { op: x, .. }, ExprKind::Logical { op: y, .. }) => x == y,
+        (ExprKind::If { .. }, ExprKind::If { .. })
+        | (ExprKind::Vector { .. }, ExprKind::Vector { .. })
+        | (ExprKind::Index { .. }, ExprKind::Index { .. }) => true,
+        (ExprKind::Call { name: x, .. }, ExprKind::Call { name: y, .. }) => x == y,
+        _ => false,
+    };
+    let (ca, cb) = (ka.children(), kb.children());
+    same_node && ca.len() == cb.len() && ca.iter().zip(&cb).all(|(&x, &y)| same_tree(arena, x, y))
+}
@@ -3,67 +3,750 @@ SPDX-FileCopyrightText: 2025 Eric Waller
 SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
 */
 
-use crate::lexer::Token;
+use crate::functions::{Arity, Builtin};
+use crate::lexer::{tokenize, tokenize_recovering, tokenize_with, LexError, Position, Span, SpannedToken, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Index;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(Debug, Clone)]
 pub enum ExprKind {
     Number(f64),
     Identifier(String),
+    /// Prefix `-`, `+` or `!`.
+    Unary {
+        op: Token,
+        operand: NodeId,
+    },
     Binary {
-        left: usize,
+        left: NodeId,
+        op: Token,
+        right: NodeId,
+    },
+    /// Short-circuiting `&&` / `||`; the right side is only evaluated when
+    /// the left side does not decide the result.
+    Logical {
+        left: NodeId,
         op: Token,
-        right: usize,
+        right: NodeId,
+    },
+    /// `if cond then a else b` or `cond ? a : b`; only the chosen branch is
+    /// evaluated. `piecewise(...)` is desugared into nested `If`s.
+    If {
+        cond: NodeId,
+        then_branch: NodeId,
+        else_branch: NodeId,
+    },
+    /// `name(args...)`; `name` is a [`Builtin`] or a function defined
+    /// earlier in the program, checked at parse time.
+    Call {
+        name: String,
+        args: Vec<NodeId>,
     },
     Assign {
         name: String,
-        value: usize,
+        value: NodeId,
+    },
+    /// `let name = value`: binds `name` for the rest of the enclosing
+    /// program without touching the caller's variables.
+    Let {
+        name: String,
+        value: NodeId,
+    },
+    /// Statements separated by `;` or newlines; evaluates to the value of
+    /// the last one. Only produced when there is more than one statement.
+    Program {
+        statements: Vec<NodeId>,
+    },
+    /// A string literal. Only allowed as a whole `let`/`=` value or `print`
+    /// argument; the numeric evaluators treat it as NaN.
+    Str(String),
+    /// `print a, b, ...`: output for the script runner
+    /// ([`crate::script`]); evaluates to NaN elsewhere.
+    Print {
+        args: Vec<NodeId>,
+    },
+    /// `name(params...) = body`. Evaluates to NaN where it appears; calls
+    /// find it through [`Arena::function`]. Names in `body` other than the
+    /// parameters resolve in the caller's scope.
+    FnDef {
+        name: String,
+        params: Vec<String>,
+        body: NodeId,
+    },
+    /// `[a, b, ...]`, a vector of scalars. Only
+    /// [`crate::interpreter::interpret_value`] evaluates vectors; the other
+    /// evaluators treat them as NaN.
+    Vector {
+        elements: Vec<NodeId>,
+    },
+    /// `target[index]`, counting from 0.
+    Index {
+        target: NodeId,
+        index: NodeId,
     },
 }
 
+impl ExprKind {
+    /// Call `f` on each direct child, in evaluation order. A `Call` to a
+    /// user function does not include the function's body.
+    pub fn for_each_child(&self, mut f: impl FnMut(NodeId)) {
+        match self {
+            ExprKind::Number(_) | ExprKind::Identifier(_) | ExprKind::Str(_) => {}
+            ExprKind::Unary { operand, .. } => f(*operand),
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                f(*left);
+                f(*right);
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                f(*cond);
+                f(*then_branch);
+                f(*else_branch);
+            }
+            ExprKind::Call { args, .. } | ExprKind::Print { args } => args.iter().copied().for_each(f),
+            ExprKind::Vector { elements } => elements.iter().copied().for_each(f),
+            ExprKind::Index { target, index } => {
+                f(*target);
+                f(*index);
+            }
+            ExprKind::Program { statements } => statements.iter().copied().for_each(f),
+            ExprKind::Assign { value, .. } | ExprKind::Let { value, .. } => f(*value),
+            ExprKind::FnDef { body, .. } => f(*body),
+        }
+    }
+
+    /// Direct children, in the order of [`ExprKind::for_each_child`].
+    pub fn children(&self) -> Vec<NodeId> {
+        let mut children = Vec::new();
+        self.for_each_child(|c| children.push(c));
+        children
+    }
+
+    /// The same node with each child replaced by `f(child)`, e.g. to copy a
+    /// tree into another arena.
+    pub fn map_children(&self, mut f: impl FnMut(NodeId) -> NodeId) -> ExprKind {
+        match self {
+            ExprKind::Number(_) | ExprKind::Identifier(_) | ExprKind::Str(_) => self.clone(),
+            ExprKind::Unary { op, operand } => ExprKind::Unary { op: op.clone(), operand: f(*operand) },
+            ExprKind::Binary { left, op, right } => {
+                let left = f(*left);
+                ExprKind::Binary { left, op: op.clone(), right: f(*right) }
+            }
+            ExprKind::Logical { left, op, right } => {
+                let left = f(*left);
+                ExprKind::Logical { left, op: op.clone(), right: f(*right) }
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                let (cond, then_branch) = (f(*cond), f(*then_branch));
+                ExprKind::If { cond, then_branch, else_branch: f(*else_branch) }
+            }
+            ExprKind::Call { name, args } => ExprKind::Call { name: name.clone(), args: args.iter().map(|&a| f(a)).collect() },
+            ExprKind::Print { args } => ExprKind::Print { args: args.iter().map(|&a| f(a)).collect() },
+            ExprKind::Program { statements } => {
+                ExprKind::Program { statements: statements.iter().map(|&s| f(s)).collect() }
+            }
+            ExprKind::Assign { name, value } => ExprKind::Assign { name: name.clone(), value: f(*value) },
+            ExprKind::Let { name, value } => ExprKind::Let { name: name.clone(), value: f(*value) },
+            ExprKind::FnDef { name, params, body } => {
+                ExprKind::FnDef { name: name.clone(), params: params.clone(), body: f(*body) }
+            }
+            ExprKind::Vector { elements } => ExprKind::Vector { elements: elements.iter().map(|&e| f(e)).collect() },
+            ExprKind::Index { target, index } => {
+                let target = f(*target);
+                ExprKind::Index { target, index: f(*index) }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Expr {
     pub kind: ExprKind,
+    /// Source range the node was parsed from (default for synthesized nodes).
+    pub span: Span,
+}
+
+/// Handle to a node in an [`Arena`]. Each arena stamps its ids with its own
+/// tag, so an id used with a different arena is caught: [`Arena::get`]
+/// returns `None` and indexing panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: u32,
+    arena: u32,
+}
+
+impl NodeId {
+    /// Position in allocation order, for side tables indexed by node.
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.index)
+    }
 }
 
+static NEXT_ARENA: AtomicU32 = AtomicU32::new(0);
+
+// Identity of a node for hash-consing. Children are already shared, so
+// comparing their ids is enough to compare whole subtrees.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct ConsKey {
+    tag: &'static str,
+    text: String,
+    bits: u64,
+    children: Vec<NodeId>,
+}
+
+impl ConsKey {
+    // `None` for statements, which stay distinct even when identical.
+    fn of(kind: &ExprKind) -> Option<ConsKey> {
+        let (tag, text, bits) = match kind {
+            ExprKind::Number(n) => ("number", String::new(), n.to_bits()),
+            ExprKind::Identifier(name) => ("identifier", name.clone(), 0),
+            ExprKind::Str(text) => ("string", text.clone(), 0),
+            ExprKind::Unary { op, .. } => ("unary", op.to_string(), 0),
+            ExprKind::Binary { op, .. } => ("binary", op.to_string(), 0),
+            ExprKind::Logical { op, .. } => ("logical", op.to_string(), 0),
+            ExprKind::If { .. } => ("if", String::new(), 0),
+            ExprKind::Call { name, .. } => ("call", name.clone(), 0),
+            ExprKind::Vector { .. } => ("vector", String::new(), 0),
+            ExprKind::Index { .. } => ("index", String::new(), 0),
+            _ => return None,
+        };
+        Some(ConsKey { tag, text, bits, children: kind.children() })
+    }
+}
+
+#[derive(Debug)]
 pub struct Arena {
+    tag: u32,
     nodes: Vec<Expr>,
+    /// How many nodes refer to each node as a child.
+    uses: Vec<u32>,
+    shared: usize,
+    /// Set in hash-consing mode: the node already allocated for each shape.
+    consed: Option<HashMap<ConsKey, NodeId>>,
+    functions: HashMap<String, NodeId>,
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Arena::new()
+    }
 }
 
 impl Arena {
     pub fn new() -> Self {
-        Arena { nodes: Vec::new() }
+        let tag = NEXT_ARENA.fetch_add(1, Ordering::Relaxed);
+        Arena { tag, nodes: Vec::new(), uses: Vec::new(), shared: 0, consed: None, functions: HashMap::new() }
+    }
+
+    /// An arena in hash-consing mode: allocating an expression identical to
+    /// one already present returns the existing id, so a repeated
+    /// subexpression like the `a + b` in `(a + b) * (a + b)` becomes one
+    /// shared node that the evaluators compute once. Statements are never
+    /// merged. A shared node keeps the span of its first occurrence, which
+    /// is never widened to take in enclosing parentheses.
+    pub fn hash_consed() -> Self {
+        Arena { consed: Some(HashMap::new()), ..Arena::new() }
+    }
+
+    pub fn is_hash_consed(&self) -> bool {
+        self.consed.is_some()
+    }
+
+    pub fn alloc(&mut self, kind: ExprKind) -> NodeId {
+        self.alloc_at(kind, Span::default())
+    }
+
+    pub fn alloc_at(&mut self, kind: ExprKind, span: Span) -> NodeId {
+        let key = self.consed.as_ref().and_then(|_| ConsKey::of(&kind));
+        if let (Some(consed), Some(key)) = (&self.consed, &key) {
+            if let Some(&id) = consed.get(key) {
+                return id;
+            }
+        }
+        let id = NodeId { index: self.nodes.len() as u32, arena: self.tag };
+        let (tag, uses, shared) = (self.tag, &mut self.uses, &mut self.shared);
+        kind.for_each_child(|child| {
+            // Foreign ids are caught when the node is read.
+            let Some(count) = uses.get_mut(child.index()).filter(|_| child.arena == tag) else { return };
+            *count += 1;
+            if *count == 2 {
+                *shared += 1;
+            }
+        });
+        self.nodes.push(Expr { kind, span });
+        self.uses.push(0);
+        if let (Some(consed), Some(key)) = (&mut self.consed, key) {
+            consed.insert(key, id);
+        }
+        id
+    }
+
+    /// Whether `id` was allocated by this arena.
+    pub fn contains(&self, id: NodeId) -> bool {
+        id.arena == self.tag && id.index() < self.nodes.len()
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Expr> {
+        if id.arena != self.tag {
+            return None;
+        }
+        self.nodes.get(id.index())
+    }
+
+    pub fn span(&self, id: NodeId) -> Option<Span> {
+        self.get(id).map(|e| e.span)
+    }
+
+    // Parser-internal: nodes are otherwise immutable once allocated. Does
+    // nothing when hash-consing, since `id` may be an earlier occurrence
+    // that other nodes share or will share.
+    fn set_span(&mut self, id: NodeId, span: Span) {
+        if !self.is_hash_consed() {
+            self.nodes[id.index()].span = span;
+        }
+    }
+
+    /// Whether more than one node refers to `id` as a child, as happens in
+    /// hash-consing mode.
+    pub fn is_shared(&self, id: NodeId) -> bool {
+        self.contains(id) && self.uses[id.index()] > 1
+    }
+
+    /// Whether any node is shared.
+    pub fn has_shared(&self) -> bool {
+        self.shared > 0
+    }
+
+    /// Number of nodes allocated, reachable from a given root or not.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Ids of all nodes in allocation order; children come before the
+    /// nodes that refer to them.
+    pub fn ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        (0..self.nodes.len() as u32).map(|index| NodeId { index, arena: self.tag })
+    }
+
+    /// All nodes with their ids, in allocation order.
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Expr)> + '_ {
+        self.ids().zip(&self.nodes)
+    }
+
+    /// Direct children of `id`; empty for leaves and foreign ids.
+    pub fn children(&self, id: NodeId) -> Vec<NodeId> {
+        self.get(id).map(|e| e.kind.children()).unwrap_or_default()
+    }
+
+    /// Number of nodes in the tree under `root`, counting a shared node once
+    /// per reference. Saturates at `usize::MAX`, which a heavily shared tree
+    /// can exceed.
+    pub fn tree_len(&self, root: NodeId) -> usize {
+        self.bottom_up(root, |total, child| total.saturating_add(child))
+    }
+
+    /// Nodes on the longest path from `root` down to a leaf; a lone leaf
+    /// has depth 1.
+    pub fn depth(&self, root: NodeId) -> usize {
+        self.bottom_up(root, usize::max)
+    }
+
+    // One plus `combine` over the children's results, for every node up to
+    // `root` in allocation order. Children come first, so each node is
+    // computed once however many nodes share it.
+    fn bottom_up(&self, root: NodeId, combine: impl Fn(usize, usize) -> usize) -> usize {
+        if !self.contains(root) {
+            return 1;
+        }
+        let mut results: Vec<usize> = Vec::with_capacity(root.index() + 1);
+        for expr in &self.nodes[..=root.index()] {
+            let mut acc = 0;
+            expr.kind.for_each_child(|child| acc = combine(acc, results[child.index()]));
+            results.push(acc.saturating_add(1));
+        }
+        results[root.index()]
+    }
+
+    /// Record `id` (an `FnDef` node) as the definition of `name`.
+    pub fn define_function(&mut self, name: &str, id: NodeId) {
+        self.functions.insert(name.to_string(), id);
+    }
+
+    /// The `FnDef` node defining `name`, if any.
+    pub fn function(&self, name: &str) -> Option<NodeId> {
+        self.functions.get(name).copied()
+    }
+
+    /// Every defined function with its `FnDef` node, in no particular order.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, NodeId)> + '_ {
+        self.functions.iter().map(|(name, &id)| (name.as_str(), id))
+    }
+}
+
+impl Index<NodeId> for Arena {
+    type Output = Expr;
+
+    /// Panics if `id` belongs to another arena.
+    fn index(&self, id: NodeId) -> &Expr {
+        assert_eq!(id.arena, self.tag, "NodeId {} used with an arena that did not allocate it", id);
+        &self.nodes[id.index()]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The input could not be tokenized.
+    Lex(LexError),
+    /// A token appeared where none of `expected` could.
+    UnexpectedToken { found: Token, expected: Vec<String>, span: Span },
+    /// A `(` or `[` that is never closed or a `)` that closes nothing;
+    /// `span` points at the offending parenthesis or bracket.
+    UnbalancedParen { span: Span },
+    /// Tokens left over after a complete statement, e.g. the `4` in `2 + 3 4`.
+    TrailingInput { found: Token, span: Span },
+    /// The input ended while one of `expected` was still required.
+    UnexpectedEof { expected: Vec<String>, span: Span },
+    /// `name(...)` where `name` is not a known function.
+    UnknownFunction { name: String, span: Span },
+    /// A call with the wrong number of arguments.
+    ArityMismatch { name: String, expected: Arity, found: usize, span: Span },
+    /// A function defined twice, or with the name of a builtin.
+    DuplicateFunction { name: String, span: Span },
+    /// A parameter listed twice in one definition; `span` points at the
+    /// second.
+    DuplicateParameter { name: String, span: Span },
+    /// A function whose body calls itself.
+    RecursiveFunction { name: String, span: Span },
+    /// An expression statement before the last, whose value is discarded,
+    /// e.g. `- discount` read as its own statement after a line break.
+    UnusedValue { span: Span },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::Lex(e) => e.span(),
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::UnbalancedParen { span }
+            | ParseError::TrailingInput { span, .. }
+            | ParseError::UnexpectedEof { span, .. }
+            | ParseError::UnknownFunction { span, .. }
+            | ParseError::ArityMismatch { span, .. }
+            | ParseError::DuplicateFunction { span, .. }
+            | ParseError::DuplicateParameter { span, .. }
+            | ParseError::RecursiveFunction { span, .. }
+            | ParseError::UnusedValue { span } => *span,
+        }
+    }
+}
+
+fn expected_list(expected: &[String]) -> String {
+    match expected {
+        [] => "more input".to_string(),
+        [one] => one.clone(),
+        [init @ .., last] => format!("{} or {}", init.join(", "), last),
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Lex(e) => e.fmt(f),
+            ParseError::UnexpectedToken { found, expected, span } => {
+                write!(f, "unexpected {} at {}, expected {}", found, span, expected_list(expected))
+            }
+            ParseError::UnbalancedParen { span } => write!(f, "unbalanced parenthesis at {}", span),
+            ParseError::TrailingInput { found, span } => {
+                write!(f, "unexpected {} at {} after end of expression", found, span)
+            }
+            ParseError::UnexpectedEof { expected, span } => {
+                write!(f, "unexpected end of input at {}, expected {}", span, expected_list(expected))
+            }
+            ParseError::UnknownFunction { name, span } => write!(f, "unknown function '{}' at {}", name, span),
+            ParseError::ArityMismatch { name, expected, found, span } => write!(
+                f,
+                "'{}' takes {} argument(s) but {} were given at {}",
+                name, expected, found, span
+            ),
+            ParseError::DuplicateFunction { name, span } => {
+                write!(f, "function '{}' is already defined (at {})", name, span)
+            }
+            ParseError::DuplicateParameter { name, span } => {
+                write!(f, "parameter '{}' is listed twice (at {})", name, span)
+            }
+            ParseError::RecursiveFunction { name, span } => {
+                write!(f, "function '{}' calls itself at {}; recursion is not supported", name, span)
+            }
+            ParseError::UnusedValue { span } => write!(f, "value of the statement at {} is never used", span),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<LexError> for ParseError {
+    fn from(e: LexError) -> Self {
+        ParseError::Lex(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// One problem found by [`parse_str_recovering`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(e: ParseError) -> Self {
+        Diagnostic { severity: Severity::Error, message: e.to_string(), span: e.span() }
+    }
+}
+
+/// What [`parse_str_recovering`] made of its input.
+#[derive(Debug)]
+pub struct Recovered {
+    /// Every node parsed, including those of statements that were dropped.
+    pub arena: Arena,
+    /// The statements that parsed, as [`parse`] would return them; `None`
+    /// if none did.
+    pub root: Option<NodeId>,
+    /// In source order.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Recovered {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.severity == Severity::Error)
+    }
+}
+
+/// How an infix operator groups with itself: `a - b - c` is `(a - b) - c`
+/// (`Left`), `a ^ b ^ c` is `a ^ (b ^ c)` (`Right`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+// The node an operator builds from its operands.
+#[derive(Debug, Clone, PartialEq)]
+enum OpNode {
+    Unary(Token),
+    Binary(Token),
+    Logical(Token),
+    Call(String),
+}
+
+#[derive(Debug, Clone)]
+struct InfixOp {
+    token: Token,
+    precedence: u8,
+    assoc: Assoc,
+    node: OpNode,
+}
+
+#[derive(Debug, Clone)]
+struct PrefixOp {
+    token: Token,
+    node: OpNode,
+}
+
+/// The operators the expression parser recognizes and how tightly each
+/// binds. The default holds the standard syntax; [`OperatorTable::infix`]
+/// and [`OperatorTable::prefix`] add operators that stand for calls, so
+/// with `.infix("%", OperatorTable::PRODUCT, Assoc::Left, "fmod")`,
+/// `a % b` parses as `fmod(a, b)`.
+///
+/// A symbol made of punctuation needs lexing with [`tokenize_with`] (as
+/// [`parse_str_with`] does); one that starts with a letter, like `mod`, is
+/// an identifier and works with any token stream. Prefix operators all bind
+/// at [`OperatorTable::PREFIX`].
+#[derive(Debug, Clone)]
+pub struct OperatorTable {
+    infix: Vec<InfixOp>,
+    prefix: Vec<PrefixOp>,
+}
+
+impl Default for OperatorTable {
+    fn default() -> Self {
+        let infix = |token: Token, precedence: u8, node: fn(Token) -> OpNode| InfixOp {
+            node: node(token.clone()),
+            token,
+            precedence,
+            assoc: Assoc::Left,
+        };
+        let mut power = infix(Token::Caret, OperatorTable::POWER, OpNode::Binary);
+        power.assoc = Assoc::Right;
+        OperatorTable {
+            infix: vec![
+                infix(Token::OrOr, OperatorTable::OR, OpNode::Logical),
+                infix(Token::AndAnd, OperatorTable::AND, OpNode::Logical),
+                infix(Token::EqEq, OperatorTable::EQUALITY, OpNode::Binary),
+                infix(Token::NotEq, OperatorTable::EQUALITY, OpNode::Binary),
+                infix(Token::Lt, OperatorTable::COMPARISON, OpNode::Binary),
+                infix(Token::Le, OperatorTable::COMPARISON, OpNode::Binary),
+                infix(Token::Gt, OperatorTable::COMPARISON, OpNode::Binary),
+                infix(Token::Ge, OperatorTable::COMPARISON, OpNode::Binary),
+                infix(Token::Plus, OperatorTable::SUM, OpNode::Binary),
+                infix(Token::Minus, OperatorTable::SUM, OpNode::Binary),
+                infix(Token::Star, OperatorTable::PRODUCT, OpNode::Binary),
+                infix(Token::Slash, OperatorTable::PRODUCT, OpNode::Binary),
+                power,
+            ],
+            prefix: [Token::Minus, Token::Plus, Token::Bang]
+                .into_iter()
+                .map(|token| PrefixOp { node: OpNode::Unary(token.clone()), token })
+                .collect(),
+        }
+    }
+}
+
+impl OperatorTable {
+    /// Precedences of the standard operators, loosest first; a custom
+    /// operator may take any level, including those in between.
+    pub const OR: u8 = 10;
+    pub const AND: u8 = 20;
+    pub const EQUALITY: u8 = 30;
+    pub const COMPARISON: u8 = 40;
+    pub const SUM: u8 = 50;
+    pub const PRODUCT: u8 = 60;
+    pub const PREFIX: u8 = 70;
+    pub const POWER: u8 = 80;
+
+    /// Parse `a symbol b` as `function(a, b)`, replacing any infix operator
+    /// already using `symbol`; a standard symbol keeps its prefix meaning,
+    /// so after `.infix("-", ..)` `-a` still negates. The function must be
+    /// a builtin or defined before the operator is used, taking two
+    /// arguments.
+    pub fn infix(mut self, symbol: &str, precedence: u8, assoc: Assoc, function: &str) -> Self {
+        let token = operator_token(symbol);
+        self.infix.retain(|op| op.token != token);
+        self.infix.push(InfixOp { token, precedence, assoc, node: OpNode::Call(function.to_string()) });
+        self
+    }
+
+    /// Parse `symbol a` as `function(a)`, replacing any prefix operator
+    /// already using `symbol`.
+    pub fn prefix(mut self, symbol: &str, function: &str) -> Self {
+        let token = operator_token(symbol);
+        self.prefix.retain(|op| op.token != token);
+        self.prefix.push(PrefixOp { token, node: OpNode::Call(function.to_string()) });
+        self
+    }
+
+    /// The custom punctuation symbols, for [`tokenize_with`].
+    pub fn symbols(&self) -> Vec<&str> {
+        let infix = self.infix.iter().map(|op| &op.token);
+        let tokens = infix.chain(self.prefix.iter().map(|op| &op.token));
+        let mut symbols: Vec<&str> = tokens
+            .filter_map(|t| match t {
+                Token::Operator(symbol) => Some(symbol.as_str()),
+                _ => None,
+            })
+            .collect();
+        symbols.sort_unstable();
+        symbols.dedup();
+        symbols
     }
 
-    pub fn alloc(&mut self, kind: ExprKind) -> usize {
-        let idx = self.nodes.len();
-        self.nodes.push(Expr { kind });
-        idx
+    fn infix_for(&self, token: &Token) -> Option<&InfixOp> {
+        self.infix.iter().find(|op| op.token == *token)
     }
 
-    pub fn get(&self, idx: usize) -> Option<&Expr> {
-        self.nodes.get(idx)
+    fn prefix_for(&self, token: &Token) -> Option<&PrefixOp> {
+        self.prefix.iter().find(|op| op.token == *token)
+    }
+}
+
+// The token `symbol` is matched as: the standard token it lexes to, such
+// as `Minus` for `-`, so that the operator it replaces is really replaced;
+// otherwise an identifier or a custom operator.
+fn operator_token(symbol: &str) -> Token {
+    if symbol.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        return Token::Identifier(symbol.to_string());
+    }
+    match tokenize(symbol).as_deref() {
+        Ok([only]) => only.token.clone(),
+        _ => Token::Operator(symbol.to_string()),
+    }
+}
+
+/// Knobs for [`parse_with`]. The default is strict.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Stop after the first complete statement and ignore whatever follows,
+    /// as `parse` did before trailing input was rejected and programs
+    /// could hold several statements.
+    pub lenient: bool,
+    /// Parse into an [`Arena::hash_consed`] arena, so repeated
+    /// subexpressions share one node.
+    pub hash_cons: bool,
+    /// Operators and their precedence; the default is the standard syntax.
+    pub operators: OperatorTable,
+}
+
+impl ParseOptions {
+    pub fn lenient() -> Self {
+        ParseOptions { lenient: true, ..ParseOptions::default() }
     }
 }
 
 struct Parser<'a> {
-    tokens: &'a [Token],
+    tokens: &'a [SpannedToken],
     pos: usize,
+    /// Function whose body is being parsed, to reject recursion.
+    defining: Option<String>,
+    operators: &'a OperatorTable,
+    /// Set by [`parse_str_recovering`]: errors are collected in `errors`
+    /// and parsing resumes after them.
+    recover: bool,
+    errors: Vec<ParseError>,
 }
 
 impl<'a> Parser<'a> {
-    fn new(tokens: &'a [Token]) -> Self {
-        Parser { tokens, pos: 0 }
+    fn new(tokens: &'a [SpannedToken], operators: &'a OperatorTable) -> Self {
+        Parser { tokens, pos: 0, defining: None, operators, recover: false, errors: Vec::new() }
     }
 
     fn peek(&self) -> Option<&Token> {
-        if self.pos < self.tokens.len() {
-            Some(&self.tokens[self.pos])
-        } else {
-            None
-        }
+        self.tokens.get(self.pos).map(|t| &t.token)
     }
 
-    fn next(&mut self) -> Option<Token> {
+    fn next(&mut self) -> Option<SpannedToken> {
         if self.pos < self.tokens.len() {
             let token = self.tokens[self.pos].clone();
             self.pos += 1;
@@ -81,101 +764,638 @@ impl<'a> Parser<'a> {
         self.pos = pos;
     }
 
-    fn eat(&mut self, expected: Token) -> bool {
-        if let Some(token) = self.peek() {
-            if *token == expected {
-                self.next();
-                true
-            } else {
-                false
+    fn at_separator(&self) -> bool {
+        matches!(self.peek(), Some(Token::Semicolon | Token::Newline))
+    }
+
+    fn skip_separators(&mut self) {
+        while self.at_separator() {
+            self.pos += 1;
+        }
+    }
+
+    // Whether token `i` starts a line after the one before it ends. Inside
+    // an unclosed bracket no `Newline` is lexed, so when recovering this is
+    // also taken as the end of a statement.
+    fn line_break_before(&self, i: usize) -> bool {
+        i > 0 && i < self.tokens.len() && self.tokens[i].span.start.line > self.tokens[i - 1].span.end.line
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.at_separator() || (self.recover && self.line_break_before(self.pos))
+    }
+
+    // Skip what is left of a failed statement that began at token `start`.
+    fn synchronize(&mut self, start: usize) {
+        self.pos = self.pos.max(start + 1);
+        while self.pos < self.tokens.len() && !self.at_boundary() {
+            self.pos += 1;
+        }
+    }
+
+    // The bracket closing the one at token `open`, if it closes before the
+    // statement ends.
+    fn matching_close(&self, open: usize) -> Option<usize> {
+        let mut depth = 0;
+        for (i, t) in self.tokens.iter().enumerate().skip(open) {
+            match t.token {
+                Token::LParen | Token::LBracket => depth += 1,
+                Token::RParen | Token::RBracket => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(i);
+                    }
+                }
+                Token::Semicolon | Token::Newline => return None,
+                _ => {}
             }
-        } else {
-            false
+        }
+        None
+    }
+
+    // When recovering, note `error` from inside the bracketed group opened
+    // at token `open` and resume after its closing bracket, with a NaN
+    // placeholder spanning from `start` standing in for the group.
+    // Otherwise, or if the group is never closed, `error` is returned.
+    fn recover_group(
+        &mut self,
+        arena: &mut Arena,
+        open: usize,
+        start: Span,
+        error: ParseError,
+    ) -> Result<NodeId, ParseError> {
+        let close = match self.matching_close(open) {
+            Some(close) if self.recover => close,
+            _ => return Err(error),
+        };
+        self.errors.push(error);
+        self.pos = close + 1;
+        Ok(arena.alloc_at(ExprKind::Number(f64::NAN), start.to(self.tokens[close].span)))
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, expected: Token) -> Option<Span> {
+        match self.tokens.get(self.pos) {
+            Some(t) if t.token == expected => {
+                self.pos += 1;
+                Some(t.span)
+            }
+            _ => None,
+        }
+    }
+
+    /// Empty span just past the last token, used for end-of-input errors.
+    fn eof_span(&self) -> Span {
+        let end = match self.tokens.last() {
+            Some(t) => t.span.end,
+            None => Position { offset: 0, line: 1, column: 1 },
+        };
+        Span::new(end, end)
+    }
+
+    /// Error for the current token (or end of input) given what was expected.
+    fn unexpected(&self, expected: &[&str]) -> ParseError {
+        let expected = expected.iter().map(|s| s.to_string()).collect();
+        match self.tokens.get(self.pos) {
+            Some(t) => ParseError::UnexpectedToken { found: t.token.clone(), expected, span: t.span },
+            None => ParseError::UnexpectedEof { expected, span: self.eof_span() },
         }
     }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Option<(Arena, usize)> {
-    let mut arena = Arena::new();
-    let mut parser = Parser::new(&tokens);
-    let root = parse_assignment(&mut parser, &mut arena)?;
-    Some((arena, root))
+pub fn parse(tokens: Vec<SpannedToken>) -> Result<(Arena, NodeId), ParseError> {
+    parse_with(tokens, &ParseOptions::default())
 }
 
-fn parse_assignment(parser: &mut Parser, arena: &mut Arena) -> Option<usize> {
-    let saved_pos = parser.save_pos();
+/// Parse a program: statements separated by `;` or newlines. A single
+/// statement is returned as the root itself, several are wrapped in a
+/// `Program`. Unless `options.lenient` is set, a statement followed by
+/// anything but a separator is a `TrailingInput` error (or
+/// `UnbalancedParen` for a stray `)`), and an expression statement before
+/// the last is an `UnusedValue` error.
+pub fn parse_with(tokens: Vec<SpannedToken>, options: &ParseOptions) -> Result<(Arena, NodeId), ParseError> {
+    let mut arena = if options.hash_cons { Arena::hash_consed() } else { Arena::new() };
+    let mut parser = Parser::new(&tokens, &options.operators);
+    parser.skip_separators();
+    if options.lenient {
+        let root = parse_statement(&mut parser, &mut arena)?;
+        register_function(&mut arena, root);
+        return Ok((arena, root));
+    }
+    let root = parse_program(&mut parser, &mut arena)?;
+    Ok((arena, root))
+}
+
+fn parse_program(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    let mut statements = Vec::new();
+    while parser.peek().is_some() {
+        let start = parser.pos;
+        let result = parse_statement(parser, arena).and_then(|stmt| {
+            let ended = end_statement(parser);
+            // When recovering, other statements with trailing input are
+            // kept, but a definition is only kept whole.
+            if ended.is_ok() || !matches!(arena[stmt].kind, ExprKind::FnDef { .. }) {
+                register_function(arena, stmt);
+                statements.push(stmt);
+            }
+            ended
+        });
+        if let Err(e) = result {
+            if !parser.recover {
+                return Err(e);
+            }
+            parser.errors.push(e);
+            parser.synchronize(start);
+        }
+        parser.skip_separators();
+    }
 
-    if let Some(first) = parser.peek() {
-        if let Token::Identifier(name) = first.clone() {
-            parser.next(); // Consume identifier
+    if !parser.recover {
+        let init = &statements[..statements.len().saturating_sub(1)];
+        if let Some(&stmt) = init.iter().find(|&&s| discards_value(&arena[s].kind)) {
+            return Err(ParseError::UnusedValue { span: arena[stmt].span });
+        }
+    }
+    match statements.len() {
+        0 => Err(parser.unexpected(EXPECT_OPERAND)),
+        1 => Ok(statements[0]),
+        _ => {
+            let span = arena[statements[0]].span.to(arena[*statements.last().unwrap()].span);
+            Ok(arena.alloc_at(ExprKind::Program { statements }, span))
+        }
+    }
+}
 
-            if let Some(second) = parser.peek() {
-                if let Token::Unknown('=') = second.clone() {
-                    parser.next(); // Consume '='
+// Whether a statement's value is lost unless it is the last: everything
+// but bindings, definitions and `print`.
+fn discards_value(kind: &ExprKind) -> bool {
+    !matches!(kind, ExprKind::Let { .. } | ExprKind::Assign { .. } | ExprKind::FnDef { .. } | ExprKind::Print { .. })
+}
 
-                    if let Some(value_idx) = parse_expr(parser, arena) {
-                        let idx = arena.alloc(ExprKind::Assign { name, value: value_idx });
-                        return Some(idx);
-                    }
-                }
+// Make the function `stmt` defines, if any, callable from later
+// statements. Done once the statement is accepted, so a definition that
+// recovery drops is not left registered.
+fn register_function(arena: &mut Arena, stmt: NodeId) {
+    if let ExprKind::FnDef { name, .. } = &arena[stmt].kind {
+        let name = name.clone();
+        arena.define_function(&name, stmt);
+    }
+}
+
+// After a statement: a separator, the end of input, or when recovering a
+// line break.
+fn end_statement(parser: &mut Parser) -> Result<(), ParseError> {
+    if parser.peek().is_none() || parser.at_boundary() {
+        return Ok(());
+    }
+    let first = parser.next().unwrap();
+    if first.token == Token::RParen {
+        return Err(ParseError::UnbalancedParen { span: first.span });
+    }
+    // Report the rest of the statement, up to the next separator.
+    let mut span = first.span;
+    while parser.peek().is_some() && !parser.at_boundary() {
+        span = span.to(parser.next().unwrap().span);
+    }
+    Err(ParseError::TrailingInput { found: first.token, span })
+}
+
+/// Tokenize and parse `input` in one step.
+pub fn parse_str(input: &str) -> Result<(Arena, NodeId), ParseError> {
+    parse(tokenize(input)?)
+}
+
+/// [`parse_str`] with `options`, lexing the custom symbols of
+/// `options.operators`.
+pub fn parse_str_with(input: &str, options: &ParseOptions) -> Result<(Arena, NodeId), ParseError> {
+    parse_with(tokenize_with(input, &options.operators.symbols())?, options)
+}
+
+/// Parse `input` like [`parse_str_with`], but report every problem instead
+/// of stopping at the first, for editors and linters. A statement that
+/// fails is dropped and parsing resumes at the next `;` or line (trailing
+/// input is skipped instead, except after a function definition); an error
+/// inside parentheses or brackets that are closed only replaces that group
+/// with a NaN placeholder, so `a = f(1 +) * 2` keeps `a`. Input that
+/// [`parse_str_with`] accepts gives the same tree. `options.lenient` is
+/// ignored.
+///
+/// Besides errors, an expression statement before the last one is warned
+/// about, since its value is discarded.
+pub fn parse_str_recovering(input: &str, options: &ParseOptions) -> Recovered {
+    let (tokens, lex_errors) = tokenize_recovering(input, &options.operators.symbols());
+    let mut arena = if options.hash_cons { Arena::hash_consed() } else { Arena::new() };
+    let mut parser = Parser::new(&tokens, &options.operators);
+    parser.recover = true;
+    parser.skip_separators();
+    let root = match parse_program(&mut parser, &mut arena) {
+        Ok(root) => Some(root),
+        // Nothing parsed; only worth saying if nothing else was reported.
+        Err(e) => {
+            if parser.errors.is_empty() && lex_errors.is_empty() {
+                parser.errors.push(e);
+            }
+            None
+        }
+    };
+
+    // Skipped text usually leaves the rest of its line unparsable too, so
+    // parse errors on a line with a lexical error are left out.
+    let lex_lines: Vec<usize> = lex_errors.iter().map(|e| e.span().start.line).collect();
+    let parse_errors = parser.errors.into_iter().filter(|e| !lex_lines.contains(&e.span().start.line));
+    let mut diagnostics: Vec<Diagnostic> =
+        lex_errors.into_iter().map(ParseError::Lex).chain(parse_errors).map(Diagnostic::from).collect();
+    if let Some(ExprKind::Program { statements }) = root.map(|r| &arena[r].kind) {
+        for &stmt in &statements[..statements.len() - 1] {
+            if discards_value(&arena[stmt].kind) {
+                let unused = Diagnostic::from(ParseError::UnusedValue { span: arena[stmt].span });
+                diagnostics.push(Diagnostic { severity: Severity::Warning, ..unused });
             }
+        }
+    }
+    diagnostics.sort_by_key(|d| d.span.start.offset);
+    Recovered { arena, root, diagnostics }
+}
 
-            // Not an assignment—restore and parse expression
-            parser.restore_pos(saved_pos);
+fn parse_statement(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    if let Some(print_span) = parser.eat(Token::Print) {
+        return parse_print(parser, arena, print_span);
+    }
+    let Some(let_span) = parser.eat(Token::Let) else {
+        return parse_assignment(parser, arena);
+    };
+    let name = match parser.peek() {
+        Some(Token::Identifier(name)) => name.clone(),
+        _ => return Err(parser.unexpected(&["identifier"])),
+    };
+    parser.next();
+    if parser.eat(Token::Assign).is_none() {
+        return Err(parser.unexpected(&["'='"]));
+    }
+    let value = parse_value(parser, arena)?;
+    let span = let_span.to(arena[value].span);
+    Ok(arena.alloc_at(ExprKind::Let { name, value }, span))
+}
+
+fn parse_assignment(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    let saved_pos = parser.save_pos();
+
+    if let Some(first) = parser.next() {
+        if let Token::Identifier(name) = first.token {
+            if parser.eat(Token::Assign).is_some() {
+                let value_idx = parse_value(parser, arena)?;
+                let span = first.span.to(arena[value_idx].span);
+                let idx = arena.alloc_at(ExprKind::Assign { name, value: value_idx }, span);
+                return Ok(idx);
+            }
+            if let Some(params) = parse_params(parser) {
+                return parse_fn_def(parser, arena, name, params, first.span);
+            }
         }
     }
 
+    // Not an assignment—restore and parse expression
+    parser.restore_pos(saved_pos);
     parse_expr(parser, arena)
 }
 
-fn parse_expr(parser: &mut Parser, arena: &mut Arena) -> Option<usize> {
-    parse_term(parser, arena)
+// `print` has been consumed; arguments are optional.
+fn parse_print(parser: &mut Parser, arena: &mut Arena, print_span: Span) -> Result<NodeId, ParseError> {
+    let mut args = Vec::new();
+    let mut span = print_span;
+    if parser.peek().is_some() && !parser.at_separator() {
+        loop {
+            let arg = parse_value(parser, arena)?;
+            span = span.to(arena[arg].span);
+            args.push(arg);
+            if parser.eat(Token::Comma).is_none() {
+                break;
+            }
+        }
+    }
+    Ok(arena.alloc_at(ExprKind::Print { args }, span))
 }
 
-fn parse_term(parser: &mut Parser, arena: &mut Arena) -> Option<usize> {
-    let mut left_idx = parse_factor(parser, arena)?;
+// A string literal or an expression: what `let`, `=` and `print` accept.
+fn parse_value(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    if let Some(SpannedToken { token: Token::Str(text), span }) = parser.tokens.get(parser.pos) {
+        let (text, span) = (text.clone(), *span);
+        parser.pos += 1;
+        return Ok(arena.alloc_at(ExprKind::Str(text), span));
+    }
+    parse_expr(parser, arena)
+}
 
-    while let Some(token) = parser.peek().cloned() {
-        match token {
-            Token::Plus | Token::Minus => {
-                let op = parser.next().unwrap();
-                let right_idx = parse_factor(parser, arena)?;
-                left_idx = arena.alloc(ExprKind::Binary { left: left_idx, op, right: right_idx });
+// `(a, b, ...) =` after a name marks a function definition. Returns `None`
+// (leaving the position for the caller to restore) on anything else, which
+// makes the statement an ordinary expression such as a call.
+fn parse_params(parser: &mut Parser) -> Option<Vec<(String, Span)>> {
+    parser.eat(Token::LParen)?;
+    let mut params = Vec::new();
+    if parser.eat(Token::RParen).is_none() {
+        loop {
+            let next = parser.next()?;
+            match next.token {
+                Token::Identifier(p) => params.push((p, next.span)),
+                _ => return None,
+            }
+            match parser.next()?.token {
+                Token::Comma => continue,
+                Token::RParen => break,
+                _ => return None,
             }
-            _ => break,
         }
     }
-    Some(left_idx)
+    parser.eat(Token::Assign)?;
+    Some(params)
 }
 
-fn parse_factor(parser: &mut Parser, arena: &mut Arena) -> Option<usize> {
-    let mut left_idx = parse_primary(parser, arena)?;
+fn parse_fn_def(
+    parser: &mut Parser,
+    arena: &mut Arena,
+    name: String,
+    params: Vec<(String, Span)>,
+    name_span: Span,
+) -> Result<NodeId, ParseError> {
+    if Builtin::from_name(&name).is_some() || name == "piecewise" || arena.function(&name).is_some() {
+        return Err(ParseError::DuplicateFunction { name, span: name_span });
+    }
+    for (i, (param, span)) in params.iter().enumerate() {
+        if params[..i].iter().any(|(earlier, _)| earlier == param) {
+            return Err(ParseError::DuplicateParameter { name: param.clone(), span: *span });
+        }
+    }
+    let params = params.into_iter().map(|(param, _)| param).collect();
+    parser.defining = Some(name.clone());
+    let body = parse_expr(parser, arena);
+    parser.defining = None;
+    let body = body?;
 
-    while let Some(token) = parser.peek().cloned() {
-        match token {
-            Token::Star | Token::Slash => {
-                let op = parser.next().unwrap();
-                let right_idx = parse_primary(parser, arena)?;
-                left_idx = arena.alloc(ExprKind::Binary { left: left_idx, op, right: right_idx });
-            }
-            _ => break,
+    let span = name_span.to(arena[body].span);
+    Ok(arena.alloc_at(ExprKind::FnDef { name, params, body }, span))
+}
+
+fn parse_expr(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    parse_ternary(parser, arena)
+}
+
+fn alloc_if(arena: &mut Arena, cond: NodeId, then_branch: NodeId, else_branch: NodeId, span: Span) -> NodeId {
+    arena.alloc_at(ExprKind::If { cond, then_branch, else_branch }, span)
+}
+
+// `c ? a : b`, right-associative so `c1 ? a : c2 ? b : d` chains.
+fn parse_ternary(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    let cond = parse_binary(parser, arena, 0)?;
+    if parser.eat(Token::Question).is_none() {
+        return Ok(cond);
+    }
+    let then_branch = parse_expr(parser, arena)?;
+    if parser.eat(Token::Colon).is_none() {
+        return Err(parser.unexpected(&["':'"]));
+    }
+    let else_branch = parse_expr(parser, arena)?;
+    let span = arena[cond].span.to(arena[else_branch].span);
+    Ok(alloc_if(arena, cond, then_branch, else_branch, span))
+}
+
+// `if` has been consumed; the else branch extends as far as possible.
+fn parse_if(parser: &mut Parser, arena: &mut Arena, if_span: Span) -> Result<NodeId, ParseError> {
+    let cond = parse_expr(parser, arena)?;
+    parser.skip_newlines();
+    if parser.eat(Token::Then).is_none() {
+        return Err(parser.unexpected(&["'then'"]));
+    }
+    let then_branch = parse_expr(parser, arena)?;
+    parser.skip_newlines();
+    if parser.eat(Token::Else).is_none() {
+        return Err(parser.unexpected(&["'else'"]));
+    }
+    let else_branch = parse_expr(parser, arena)?;
+    let span = if_span.to(arena[else_branch].span);
+    Ok(alloc_if(arena, cond, then_branch, else_branch, span))
+}
+
+fn alloc_operator(arena: &mut Arena, node: &OpNode, args: Vec<NodeId>, span: Span) -> NodeId {
+    let kind = match (node, args.as_slice()) {
+        (OpNode::Unary(op), &[operand]) => ExprKind::Unary { op: op.clone(), operand },
+        (OpNode::Binary(op), &[left, right]) => ExprKind::Binary { left, op: op.clone(), right },
+        (OpNode::Logical(op), &[left, right]) => ExprKind::Logical { left, op: op.clone(), right },
+        (OpNode::Call(name), _) => ExprKind::Call { name: name.clone(), args },
+        _ => unreachable!("operator applied to the wrong number of operands"),
+    };
+    arena.alloc_at(kind, span)
+}
+
+// Operators of precedence at least `min`, by precedence climbing: each
+// operator takes as its right operand everything that binds more tightly
+// (or as tightly, if right-associative).
+// Precedences are widened to `u16` so one past the highest still fits.
+fn parse_binary(parser: &mut Parser, arena: &mut Arena, min: u16) -> Result<NodeId, ParseError> {
+    let operators = parser.operators;
+    let mut left = parse_prefix(parser, arena)?;
+    while let Some(op) = parser.peek().and_then(|t| operators.infix_for(t)) {
+        let precedence = u16::from(op.precedence);
+        if precedence < min {
+            break;
         }
+        let op_span = parser.next().unwrap().span;
+        let next = match op.assoc {
+            Assoc::Left => precedence + 1,
+            Assoc::Right => precedence,
+        };
+        let right = parse_binary(parser, arena, next)?;
+        let span = arena[left].span.to(arena[right].span);
+        if let OpNode::Call(name) = &op.node {
+            check_call(parser, arena, name, 2, op_span, span)?;
+        }
+        left = alloc_operator(arena, &op.node, vec![left, right], span);
+    }
+    Ok(left)
+}
+
+// Prefix operators bind looser than `^`: `-x ^ 2` is `-(x ^ 2)`, and a
+// power's exponent may carry a sign, so `2 ^ -1` parses.
+fn parse_prefix(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    // An operand may start on the next line, so `a +` continues below.
+    parser.skip_newlines();
+    let operators = parser.operators;
+    let Some(op) = parser.peek().and_then(|t| operators.prefix_for(t)) else {
+        return parse_postfix(parser, arena);
+    };
+    let op_span = parser.next().unwrap().span;
+    let operand = parse_binary(parser, arena, u16::from(OperatorTable::PREFIX) + 1)?;
+    let span = op_span.to(arena[operand].span);
+    if let OpNode::Call(name) = &op.node {
+        check_call(parser, arena, name, 1, op_span, span)?;
+    }
+    Ok(alloc_operator(arena, &op.node, vec![operand], span))
+}
+
+// Indexing binds tightest: `-v[0] ^ 2` is `-((v[0]) ^ 2)`.
+fn parse_postfix(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    let mut target = parse_primary(parser, arena)?;
+    while let Some(open) = parser.eat(Token::LBracket) {
+        let at = parser.pos - 1;
+        let start = arena[target].span;
+        target = parse_index(parser, arena, target, open).or_else(|e| parser.recover_group(arena, at, start, e))?;
     }
-    Some(left_idx)
+    Ok(target)
 }
 
-fn parse_primary(parser: &mut Parser, arena: &mut Arena) -> Option<usize> {
-    match parser.next()? {
-        Token::Number(n) => Some(arena.alloc(ExprKind::Number(n))),
-        Token::Identifier(name) => Some(arena.alloc(ExprKind::Identifier(name))),
+// `target[` has been consumed.
+fn parse_index(parser: &mut Parser, arena: &mut Arena, target: NodeId, open: Span) -> Result<NodeId, ParseError> {
+    let index = parse_expr(parser, arena)?;
+    let Some(close) = parser.eat(Token::RBracket) else {
+        return Err(match parser.peek() {
+            None => ParseError::UnbalancedParen { span: open },
+            Some(_) => parser.unexpected(&["']'"]),
+        });
+    };
+    let span = arena[target].span.to(close);
+    Ok(arena.alloc_at(ExprKind::Index { target, index }, span))
+}
+
+const EXPECT_OPERAND: &[&str] = &["number", "identifier", "'('", "'-'", "'+'", "'!'", "'if'"];
+
+fn parse_primary(parser: &mut Parser, arena: &mut Arena) -> Result<NodeId, ParseError> {
+    let Some(SpannedToken { token, span }) = parser.next() else {
+        return Err(parser.unexpected(EXPECT_OPERAND));
+    };
+    match token {
+        Token::Number(n) => Ok(arena.alloc_at(ExprKind::Number(n), span)),
+        Token::Identifier(name) if parser.peek() == Some(&Token::LParen) => {
+            let open = parser.pos;
+            parse_call(parser, arena, name, span).or_else(|e| parser.recover_group(arena, open, span, e))
+        }
+        Token::Identifier(name) => Ok(arena.alloc_at(ExprKind::Identifier(name), span)),
+        Token::If => parse_if(parser, arena, span),
+        Token::LBracket => {
+            let open = parser.pos - 1;
+            parse_vector(parser, arena, span).or_else(|e| parser.recover_group(arena, open, span, e))
+        }
         Token::LParen => {
-            let expr_idx = parse_expr(parser, arena);
-            if parser.eat(Token::RParen) {
-                expr_idx
-            } else {
-                None
+            let open = parser.pos - 1;
+            parse_group(parser, arena, span).or_else(|e| parser.recover_group(arena, open, span, e))
+        }
+        found => Err(ParseError::UnexpectedToken {
+            found,
+            expected: EXPECT_OPERAND.iter().map(|s| s.to_string()).collect(),
+            span,
+        }),
+    }
+}
+
+// `(` has been consumed.
+fn parse_group(parser: &mut Parser, arena: &mut Arena, open: Span) -> Result<NodeId, ParseError> {
+    let expr_idx = parse_expr(parser, arena)?;
+    let Some(close) = parser.eat(Token::RParen) else {
+        return Err(match parser.peek() {
+            None => ParseError::UnbalancedParen { span: open },
+            Some(_) => parser.unexpected(&["')'"]),
+        });
+    };
+    // Widen the inner node to include its parentheses.
+    arena.set_span(expr_idx, open.to(close));
+    Ok(expr_idx)
+}
+
+// `[` has been consumed; elements up to the matching `]`.
+fn parse_vector(parser: &mut Parser, arena: &mut Arena, open: Span) -> Result<NodeId, ParseError> {
+    let mut elements = Vec::new();
+    let close = loop {
+        if elements.is_empty() {
+            if let Some(close) = parser.eat(Token::RBracket) {
+                break close;
+            }
+        }
+        elements.push(parse_expr(parser, arena)?);
+        if parser.eat(Token::Comma).is_some() {
+            continue;
+        }
+        match parser.eat(Token::RBracket) {
+            Some(close) => break close,
+            None if parser.peek().is_none() => return Err(ParseError::UnbalancedParen { span: open }),
+            None => return Err(parser.unexpected(&["','", "']'"])),
+        }
+    };
+    Ok(arena.alloc_at(ExprKind::Vector { elements }, open.to(close)))
+}
+
+// `name` and its span have been consumed; the next token is `(`.
+fn parse_call(parser: &mut Parser, arena: &mut Arena, name: String, name_span: Span) -> Result<NodeId, ParseError> {
+    let open = parser.next().unwrap().span;
+    let mut args = Vec::new();
+    let close = loop {
+        if args.is_empty() {
+            if let Some(close) = parser.eat(Token::RParen) {
+                break close;
             }
         }
-        _ => None,
+        args.push(parse_expr(parser, arena)?);
+        if parser.eat(Token::Comma).is_some() {
+            continue;
+        }
+        match parser.eat(Token::RParen) {
+            Some(close) => break close,
+            None if parser.peek().is_none() => return Err(ParseError::UnbalancedParen { span: open }),
+            None => return Err(parser.unexpected(&["','", "')'"])),
+        }
+    };
+
+    let span = name_span.to(close);
+    if name == "piecewise" {
+        return piecewise(arena, name, args, span);
+    }
+    check_call(parser, arena, &name, args.len(), name_span, span)?;
+    Ok(arena.alloc_at(ExprKind::Call { name, args }, span))
+}
+
+// A call to `name` with `found` arguments must reach a builtin or an
+// earlier function, not the one being defined, with an accepted count.
+fn check_call(
+    parser: &Parser,
+    arena: &Arena,
+    name: &str,
+    found: usize,
+    name_span: Span,
+    span: Span,
+) -> Result<(), ParseError> {
+    let name_owned = || name.to_string();
+    if parser.defining.as_deref() == Some(name) {
+        return Err(ParseError::RecursiveFunction { name: name_owned(), span: name_span });
+    }
+    let arity = match (Builtin::from_name(name), arena.function(name)) {
+        (Some(builtin), _) => builtin.arity(),
+        (None, Some(def)) => match &arena[def].kind {
+            ExprKind::FnDef { params, .. } => Arity::Exact(params.len()),
+            _ => unreachable!("function table points at a non-definition"),
+        },
+        (None, None) => return Err(ParseError::UnknownFunction { name: name_owned(), span: name_span }),
+    };
+    if !arity.accepts(found) {
+        return Err(ParseError::ArityMismatch { name: name_owned(), expected: arity, found, span });
+    }
+    Ok(())
+}
+
+// `piecewise(c1, v1, c2, v2, ..., default)` becomes
+// `if c1 then v1 else if c2 then v2 else ... default`.
+fn piecewise(arena: &mut Arena, name: String, args: Vec<NodeId>, span: Span) -> Result<NodeId, ParseError> {
+    let arity = Arity::OddAtLeast(3);
+    if !arity.accepts(args.len()) {
+        return Err(ParseError::ArityMismatch { name, expected: arity, found: args.len(), span });
+    }
+    let (pairs, default) = args.split_at(args.len() - 1);
+    let mut acc = default[0];
+    for pair in pairs.chunks(2).rev() {
+        let branch_span = arena[pair[0]].span.to(arena[acc].span);
+        acc = alloc_if(arena, pair[0], pair[1], acc, branch_span);
     }
+    arena.set_span(acc, span);
+    Ok(acc)
 }
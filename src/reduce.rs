@@ -0,0 +1,125 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Reductions of a vector to one number, behind `sum`, `mean`, `std`,
+//! `dot`, `norm`, `min`, `max` and `count_if` in [`crate::value`].
+//!
+//! Inner loops run four lanes at a time. Sums are Neumaier-compensated,
+//! per lane and again when the lanes are combined, so `sum` of ten `0.1`s
+//! is exactly `1` and cancellation (`[1e16, 1, -1e16]`) keeps the `1`.
+//! `min` and `max` skip NaN elements like the builtins do.
+
+use crate::interpreter::truthy_mask;
+use wide::{f64x4, CmpGe};
+
+// The elements of `chunk` (at most four) as lanes, with `fill` in the rest.
+fn load(chunk: &[f64], fill: f64) -> f64x4 {
+    let mut lanes = [fill; 4];
+    lanes[..chunk.len()].copy_from_slice(chunk);
+    f64x4::from(lanes)
+}
+
+#[inline]
+fn neumaier(sum: f64, term: f64, comp: &mut f64) -> f64 {
+    let next = sum + term;
+    *comp += if sum.abs() >= term.abs() { (sum - next) + term } else { (term - next) + sum };
+    next
+}
+
+// Compensated sum of every lane of `terms`.
+fn compensated(terms: impl Iterator<Item = f64x4>) -> f64 {
+    let (mut sum, mut comp) = (f64x4::ZERO, f64x4::ZERO);
+    for term in terms {
+        let next = sum + term;
+        let bigger = sum.abs().cmp_ge(term.abs());
+        comp += bigger.blend((sum - next) + term, (term - next) + sum);
+        sum = next;
+    }
+    // Corrections mean nothing once the sum is infinite or NaN.
+    let naive = sum.reduce_add();
+    if !naive.is_finite() {
+        return naive;
+    }
+    let (mut total, mut c) = (0.0, 0.0);
+    for v in sum.to_array().into_iter().chain(comp.to_array()) {
+        total = neumaier(total, v, &mut c);
+    }
+    total + c
+}
+
+pub fn sum(xs: &[f64]) -> f64 {
+    compensated(xs.chunks(4).map(|c| load(c, 0.0)))
+}
+
+/// NaN for an empty vector.
+pub fn mean(xs: &[f64]) -> f64 {
+    if xs.is_empty() {
+        return f64::NAN;
+    }
+    sum(xs) / xs.len() as f64
+}
+
+/// Population standard deviation (dividing by `n`), from the deviations
+/// about the mean; NaN for an empty vector.
+pub fn std(xs: &[f64]) -> f64 {
+    let m = mean(xs);
+    if !m.is_finite() {
+        return f64::NAN;
+    }
+    let centre = f64x4::splat(m);
+    let squares = compensated(xs.chunks(4).map(|c| {
+        let d = load(c, m) - centre;
+        d * d
+    }));
+    (squares / xs.len() as f64).sqrt()
+}
+
+/// NaN when the lengths differ.
+pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+    if a.len() != b.len() {
+        return f64::NAN;
+    }
+    compensated(a.chunks(4).zip(b.chunks(4)).map(|(x, y)| load(x, 0.0) * load(y, 0.0)))
+}
+
+/// Euclidean length, scaled by the largest element so squares neither
+/// overflow nor underflow; infinite if any element is, as with `hypot`.
+pub fn norm(xs: &[f64]) -> f64 {
+    let largest = xs.chunks(4).fold(f64x4::ZERO, |m, c| m.max(load(c, 0.0).abs()));
+    let scale = largest.to_array().into_iter().fold(0.0, f64::max);
+    if scale.is_infinite() {
+        return scale;
+    }
+    if scale == 0.0 {
+        return if xs.iter().any(|x| x.is_nan()) { f64::NAN } else { 0.0 };
+    }
+    let lanes = f64x4::splat(scale);
+    let squares = compensated(xs.chunks(4).map(|c| {
+        let r = load(c, 0.0) / lanes;
+        r * r
+    }));
+    scale * squares.sqrt()
+}
+
+/// Smallest element other than NaN; NaN if there is none.
+pub fn min(xs: &[f64]) -> f64 {
+    let lanes = xs.chunks(4).fold(f64x4::splat(f64::NAN), |m, c| m.min(load(c, f64::NAN)));
+    lanes.to_array().into_iter().fold(f64::NAN, f64::min)
+}
+
+/// Largest element other than NaN; NaN if there is none.
+pub fn max(xs: &[f64]) -> f64 {
+    let lanes = xs.chunks(4).fold(f64x4::splat(f64::NAN), |m, c| m.max(load(c, f64::NAN)));
+    lanes.to_array().into_iter().fold(f64::NAN, f64::max)
+}
+
+/// Number of true elements (neither 0 nor NaN), so `count_if(v > 3)`
+/// counts the elements above 3.
+pub fn count_if(xs: &[f64]) -> f64 {
+    let counts = xs
+        .chunks(4)
+        .fold(f64x4::ZERO, |n, c| n + truthy_mask(load(c, 0.0)).blend(f64x4::ONE, f64x4::ZERO));
+    counts.reduce_add()
+}
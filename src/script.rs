@@ -0,0 +1,262 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Runner for `.erock` scripts: programs whose top-level statements may
+//! bind strings and `print` results.
+//!
+//! ```text
+//! # comments start with `#` or `//`
+//! let name = 'drag'
+//! drag(v, cd) = 0.5 * 1.2 * cd * v^2
+//! print 'force for {} at {:.1} m/s:', name, 12.5, drag(12.5, 0.3)
+//! ```
+//!
+//! Only the first `print` argument acts as a template: each `{}` (or
+//! `{:.N}` for `N` decimal places) takes the next value, `{{` and `}}` are
+//! literal braces, and values left over are appended separated by spaces.
+
+use crate::lexer::Span;
+use crate::parser::{parse_str, Arena, ExprKind, NodeId, ParseError};
+use crate::value::{interpret_value, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Parse(ParseError),
+    /// A name read before anything was assigned to it.
+    UndefinedVariable { name: String, span: Span },
+    /// A variable holding a string used where a number is needed.
+    StringInExpression { name: String, span: Span },
+    /// A malformed `print` template, or one with more placeholders than values.
+    Format { message: String, span: Span },
+    Io(io::Error),
+}
+
+impl ScriptError {
+    /// Where in the script the error occurred; `None` for output errors.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ScriptError::Parse(e) => Some(e.span()),
+            ScriptError::UndefinedVariable { span, .. }
+            | ScriptError::StringInExpression { span, .. }
+            | ScriptError::Format { span, .. } => Some(*span),
+            ScriptError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Parse(e) => e.fmt(f),
+            ScriptError::UndefinedVariable { name, span } => write!(f, "undefined variable '{}' at {}", name, span),
+            ScriptError::StringInExpression { name, span } => {
+                write!(f, "'{}' holds a string and cannot be used in an expression at {}", name, span)
+            }
+            ScriptError::Format { message, span } => write!(f, "{} at {}", message, span),
+            ScriptError::Io(e) => write!(f, "output error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<ParseError> for ScriptError {
+    fn from(e: ParseError) -> Self {
+        ScriptError::Parse(e)
+    }
+}
+
+impl From<io::Error> for ScriptError {
+    fn from(e: io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+// A `print` argument after evaluation.
+enum Printed {
+    Text(String),
+    Value(Value),
+}
+
+impl Printed {
+    fn render(&self, precision: Option<usize>) -> String {
+        match (self, precision) {
+            (Printed::Text(text), _) => text.clone(),
+            (Printed::Value(v), Some(p)) => format!("{:.*}", p, v),
+            (Printed::Value(v), None) => v.to_string(),
+        }
+    }
+}
+
+struct Runner<'a> {
+    arena: &'a Arena,
+    values: HashMap<String, Value>,
+    strings: HashMap<String, String>,
+}
+
+/// Parse and run `source`, writing `print` output to `out`. Statements run
+/// top to bottom and stop at the first error; `let` and `=` both bind in
+/// the script's single scope.
+pub fn run(source: &str, out: &mut impl Write) -> Result<(), ScriptError> {
+    let (arena, root) = parse_str(source)?;
+    let statements = match arena.get(root).map(|e| &e.kind) {
+        Some(ExprKind::Program { statements }) => statements.clone(),
+        _ => vec![root],
+    };
+    let mut runner = Runner { arena: &arena, values: HashMap::new(), strings: HashMap::new() };
+    for stmt in statements {
+        runner.statement(stmt, out)?;
+    }
+    Ok(())
+}
+
+impl Runner<'_> {
+    fn statement(&mut self, idx: NodeId, out: &mut impl Write) -> Result<(), ScriptError> {
+        let Some(expr) = self.arena.get(idx) else { return Ok(()) };
+        match &expr.kind {
+            ExprKind::Let { name, value } | ExprKind::Assign { name, value } => match self.value(*value)? {
+                Printed::Text(text) => {
+                    self.values.remove(name);
+                    self.strings.insert(name.clone(), text);
+                }
+                Printed::Value(v) => {
+                    self.strings.remove(name);
+                    self.values.insert(name.clone(), v);
+                }
+            },
+            ExprKind::Print { args } => {
+                let values = args.iter().map(|&a| self.value(a)).collect::<Result<Vec<_>, _>>()?;
+                let line = self.format(&values, expr.span)?;
+                writeln!(out, "{}", line)?;
+            }
+            ExprKind::FnDef { .. } => {}
+            _ => {
+                self.evaluate(idx)?;
+            }
+        }
+        Ok(())
+    }
+
+    // A string literal, a variable holding a string, or a number or vector.
+    fn value(&mut self, idx: NodeId) -> Result<Printed, ScriptError> {
+        match self.arena.get(idx).map(|e| &e.kind) {
+            Some(ExprKind::Str(text)) => Ok(Printed::Text(text.clone())),
+            Some(ExprKind::Identifier(name)) if self.strings.contains_key(name) => {
+                Ok(Printed::Text(self.strings[name].clone()))
+            }
+            _ => self.evaluate(idx).map(Printed::Value),
+        }
+    }
+
+    fn evaluate(&mut self, idx: NodeId) -> Result<Value, ScriptError> {
+        self.check_names(idx, &[])?;
+        Ok(interpret_value(idx, self.arena, &mut self.values))
+    }
+
+    // Reject names that are unbound or hold strings before evaluating, since
+    // the interpreter would quietly read them as 0. Function bodies are
+    // checked at each call, where their free names resolve.
+    fn check_names(&self, idx: NodeId, params: &[String]) -> Result<(), ScriptError> {
+        let Some(expr) = self.arena.get(idx) else { return Ok(()) };
+        match &expr.kind {
+            ExprKind::Identifier(name) if params.contains(name) => Ok(()),
+            ExprKind::Identifier(name) if self.strings.contains_key(name) => {
+                Err(ScriptError::StringInExpression { name: name.clone(), span: expr.span })
+            }
+            ExprKind::Identifier(name) if !self.values.contains_key(name) => {
+                Err(ScriptError::UndefinedVariable { name: name.clone(), span: expr.span })
+            }
+            ExprKind::Unary { operand, .. } => self.check_names(*operand, params),
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.check_names(*left, params)?;
+                self.check_names(*right, params)
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                self.check_names(*cond, params)?;
+                self.check_names(*then_branch, params)?;
+                self.check_names(*else_branch, params)
+            }
+            ExprKind::Vector { elements } => elements.iter().try_for_each(|&e| self.check_names(e, params)),
+            ExprKind::Index { target, index } => {
+                self.check_names(*target, params)?;
+                self.check_names(*index, params)
+            }
+            ExprKind::Call { name, args } => {
+                for &a in args {
+                    self.check_names(a, params)?;
+                }
+                let def = self.arena.function(name).and_then(|f| self.arena.get(f));
+                match def.map(|d| &d.kind) {
+                    // The caller's parameters stay visible in the body.
+                    Some(ExprKind::FnDef { params: own, body, .. }) => self.check_names(*body, &[params, own.as_slice()].concat()),
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn format(&self, values: &[Printed], span: Span) -> Result<String, ScriptError> {
+        let Some((Printed::Text(template), rest)) = values.split_first() else {
+            return Ok(values.iter().map(|v| v.render(None)).collect::<Vec<_>>().join(" "));
+        };
+        let mut rest = rest.iter();
+        let mut line = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    line.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    line.push('}');
+                }
+                '{' => {
+                    let mut spec = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        spec.push(c);
+                    }
+                    if !closed {
+                        let message = "unclosed '{' in print template".to_string();
+                        return Err(ScriptError::Format { message, span });
+                    }
+                    let precision = match spec.as_str() {
+                        "" => None,
+                        s => match s.strip_prefix(":.").and_then(|p| p.parse().ok()) {
+                            Some(p) => Some(p),
+                            None => {
+                                let message = format!("invalid placeholder '{{{}}}', expected '{{}}' or '{{:.N}}'", s);
+                                return Err(ScriptError::Format { message, span });
+                            }
+                        },
+                    };
+                    let Some(value) = rest.next() else {
+                        let message = "print template has more placeholders than values".to_string();
+                        return Err(ScriptError::Format { message, span });
+                    };
+                    line.push_str(&value.render(precision));
+                }
+                c => line.push(c),
+            }
+        }
+        for value in rest {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&value.render(None));
+        }
+        Ok(line)
+    }
+}
@@ -0,0 +1,817 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Stable, versioned serialized forms of an [`Arena`]: pretty JSON for
+//! people and a compact binary encoding for transport.
+//!
+//! A document lists every node with the indices of its children, the root,
+//! and the user-function table. Loading checks everything the parser would
+//! have guaranteed (child indices in bounds, no cycles, known node kinds and
+//! operators, calls matching a builtin or defined function, no recursion,
+//! nesting no deeper than [`MAX_DEPTH`], and no more than [`MAX_TREE_LEN`]
+//! nodes once shared nodes are expanded), so a tree from an untrusted
+//! source is safe to evaluate.
+//!
+//! JSON nodes look like `{"op": "binary", "left": 0, "operator": "+",
+//! "right": 1}`, with an optional `span` of `[start offset, line, column,
+//! end offset, line, column]`; NaN and infinities are written as the
+//! strings `"nan"`, `"inf"` and `"-inf"`.
+
+use crate::functions::Builtin;
+use crate::lexer::{Position, Span, Token};
+use crate::parser::{Arena, ExprKind, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// Version written to, and required of, every document.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Deepest nesting a document may hold, counting a call as reaching into
+/// the body of the function it calls. The evaluators recurse once per
+/// level, so deeper trees could overflow the stack.
+pub const MAX_DEPTH: usize = 1000;
+
+/// Most nodes a document's tree may have with each shared node counted once
+/// per reference, as [`Arena::tree_len`] does and through calls as for
+/// [`MAX_DEPTH`]. Traversals that expand sharing, like formatting or
+/// analysis, take time in proportion to this, and a small document that
+/// shares nodes can describe an exponentially large tree.
+pub const MAX_TREE_LEN: usize = 1 << 20;
+
+const FORMAT_NAME: &str = "erock-ast";
+const MAGIC: &[u8; 4] = b"EROK";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// Not JSON, or not shaped like a document.
+    Json(String),
+    /// Binary input that is truncated or otherwise undecodable.
+    Malformed(String),
+    UnsupportedVersion { format: String, version: u32 },
+    UnknownNode { node: usize, op: String },
+    UnknownOperator { node: usize, op: String },
+    /// A number that is neither a JSON number nor `nan`/`inf`/`-inf`.
+    BadNumber { node: usize },
+    OutOfBounds { node: usize, child: usize },
+    /// `node` is reachable from itself.
+    Cycle { node: usize },
+    BadRoot { root: usize },
+    /// A call to an unknown function or with the wrong number of arguments.
+    BadCall { node: usize, name: String },
+    /// A function-table entry that is not the matching definition.
+    BadFunction { name: String },
+    RecursiveFunction { name: String },
+    /// `node` is nested more than [`MAX_DEPTH`] levels deep.
+    TooDeep { node: usize },
+    /// The tree under `node` expands to more than [`MAX_TREE_LEN`] nodes.
+    TooLarge { node: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Json(msg) => write!(f, "invalid JSON document: {}", msg),
+            LoadError::Malformed(msg) => write!(f, "malformed binary document: {}", msg),
+            LoadError::UnsupportedVersion { format, version } => {
+                write!(f, "unsupported document '{}' version {}, expected '{}' version {}", format, version, FORMAT_NAME, FORMAT_VERSION)
+            }
+            LoadError::UnknownNode { node, op } => write!(f, "node {}: unknown node kind '{}'", node, op),
+            LoadError::UnknownOperator { node, op } => write!(f, "node {}: unknown operator '{}'", node, op),
+            LoadError::BadNumber { node } => write!(f, "node {}: invalid number", node),
+            LoadError::OutOfBounds { node, child } => write!(f, "node {}: child index {} out of bounds", node, child),
+            LoadError::Cycle { node } => write!(f, "node {} is part of a cycle", node),
+            LoadError::BadRoot { root } => write!(f, "root index {} out of bounds", root),
+            LoadError::BadCall { node, name } => {
+                write!(f, "node {}: call to unknown function '{}' or with the wrong number of arguments", node, name)
+            }
+            LoadError::BadFunction { name } => write!(f, "function table entry '{}' is not its definition", name),
+            LoadError::RecursiveFunction { name } => write!(f, "function '{}' calls itself", name),
+            LoadError::TooDeep { node } => write!(f, "node {}: nested more than {} levels deep", node, MAX_DEPTH),
+            LoadError::TooLarge { node } => write!(f, "node {}: expands to more than {} nodes", node, MAX_TREE_LEN),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// ---------- document model ----------
+
+// Node kinds by name; the binary tag is the position in this list.
+const NODE_KINDS: [&str; 15] = [
+    "number", "identifier", "unary", "binary", "logical", "if", "call", "assign", "let", "program", "str", "print", "fn_def",
+    "vector", "index",
+];
+
+// Operators by symbol; the binary code is the position in this list.
+const OPERATORS: [&str; 14] = ["+", "-", "*", "/", "^", "<", "<=", ">", ">=", "==", "!=", "!", "&&", "||"];
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Node {
+    Number { value: NumberRepr },
+    Identifier { name: String },
+    Unary { operator: String, operand: usize },
+    Binary { left: usize, operator: String, right: usize },
+    Logical { left: usize, operator: String, right: usize },
+    If { cond: usize, then: usize, #[serde(rename = "else")] otherwise: usize },
+    Call { name: String, args: Vec<usize> },
+    Assign { name: String, value: usize },
+    Let { name: String, value: usize },
+    Program { statements: Vec<usize> },
+    Str { text: String },
+    Print { args: Vec<usize> },
+    FnDef { name: String, params: Vec<String>, body: usize },
+    Vector { elements: Vec<usize> },
+    Index { target: usize, index: usize },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum NumberRepr {
+    Finite(f64),
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    node: Node,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    span: Option<[usize; 6]>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Document<N> {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    hash_consed: bool,
+    root: usize,
+    #[serde(default)]
+    functions: BTreeMap<String, usize>,
+    nodes: Vec<N>,
+}
+
+fn symbol(op: &Token) -> &'static str {
+    match op {
+        Token::Plus => "+",
+        Token::Minus => "-",
+        Token::Star => "*",
+        Token::Slash => "/",
+        Token::Caret => "^",
+        Token::Lt => "<",
+        Token::Le => "<=",
+        Token::Gt => ">",
+        Token::Ge => ">=",
+        Token::EqEq => "==",
+        Token::NotEq => "!=",
+        Token::Bang => "!",
+        Token::AndAnd => "&&",
+        Token::OrOr => "||",
+        _ => "?",
+    }
+}
+
+// The operator `op` if it may appear in a node of kind `kind`.
+fn operator(kind: &str, op: &str) -> Option<Token> {
+    let token = match op {
+        "+" => Token::Plus,
+        "-" => Token::Minus,
+        "*" => Token::Star,
+        "/" => Token::Slash,
+        "^" => Token::Caret,
+        "<" => Token::Lt,
+        "<=" => Token::Le,
+        ">" => Token::Gt,
+        ">=" => Token::Ge,
+        "==" => Token::EqEq,
+        "!=" => Token::NotEq,
+        "!" => Token::Bang,
+        "&&" => Token::AndAnd,
+        "||" => Token::OrOr,
+        _ => return None,
+    };
+    let allowed = match kind {
+        "unary" => matches!(op, "-" | "+" | "!"),
+        "logical" => matches!(op, "&&" | "||"),
+        _ => !matches!(op, "!" | "&&" | "||"),
+    };
+    allowed.then_some(token)
+}
+
+fn span_repr(span: Span) -> Option<[usize; 6]> {
+    let (s, e) = (span.start, span.end);
+    (span != Span::default()).then_some([s.offset, s.line, s.column, e.offset, e.line, e.column])
+}
+
+fn span_from(repr: Option<[usize; 6]>) -> Span {
+    let Some([so, sl, sc, eo, el, ec]) = repr else { return Span::default() };
+    let start = Position { offset: so, line: sl, column: sc };
+    let end = Position { offset: eo, line: el, column: ec };
+    Span::new(start, end)
+}
+
+impl Node {
+    fn of(kind: &ExprKind) -> Node {
+        let op = |t: &Token| symbol(t).to_string();
+        let ids = |v: &[NodeId]| v.iter().map(|c| c.index()).collect();
+        match kind {
+            ExprKind::Number(v) => Node::Number {
+                value: match *v {
+                    v if v.is_nan() => NumberRepr::Text("nan".to_string()),
+                    v if v.is_infinite() => NumberRepr::Text(if v > 0.0 { "inf" } else { "-inf" }.to_string()),
+                    v => NumberRepr::Finite(v),
+                },
+            },
+            ExprKind::Identifier(name) => Node::Identifier { name: name.clone() },
+            ExprKind::Unary { op: o, operand } => Node::Unary { operator: op(o), operand: operand.index() },
+            ExprKind::Binary { left, op: o, right } => {
+                Node::Binary { left: left.index(), operator: op(o), right: right.index() }
+            }
+            ExprKind::Logical { left, op: o, right } => {
+                Node::Logical { left: left.index(), operator: op(o), right: right.index() }
+            }
+            ExprKind::If { cond, then_branch, else_branch } => {
+                Node::If { cond: cond.index(), then: then_branch.index(), otherwise: else_branch.index() }
+            }
+            ExprKind::Call { name, args } => Node::Call { name: name.clone(), args: ids(args) },
+            ExprKind::Assign { name, value } => Node::Assign { name: name.clone(), value: value.index() },
+            ExprKind::Let { name, value } => Node::Let { name: name.clone(), value: value.index() },
+            ExprKind::Program { statements } => Node::Program { statements: ids(statements) },
+            ExprKind::Str(text) => Node::Str { text: text.clone() },
+            ExprKind::Print { args } => Node::Print { args: ids(args) },
+            ExprKind::FnDef { name, params, body } => {
+                Node::FnDef { name: name.clone(), params: params.clone(), body: body.index() }
+            }
+            ExprKind::Vector { elements } => Node::Vector { elements: ids(elements) },
+            ExprKind::Index { target, index } => Node::Index { target: target.index(), index: index.index() },
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
+        NODE_KINDS[self.tag() as usize]
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Node::Number { .. } => 0,
+            Node::Identifier { .. } => 1,
+            Node::Unary { .. } => 2,
+            Node::Binary { .. } => 3,
+            Node::Logical { .. } => 4,
+            Node::If { .. } => 5,
+            Node::Call { .. } => 6,
+            Node::Assign { .. } => 7,
+            Node::Let { .. } => 8,
+            Node::Program { .. } => 9,
+            Node::Str { .. } => 10,
+            Node::Print { .. } => 11,
+            Node::FnDef { .. } => 12,
+            Node::Vector { .. } => 13,
+            Node::Index { .. } => 14,
+        }
+    }
+
+    fn children(&self) -> Vec<usize> {
+        match self {
+            Node::Number { .. } | Node::Identifier { .. } | Node::Str { .. } => vec![],
+            Node::Unary { operand, .. } => vec![*operand],
+            Node::Binary { left, right, .. } | Node::Logical { left, right, .. } => vec![*left, *right],
+            Node::If { cond, then, otherwise } => vec![*cond, *then, *otherwise],
+            Node::Call { args, .. } | Node::Print { args } | Node::Vector { elements: args } => args.clone(),
+            Node::Index { target, index } => vec![*target, *index],
+            Node::Program { statements } => statements.clone(),
+            Node::Assign { value, .. } | Node::Let { value, .. } => vec![*value],
+            Node::FnDef { body, .. } => vec![*body],
+        }
+    }
+
+    // The node as an `ExprKind`, with children already allocated in `ids`.
+    fn to_kind(&self, index: usize, ids: &[Option<NodeId>]) -> Result<ExprKind, LoadError> {
+        let id = |i: &usize| ids[*i].expect("children are allocated first");
+        let list = |v: &[usize]| v.iter().map(id).collect();
+        let op = |o: &str| {
+            operator(self.kind_name(), o).ok_or_else(|| LoadError::UnknownOperator { node: index, op: o.to_string() })
+        };
+        Ok(match self {
+            Node::Number { value: NumberRepr::Finite(v) } => ExprKind::Number(*v),
+            Node::Number { value: NumberRepr::Text(text) } => ExprKind::Number(match text.as_str() {
+                "nan" => f64::NAN,
+                "inf" => f64::INFINITY,
+                "-inf" => f64::NEG_INFINITY,
+                _ => return Err(LoadError::BadNumber { node: index }),
+            }),
+            Node::Identifier { name } => ExprKind::Identifier(name.clone()),
+            Node::Unary { operator, operand } => ExprKind::Unary { op: op(operator)?, operand: id(operand) },
+            Node::Binary { left, operator, right } => {
+                ExprKind::Binary { left: id(left), op: op(operator)?, right: id(right) }
+            }
+            Node::Logical { left, operator, right } => {
+                ExprKind::Logical { left: id(left), op: op(operator)?, right: id(right) }
+            }
+            Node::If { cond, then, otherwise } => {
+                ExprKind::If { cond: id(cond), then_branch: id(then), else_branch: id(otherwise) }
+            }
+            Node::Call { name, args } => ExprKind::Call { name: name.clone(), args: list(args) },
+            Node::Assign { name, value } => ExprKind::Assign { name: name.clone(), value: id(value) },
+            Node::Let { name, value } => ExprKind::Let { name: name.clone(), value: id(value) },
+            Node::Program { statements } => ExprKind::Program { statements: list(statements) },
+            Node::Str { text } => ExprKind::Str(text.clone()),
+            Node::Print { args } => ExprKind::Print { args: list(args) },
+            Node::FnDef { name, params, body } => {
+                ExprKind::FnDef { name: name.clone(), params: params.clone(), body: id(body) }
+            }
+            Node::Vector { elements } => ExprKind::Vector { elements: list(elements) },
+            Node::Index { target, index } => ExprKind::Index { target: id(target), index: id(index) },
+        })
+    }
+}
+
+fn document(arena: &Arena, root: NodeId) -> Document<Record> {
+    Document {
+        format: FORMAT_NAME.to_string(),
+        version: FORMAT_VERSION,
+        hash_consed: arena.is_hash_consed(),
+        root: root.index(),
+        functions: arena.functions().map(|(name, id)| (name.to_string(), id.index())).collect(),
+        nodes: arena.iter().map(|(_, e)| Record { node: Node::of(&e.kind), span: span_repr(e.span) }).collect(),
+    }
+}
+
+fn check_version(format: &str, version: u32) -> Result<(), LoadError> {
+    if format != FORMAT_NAME || version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion { format: format.to_string(), version });
+    }
+    Ok(())
+}
+
+// ---------- JSON ----------
+
+/// The whole arena as an indented JSON document rooted at `root`.
+pub fn to_json(arena: &Arena, root: NodeId) -> String {
+    serde_json::to_string_pretty(&document(arena, root)).expect("documents always serialize")
+}
+
+/// Load and validate a document written by [`to_json`].
+pub fn from_json(text: &str) -> Result<(Arena, NodeId), LoadError> {
+    let doc: Document<serde_json::Value> = serde_json::from_str(text).map_err(|e| LoadError::Json(e.to_string()))?;
+    check_version(&doc.format, doc.version)?;
+    let mut records = Vec::with_capacity(doc.nodes.len());
+    for (index, value) in doc.nodes.into_iter().enumerate() {
+        let op = value.get("op").and_then(|op| op.as_str()).unwrap_or("");
+        if !NODE_KINDS.contains(&op) {
+            return Err(LoadError::UnknownNode { node: index, op: op.to_string() });
+        }
+        let record: Record =
+            serde_json::from_value(value).map_err(|e| LoadError::Json(format!("node {}: {}", index, e)))?;
+        records.push(record);
+    }
+    build(records, doc.root, doc.functions, doc.hash_consed)
+}
+
+// ---------- binary ----------
+
+// Layout: magic, version, flags (bit 0: hash-consed), root, function table
+// (count, then name and index each), nodes (count, then each node's tag,
+// fields in declaration order, and span). Integers are LEB128 varints,
+// numbers little-endian f64, strings a length then UTF-8 bytes, operators
+// one byte, and a span a presence byte then six integers.
+
+/// The whole arena in the compact binary format, rooted at `root`.
+pub fn to_binary(arena: &Arena, root: NodeId) -> Vec<u8> {
+    let doc = document(arena, root);
+    let mut w = Writer(Vec::new());
+    w.0.extend_from_slice(MAGIC);
+    w.uint(doc.version as usize);
+    w.0.push(doc.hash_consed as u8);
+    w.uint(doc.root);
+    w.uint(doc.functions.len());
+    for (name, &index) in &doc.functions {
+        w.str(name);
+        w.uint(index);
+    }
+    w.uint(doc.nodes.len());
+    for record in &doc.nodes {
+        w.node(&record.node);
+        match record.span {
+            Some(span) => {
+                w.0.push(1);
+                span.iter().for_each(|&n| w.uint(n));
+            }
+            None => w.0.push(0),
+        }
+    }
+    w.0
+}
+
+/// Load and validate a document written by [`to_binary`].
+pub fn from_binary(bytes: &[u8]) -> Result<(Arena, NodeId), LoadError> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.take(MAGIC.len())? != MAGIC {
+        return Err(LoadError::Malformed("missing 'EROK' header".to_string()));
+    }
+    let version = r.uint()?;
+    check_version(FORMAT_NAME, u32::try_from(version).unwrap_or(u32::MAX))?;
+    let hash_consed = r.byte()? & 1 != 0;
+    let root = r.uint()?;
+    let mut functions = BTreeMap::new();
+    for _ in 0..r.uint()? {
+        let name = r.str()?;
+        functions.insert(name, r.uint()?);
+    }
+    let count = r.uint()?;
+    // Every node takes at least two bytes, so a larger count is corrupt.
+    if count > r.remaining() / 2 {
+        return Err(LoadError::Malformed(format!("node count {} exceeds the input", count)));
+    }
+    let mut records = Vec::with_capacity(count);
+    for index in 0..count {
+        let node = r.node(index)?;
+        let span = match r.byte()? {
+            0 => None,
+            _ => Some([r.uint()?, r.uint()?, r.uint()?, r.uint()?, r.uint()?, r.uint()?]),
+        };
+        records.push(Record { node, span });
+    }
+    if r.remaining() > 0 {
+        return Err(LoadError::Malformed(format!("{} trailing bytes", r.remaining())));
+    }
+    build(records, root, functions, hash_consed)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn uint(&mut self, mut n: usize) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.0.push(byte);
+                return;
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.uint(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn list(&mut self, ids: &[usize]) {
+        self.uint(ids.len());
+        ids.iter().for_each(|&i| self.uint(i));
+    }
+
+    fn op(&mut self, op: &str) {
+        self.0.push(OPERATORS.iter().position(|&o| o == op).unwrap_or(OPERATORS.len()) as u8);
+    }
+
+    fn node(&mut self, node: &Node) {
+        self.0.push(node.tag());
+        match node {
+            Node::Number { value } => {
+                let v = match value {
+                    NumberRepr::Finite(v) => *v,
+                    NumberRepr::Text(t) if t == "inf" => f64::INFINITY,
+                    NumberRepr::Text(t) if t == "-inf" => f64::NEG_INFINITY,
+                    NumberRepr::Text(_) => f64::NAN,
+                };
+                self.0.extend_from_slice(&v.to_le_bytes());
+            }
+            Node::Identifier { name } => self.str(name),
+            Node::Unary { operator, operand } => {
+                self.op(operator);
+                self.uint(*operand);
+            }
+            Node::Binary { left, operator, right } | Node::Logical { left, operator, right } => {
+                self.uint(*left);
+                self.op(operator);
+                self.uint(*right);
+            }
+            Node::If { cond, then, otherwise } => [*cond, *then, *otherwise].iter().for_each(|&i| self.uint(i)),
+            Node::Call { name, args } => {
+                self.str(name);
+                self.list(args);
+            }
+            Node::Assign { name, value } | Node::Let { name, value } => {
+                self.str(name);
+                self.uint(*value);
+            }
+            Node::Program { statements: ids } | Node::Print { args: ids } | Node::Vector { elements: ids } => {
+                self.list(ids)
+            }
+            Node::Str { text } => self.str(text),
+            Node::FnDef { name, params, body } => {
+                self.str(name);
+                self.uint(params.len());
+                params.iter().for_each(|p| self.str(p));
+                self.uint(*body);
+            }
+            Node::Index { target, index } => {
+                self.uint(*target);
+                self.uint(*index);
+            }
+        }
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], LoadError> {
+        if n > self.remaining() {
+            return Err(LoadError::Malformed(format!("unexpected end of input at byte {}", self.bytes.len())));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<usize, LoadError> {
+        let start = self.pos;
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(n).map_err(|_| LoadError::Malformed(format!("integer too large at byte {}", start)));
+            }
+        }
+        Err(LoadError::Malformed(format!("integer too long at byte {}", start)))
+    }
+
+    fn str(&mut self) -> Result<String, LoadError> {
+        let (start, len) = (self.pos, self.uint()?);
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::Malformed(format!("invalid UTF-8 at byte {}", start)))
+    }
+
+    fn list(&mut self) -> Result<Vec<usize>, LoadError> {
+        // Not preallocated: the count is untrusted.
+        (0..self.uint()?).map(|_| self.uint()).collect()
+    }
+
+    fn op(&mut self, node: usize) -> Result<String, LoadError> {
+        let code = self.byte()?;
+        match OPERATORS.get(code as usize) {
+            Some(op) => Ok(op.to_string()),
+            None => Err(LoadError::UnknownOperator { node, op: format!("#{}", code) }),
+        }
+    }
+
+    fn node(&mut self, index: usize) -> Result<Node, LoadError> {
+        let tag = self.byte()?;
+        Ok(match tag {
+            0 => {
+                let bytes: [u8; 8] = self.take(8)?.try_into().expect("took 8 bytes");
+                let v = f64::from_le_bytes(bytes);
+                let value = match v {
+                    v if v.is_nan() => NumberRepr::Text("nan".to_string()),
+                    v if v.is_infinite() => NumberRepr::Text(if v > 0.0 { "inf" } else { "-inf" }.to_string()),
+                    v => NumberRepr::Finite(v),
+                };
+                Node::Number { value }
+            }
+            1 => Node::Identifier { name: self.str()? },
+            2 => Node::Unary { operator: self.op(index)?, operand: self.uint()? },
+            3 | 4 => {
+                let left = self.uint()?;
+                let operator = self.op(index)?;
+                let right = self.uint()?;
+                if tag == 3 {
+                    Node::Binary { left, operator, right }
+                } else {
+                    Node::Logical { left, operator, right }
+                }
+            }
+            5 => Node::If { cond: self.uint()?, then: self.uint()?, otherwise: self.uint()? },
+            6 => Node::Call { name: self.str()?, args: self.list()? },
+            7 => Node::Assign { name: self.str()?, value: self.uint()? },
+            8 => Node::Let { name: self.str()?, value: self.uint()? },
+            9 => Node::Program { statements: self.list()? },
+            10 => Node::Str { text: self.str()? },
+            11 => Node::Print { args: self.list()? },
+            12 => {
+                let name = self.str()?;
+                let params = (0..self.uint()?).map(|_| self.str()).collect::<Result<_, _>>()?;
+                Node::FnDef { name, params, body: self.uint()? }
+            }
+            13 => Node::Vector { elements: self.list()? },
+            14 => Node::Index { target: self.uint()?, index: self.uint()? },
+            _ => return Err(LoadError::UnknownNode { node: index, op: format!("#{}", tag) }),
+        })
+    }
+}
+
+// ---------- validation ----------
+
+// Check `records` and allocate them into a new arena, children first.
+fn build(
+    records: Vec<Record>,
+    root: usize,
+    functions: BTreeMap<String, usize>,
+    hash_consed: bool,
+) -> Result<(Arena, NodeId), LoadError> {
+    let n = records.len();
+    let children: Vec<Vec<usize>> = records.iter().map(|r| r.node.children()).collect();
+    for (node, kids) in children.iter().enumerate() {
+        if let Some(&child) = kids.iter().find(|&&c| c >= n) {
+            return Err(LoadError::OutOfBounds { node, child });
+        }
+    }
+    if root >= n {
+        return Err(LoadError::BadRoot { root });
+    }
+    let order = topological_order(&children)?;
+
+    for (name, &index) in &functions {
+        match records.get(index).map(|r| &r.node) {
+            Some(Node::FnDef { name: defined, .. }) if defined == name => {}
+            _ => return Err(LoadError::BadFunction { name: name.clone() }),
+        }
+    }
+    for (node, record) in records.iter().enumerate() {
+        let Node::Call { name, args } = &record.node else { continue };
+        let ok = match Builtin::from_name(name) {
+            Some(builtin) => builtin.arity().accepts(args.len()),
+            None => match functions.get(name).map(|&f| &records[f].node) {
+                Some(Node::FnDef { params, .. }) => params.len() == args.len(),
+                _ => false,
+            },
+        };
+        if !ok {
+            return Err(LoadError::BadCall { node, name: name.clone() });
+        }
+    }
+    check_recursion(&records, &children, &functions)?;
+    // Without recursion, a call's edge to its function's definition keeps
+    // the graph acyclic, and a chain of calls counts in full.
+    let through_calls: Vec<Vec<usize>> = records
+        .iter()
+        .zip(&children)
+        .map(|(record, kids)| match &record.node {
+            Node::Call { name, .. } => kids.iter().copied().chain(functions.get(name).copied()).collect(),
+            _ => kids.clone(),
+        })
+        .collect();
+    let calls_order = topological_order(&through_calls)?;
+    for (node, (depth, len)) in measure(&through_calls, &calls_order).into_iter().enumerate() {
+        if depth > MAX_DEPTH {
+            return Err(LoadError::TooDeep { node });
+        }
+        if len > MAX_TREE_LEN {
+            return Err(LoadError::TooLarge { node });
+        }
+    }
+
+    let mut arena = if hash_consed { Arena::hash_consed() } else { Arena::new() };
+    let mut ids = vec![None; n];
+    for index in order {
+        let kind = records[index].node.to_kind(index, &ids)?;
+        ids[index] = Some(arena.alloc_at(kind, span_from(records[index].span)));
+    }
+    for (name, index) in functions {
+        arena.define_function(&name, ids[index].expect("every node is allocated"));
+    }
+    Ok((arena, ids[root].expect("every node is allocated")))
+}
+
+// All nodes with each after its children; fails on a cycle. Iterative, so
+// a deep untrusted tree cannot overflow the stack here.
+fn topological_order(children: &[Vec<usize>]) -> Result<Vec<usize>, LoadError> {
+    const NEW: u8 = 0;
+    const OPEN: u8 = 1;
+    const DONE: u8 = 2;
+    let mut state = vec![NEW; children.len()];
+    let mut order = Vec::with_capacity(children.len());
+    for start in 0..children.len() {
+        if state[start] != NEW {
+            continue;
+        }
+        state[start] = OPEN;
+        let mut stack = vec![(start, 0)];
+        while let Some(top) = stack.last_mut() {
+            let (node, next) = *top;
+            match children[node].get(next) {
+                Some(&child) => {
+                    top.1 += 1;
+                    match state[child] {
+                        NEW => {
+                            state[child] = OPEN;
+                            stack.push((child, 0));
+                        }
+                        OPEN => return Err(LoadError::Cycle { node: child }),
+                        _ => {}
+                    }
+                }
+                None => {
+                    state[node] = DONE;
+                    order.push(node);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+// Depth (1 for a leaf) and expanded size of each node, given `order` from
+// `topological_order`. Sizes saturate rather than overflow.
+fn measure(children: &[Vec<usize>], order: &[usize]) -> Vec<(usize, usize)> {
+    let mut out = vec![(0, 0); children.len()];
+    for &node in order {
+        let kids = children[node].iter().map(|&c| out[c]);
+        out[node] = kids.fold((1, 1), |(depth, len): (usize, usize), (d, l)| (depth.max(d + 1), len.saturating_add(l)));
+    }
+    out
+}
+
+// Reject a user function that can reach a call to itself, which the
+// evaluators would follow forever.
+fn check_recursion(
+    records: &[Record],
+    children: &[Vec<usize>],
+    functions: &BTreeMap<String, usize>,
+) -> Result<(), LoadError> {
+    // User functions called from each function's body.
+    let mut calls: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+    for (name, &def) in functions {
+        let Node::FnDef { body, .. } = &records[def].node else { continue };
+        let (mut seen, mut stack, mut called) = (BTreeSet::new(), vec![*body], BTreeSet::new());
+        while let Some(node) = stack.pop() {
+            if !seen.insert(node) {
+                continue;
+            }
+            if let Node::Call { name, .. } = &records[node].node {
+                if functions.contains_key(name) {
+                    called.insert(name.as_str());
+                }
+            }
+            stack.extend(&children[node]);
+        }
+        calls.insert(name.as_str(), called);
+    }
+    for name in functions.keys() {
+        let (mut seen, mut stack) = (BTreeSet::new(), calls[name.as_str()].iter().copied().collect::<Vec<_>>());
+        while let Some(callee) = stack.pop() {
+            if callee == name {
+                return Err(LoadError::RecursiveFunction { name: name.clone() });
+            }
+            if seen.insert(callee) {
+                stack.extend(calls[callee].iter().copied());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `count` nodes where each adds the one before to itself, so node i
+    // expands to 2^(i+1) - 1 nodes.
+    fn fan_out(count: usize) -> String {
+        let mut nodes = vec![r#"{"op": "identifier", "name": "x"}"#.to_string()];
+        nodes.extend((1..count).map(|i| format!(r#"{{"op": "binary", "left": {0}, "operator": "+", "right": {0}}}"#, i - 1)));
+        format!(r#"{{"format": "erock-ast", "version": 1, "root": {}, "functions": {{}}, "nodes": [{}]}}"#, count - 1, nodes.join(", "))
+    }
+
+    #[test]
+    fn fan_out_is_limited_by_expanded_size() {
+        let (arena, root) = from_json(&fan_out(20)).unwrap();
+        assert_eq!(arena.len(), 20);
+        assert!(from_binary(&to_binary(&arena, root)).is_ok());
+        assert_eq!(from_json(&fan_out(21)).unwrap_err(), LoadError::TooLarge { node: 20 });
+        // Far past the point where expanding it would never finish.
+        assert_eq!(from_json(&fan_out(200)).unwrap_err(), LoadError::TooLarge { node: 20 });
+    }
+
+    #[test]
+    fn fan_out_through_calls_is_limited() {
+        // A function whose body is 2^19 - 1 nodes, called from both sides
+        // of a sum: each call is under the limit but the sum is not.
+        let body = fan_out(19);
+        let nodes = &body[body.find("\"nodes\": [").unwrap() + 10..body.len() - 2];
+        let doc = format!(
+            r#"{{"format": "erock-ast", "version": 1, "root": 22, "functions": {{"f": 19}}, "nodes": [{}, {{"op": "fn_def", "name": "f", "params": ["x"], "body": 18}}, {{"op": "number", "value": 1}}, {{"op": "call", "name": "f", "args": [20]}}, {{"op": "binary", "left": 21, "operator": "+", "right": 21}}]}}"#,
+            nodes
+        );
+        assert_eq!(from_json(&doc).unwrap_err(), LoadError::TooLarge { node: 22 });
+    }
+}
@@ -0,0 +1,292 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Values that may be vectors: `[vx, vy, vz]` literals, `v[i]` indexing
+//! from 0, `len(v)`, and element-wise arithmetic.
+//!
+//! [`interpret_value`] is the tree-walking interpreter;
+//! [`crate::interpreter::interpret`] wraps it for numeric variables.
+//! Operators and builtins apply element by element, repeating a scalar
+//! operand to match, so `[1, 2] * 3` is `[3, 6]`; vectors of different
+//! lengths give NaN. A vector condition in `if`, `&&` or `||` also works
+//! per element, evaluating both sides. An index that is not a whole number
+//! in range, and a vector nested inside a literal, give NaN.
+//!
+//! Reductions (`sum`, `mean`, `std`, `norm`, `count_if`, and `min` or `max`
+//! of one argument) turn a vector into a scalar; `dot(a, b)` multiplies
+//! element-wise first. See [`crate::reduce`].
+
+use crate::functions::Builtin;
+use crate::interpreter::{binary_op, from_bool, truthy, user_function, Memo};
+use crate::lexer::Token;
+use crate::parser::{Arena, ExprKind, NodeId};
+use crate::reduce;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<f64>),
+}
+
+impl Value {
+    pub fn as_scalar(&self) -> Option<f64> {
+        match self {
+            Value::Scalar(v) => Some(*v),
+            Value::Vector(_) => None,
+        }
+    }
+
+    pub fn as_vector(&self) -> Option<&[f64]> {
+        match self {
+            Value::Scalar(_) => None,
+            Value::Vector(v) => Some(v),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Scalar(v)
+    }
+}
+
+impl From<Vec<f64>> for Value {
+    fn from(v: Vec<f64>) -> Self {
+        Value::Vector(v)
+    }
+}
+
+/// A vector prints as `[1, 2.5]`; a precision (`{:.2}`) applies to each
+/// element.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let write = |f: &mut fmt::Formatter<'_>, v: f64| match f.precision() {
+            Some(p) => write!(f, "{:.*}", p, v),
+            None => write!(f, "{}", v),
+        };
+        match self {
+            Value::Scalar(v) => write(f, *v),
+            Value::Vector(items) => {
+                f.write_str("[")?;
+                for (i, &v) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write(f, v)?;
+                }
+                f.write_str("]")
+            }
+        }
+    }
+}
+
+const NAN: Value = Value::Scalar(f64::NAN);
+
+/// Position `i` of a vector of `len` elements, if `i` is a whole number in
+/// range.
+pub(crate) fn element(len: usize, i: f64) -> Option<usize> {
+    (i >= 0.0 && i.fract() == 0.0 && i < len as f64).then_some(i as usize)
+}
+
+// `f` applied to `args` element by element, with scalars repeated; a scalar
+// if every argument is one.
+fn broadcast(args: &[Value], f: impl Fn(&[f64]) -> f64) -> Value {
+    let mut len = None;
+    for arg in args {
+        if let Value::Vector(v) = arg {
+            match len {
+                None => len = Some(v.len()),
+                Some(n) if n != v.len() => return NAN,
+                Some(_) => {}
+            }
+        }
+    }
+    let mut row: Vec<f64> = args.iter().map(|a| a.as_scalar().unwrap_or(f64::NAN)).collect();
+    let Some(len) = len else { return Value::Scalar(f(&row)) };
+    let out = (0..len)
+        .map(|i| {
+            for (slot, arg) in row.iter_mut().zip(args) {
+                if let Value::Vector(v) = arg {
+                    *slot = v[i];
+                }
+            }
+            f(&row)
+        })
+        .collect();
+    Value::Vector(out)
+}
+
+fn index(target: &Value, at: &Value) -> Value {
+    let Value::Vector(items) = target else { return NAN };
+    let get = |i: f64| element(items.len(), i).map_or(f64::NAN, |k| items[k]);
+    match at {
+        Value::Scalar(i) => Value::Scalar(get(*i)),
+        Value::Vector(positions) => Value::Vector(positions.iter().map(|&i| get(i)).collect()),
+    }
+}
+
+pub(crate) fn call_builtin(builtin: Builtin, args: &[Value]) -> Value {
+    use Value::{Scalar, Vector};
+    let reduced = match (builtin, args) {
+        (Builtin::Len, [Vector(v)]) => v.len() as f64,
+        (Builtin::Sum, [Vector(v)]) => reduce::sum(v),
+        (Builtin::Mean, [Vector(v)]) => reduce::mean(v),
+        (Builtin::Std, [Vector(v)]) => reduce::std(v),
+        (Builtin::Norm, [Vector(v)]) => reduce::norm(v),
+        (Builtin::CountIf, [Vector(v)]) => reduce::count_if(v),
+        (Builtin::Min, [Vector(v)]) => reduce::min(v),
+        (Builtin::Max, [Vector(v)]) => reduce::max(v),
+        (Builtin::Dot, [Vector(a), Vector(b)]) => reduce::dot(a, b),
+        (Builtin::Dot, [Vector(v), Scalar(k)] | [Scalar(k), Vector(v)]) => reduce::sum(v) * k,
+        _ => return broadcast(args, |row| builtin.eval(row)),
+    };
+    Scalar(reduced)
+}
+
+// Variables visible while evaluating: program-scoped `let` bindings
+// (innermost last) shadow the caller's map, which `=` writes through to.
+struct Env<'v> {
+    globals: &'v mut HashMap<String, Value>,
+    locals: Vec<(String, Value)>,
+    memo: Memo<Value>,
+}
+
+impl Env<'_> {
+    fn get(&self, name: &str) -> Value {
+        match self.locals.iter().rev().find(|(n, _)| n == name) {
+            Some((_, v)) => v.clone(),
+            None => self.globals.get(name).cloned().unwrap_or(Value::Scalar(0.0)),
+        }
+    }
+
+    fn assign(&mut self, name: &str, v: Value) {
+        match self.locals.iter_mut().rev().find(|(n, _)| n == name) {
+            Some(slot) => slot.1 = v,
+            None => {
+                self.globals.insert(name.to_string(), v);
+            }
+        }
+        self.memo.invalidate();
+    }
+}
+
+/// Evaluate the tree at `root` with variables that may hold vectors.
+/// Unbound names read as `0`, and `=` writes back to `variables`.
+pub fn interpret_value(root: NodeId, arena: &Arena, variables: &mut HashMap<String, Value>) -> Value {
+    let mut env = Env { globals: variables, locals: Vec::new(), memo: Memo::new(arena, Value::Scalar(0.0)) };
+    eval(root, arena, &mut env)
+}
+
+fn eval(idx: NodeId, arena: &Arena, env: &mut Env) -> Value {
+    if !arena.is_shared(idx) {
+        return eval_node(idx, arena, env);
+    }
+    if let Some(v) = env.memo.get(idx) {
+        return v;
+    }
+    let v = eval_node(idx, arena, env);
+    env.memo.set(idx, v.clone());
+    v
+}
+
+fn eval_node(idx: NodeId, arena: &Arena, env: &mut Env) -> Value {
+    let Some(expr) = arena.get(idx) else { return NAN };
+    match &expr.kind {
+        ExprKind::Number(n) => Value::Scalar(*n),
+        ExprKind::Identifier(name) => env.get(name),
+        ExprKind::Vector { elements } => {
+            let items = elements.iter().map(|&e| eval(e, arena, env).as_scalar().unwrap_or(f64::NAN)).collect();
+            Value::Vector(items)
+        }
+        ExprKind::Index { target, index: at } => {
+            let target = eval(*target, arena, env);
+            index(&target, &eval(*at, arena, env))
+        }
+        ExprKind::Unary { op, operand } => {
+            let v = eval(*operand, arena, env);
+            broadcast(&[v], |a| match op {
+                Token::Minus => -a[0],
+                Token::Plus => a[0],
+                Token::Bang => from_bool(!truthy(a[0])),
+                _ => f64::NAN,
+            })
+        }
+        ExprKind::Binary { left, op, right } => {
+            let l = eval(*left, arena, env);
+            let r = eval(*right, arena, env);
+            match (&l, &r) {
+                (Value::Scalar(a), Value::Scalar(b)) => Value::Scalar(binary_op(op, *a, *b)),
+                _ => broadcast(&[l, r], |a| binary_op(op, a[0], a[1])),
+            }
+        }
+        ExprKind::Logical { left, op, right } => {
+            let l = eval(*left, arena, env);
+            if let Value::Scalar(v) = l {
+                match op {
+                    Token::AndAnd if !truthy(v) => return Value::Scalar(0.0),
+                    Token::OrOr if truthy(v) => return Value::Scalar(1.0),
+                    Token::AndAnd | Token::OrOr => {}
+                    _ => return NAN,
+                }
+            }
+            let r = eval(*right, arena, env);
+            broadcast(&[l, r], |a| match op {
+                Token::AndAnd => from_bool(truthy(a[0]) && truthy(a[1])),
+                Token::OrOr => from_bool(truthy(a[0]) || truthy(a[1])),
+                _ => f64::NAN,
+            })
+        }
+        ExprKind::If { cond, then_branch, else_branch } => match eval(*cond, arena, env) {
+            Value::Scalar(c) if truthy(c) => eval(*then_branch, arena, env),
+            Value::Scalar(_) => eval(*else_branch, arena, env),
+            c => {
+                let t = eval(*then_branch, arena, env);
+                let e = eval(*else_branch, arena, env);
+                broadcast(&[c, t, e], |a| if truthy(a[0]) { a[1] } else { a[2] })
+            }
+        },
+        ExprKind::Call { name, args } => {
+            let vals: Vec<Value> = args.iter().map(|&a| eval(a, arena, env)).collect();
+            if let Some(builtin) = Builtin::from_name(name) {
+                return call_builtin(builtin, &vals);
+            }
+            let Some((params, body)) = user_function(arena, name) else { return NAN };
+            if params.len() != vals.len() {
+                return NAN;
+            }
+            let scope = env.locals.len();
+            env.locals.extend(params.iter().cloned().zip(vals));
+            env.memo.invalidate();
+            let v = eval(body, arena, env);
+            env.locals.truncate(scope);
+            env.memo.invalidate();
+            v
+        }
+        ExprKind::Assign { name, value } => {
+            let v = eval(*value, arena, env);
+            env.assign(name, v.clone());
+            v
+        }
+        ExprKind::Let { name, value } => {
+            let v = eval(*value, arena, env);
+            env.locals.push((name.clone(), v.clone()));
+            env.memo.invalidate();
+            v
+        }
+        ExprKind::Program { statements } => {
+            let scope = env.locals.len();
+            let mut v = NAN;
+            for &stmt in statements {
+                v = eval(stmt, arena, env);
+            }
+            env.locals.truncate(scope);
+            env.memo.invalidate();
+            v
+        }
+        ExprKind::FnDef { .. } | ExprKind::Str(_) | ExprKind::Print { .. } => NAN,
+    }
+}
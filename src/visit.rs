@@ -0,0 +1,101 @@
+/*
+SPDX-FileCopyrightText: 2025 Eric Waller
+SPDX-License-Identifier: LicenseRef-eRock-Business-1.0
+*/
+
+//! Generic traversals over a tree in an [`Arena`], for analyses that live
+//! outside this crate.
+
+use crate::parser::{Arena, ExprKind, NodeId};
+use std::collections::HashMap;
+
+/// Callbacks for [`walk`], which visits a tree depth-first with children in
+/// evaluation order.
+pub trait Visitor {
+    /// Called before `id`'s children; return `false` to skip them.
+    fn enter(&mut self, _arena: &Arena, _id: NodeId, _kind: &ExprKind) -> bool {
+        true
+    }
+
+    /// Called after `id`'s children (or right after `enter` if they were
+    /// skipped).
+    fn exit(&mut self, _arena: &Arena, _id: NodeId, _kind: &ExprKind) {}
+}
+
+/// Run `visitor` over the tree under `root`. A node referenced from two
+/// places is visited twice, once per path to it, so on a heavily shared
+/// tree (see [`Arena::tree_len`]) a visitor that never skips children can
+/// take time exponential in the arena's size.
+pub fn walk<V: Visitor + ?Sized>(arena: &Arena, root: NodeId, visitor: &mut V) {
+    let kind = &arena[root].kind;
+    if visitor.enter(arena, root, kind) {
+        kind.for_each_child(|child| walk(arena, child, visitor));
+    }
+    visitor.exit(arena, root, kind);
+}
+
+/// A bottom-up computation: each node's result is built from its own kind
+/// and the results of its children.
+pub trait Fold {
+    type Output: Clone;
+
+    /// `children` holds the results for `kind.children()`, in that order.
+    fn fold(&mut self, arena: &Arena, id: NodeId, kind: &ExprKind, children: Vec<Self::Output>) -> Self::Output;
+}
+
+/// Run `folder` over the tree under `root` and return the root's result.
+/// A shared node is folded once and its result reused at every reference.
+pub fn fold<F: Fold + ?Sized>(arena: &Arena, root: NodeId, folder: &mut F) -> F::Output {
+    fold_cached(arena, root, folder, &mut HashMap::new())
+}
+
+fn fold_cached<F: Fold + ?Sized>(arena: &Arena, id: NodeId, folder: &mut F, done: &mut HashMap<NodeId, F::Output>) -> F::Output {
+    if let Some(result) = done.get(&id) {
+        return result.clone();
+    }
+    let kind = &arena[id].kind;
+    let mut children = Vec::new();
+    kind.for_each_child(|child| children.push(fold_cached(arena, child, folder, done)));
+    let result = folder.fold(arena, id, kind, children);
+    if arena.is_shared(id) {
+        done.insert(id, result.clone());
+    }
+    result
+}
+
+// Copies each node into `target`, keeping spans and function definitions.
+struct CopyInto<'a> {
+    target: &'a mut Arena,
+}
+
+impl Fold for CopyInto<'_> {
+    type Output = NodeId;
+
+    fn fold(&mut self, arena: &Arena, id: NodeId, kind: &ExprKind, children: Vec<NodeId>) -> NodeId {
+        let mut children = children.into_iter();
+        let kind = kind.map_children(|_| children.next().expect("one result per child"));
+        let copy = self.target.alloc_at(kind, arena[id].span);
+        if let ExprKind::FnDef { name, .. } = &self.target[copy].kind {
+            let name = name.clone();
+            self.target.define_function(&name, copy);
+        }
+        copy
+    }
+}
+
+/// Copy the tree under `root` into `target` and return the copy's root.
+/// Calls to user functions defined outside the tree are copied as-is, so
+/// the definitions must already be in `target` for them to resolve.
+pub fn copy_tree(arena: &Arena, root: NodeId, target: &mut Arena) -> NodeId {
+    fold(arena, root, &mut CopyInto { target })
+}
+
+/// Copy every function defined in `arena` into `target`, so that calls in
+/// trees copied afterwards resolve there.
+pub fn copy_functions(arena: &Arena, target: &mut Arena) {
+    let mut defs: Vec<NodeId> = arena.functions().map(|(_, def)| def).collect();
+    defs.sort_by_key(|def| def.index());
+    for def in defs {
+        copy_tree(arena, def, target);
+    }
+}